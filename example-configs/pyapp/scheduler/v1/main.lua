@@ -1,4 +1,3 @@
-local JSON = require "JSON"
 local trace = require "trace"
 local func = require "func"
 local merge = require "merge"
@@ -187,7 +186,7 @@ local function _scheduler(state)
         app3=rich_migration_app,
     }
     preprocess.state(state)
-    return JSON:encode(merge.schedules(
+    return verwalter.json_encode(merge.schedules(
         func.map_pairs(function (role_name, role_func)
             print("-------------- ROLE", role_name, "-----------------")
             return role_func {
@@ -1,11 +1,9 @@
-local inspect = require "inspect"
-
 local text = nil
 
 local function object(title, data)
     text = text
         .. string.format('----- %s ----\n', title)
-        .. inspect(data)
+        .. (verwalter.json_encode(data, true) or tostring(data))
         .. "\n"
 end
 
@@ -478,6 +478,18 @@ impl<'a, 'b> Role<'a, 'b> {
         add_err(&mut self.err, self.log.write_fmt(args).err());
         err_msg(args.to_string())
     }
+    /// Closes log without writing finish marker
+    ///
+    /// Log may be reopened by `Deployment::role(name, false)` later and
+    /// then closed by `finish()`. This allows other roles to be logged
+    /// in the meantime.
+    pub fn suspend(mut self) {
+        self.full_role = false;
+    }
+    /// Closes log writing finish marker, even if opened with `start=false`
+    pub fn finish(mut self) {
+        self.full_role = true;
+    }
 }
 
 impl<'a, 'b> Drop for Role<'a, 'b> {
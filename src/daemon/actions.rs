@@ -0,0 +1,324 @@
+//! Queue of actions submitted to the leader
+//!
+//! Queue is persisted in the storage directory and replicated to followers
+//! along with the schedule (in the `actions` field, next to `data`, so it
+//! doesn't change the hash), so that a new leader picks up actions that are
+//! not processed yet.
+use std::collections::{BTreeMap, VecDeque, HashSet};
+use std::io;
+use std::usize;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{Value as Json, to_value, from_value};
+
+use fs_util::{read_json, write_file};
+use scheduler::Schedule;
+
+
+/// Number of finished actions kept in the history
+pub const HISTORY_LIMIT: usize = 100;
+/// Time finished actions are kept in the history (milliseconds)
+pub const HISTORY_RETENTION: u64 = 3600_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(GraphQLEnum)]
+#[graphql(name="ActionStatus", description="Status of an action")]
+#[serde(rename_all="snake_case")]
+pub enum ActionStatus {
+    Pending,
+    Done,
+    NoResponse,
+    Cancelled,
+}
+
+#[derive(Debug)]
+pub enum CancelError {
+    NotFound,
+    AlreadyStarted,
+    AlreadyFinished(ActionStatus),
+}
+
+/// Filter for listing actions, all the conditions must match
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub status: Option<ActionStatus>,
+    pub submitter: Option<String>,
+    pub idempotency_key: Option<String>,
+    /// Only actions submitted at this time (milliseconds) or later
+    pub since: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActionRecord {
+    pub id: u64,
+    /// Address of the client that submitted the action
+    pub submitter: String,
+    pub submitted: u64,
+    /// Time when action was first passed to the scheduler
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    pub status: ActionStatus,
+    pub data: Arc<Json>,
+    pub result: Option<Json>,
+    /// Key supplied by client to avoid submitting same action twice
+    #[serde(default, skip_serializing_if="Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Queue {
+    pub pending: BTreeMap<u64, ActionRecord>,
+    pub history: VecDeque<ActionRecord>,
+    /// All actions with this id or lower are finished
+    ///
+    /// Ids are monotonic, so this is used to drop stale pending actions of
+    /// other nodes which are already removed from the history.
+    #[serde(default)]
+    pub finished_up_to: u64,
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue::default()
+    }
+    pub fn load(path: &Path) -> io::Result<Queue> {
+        from_value(read_json(path)?).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+        })
+    }
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        write_file(path, self)
+    }
+    /// Reads queue replicated with the schedule
+    pub fn from_schedule(schedule: &Schedule) -> Option<Queue> {
+        schedule.actions.as_ref()
+            .and_then(|x| from_value(x.clone()).map_err(|e| {
+                warn!("Can't decode actions from schedule: {}", e);
+            }).ok())
+    }
+    pub fn to_json(&self) -> Json {
+        to_value(self).expect("can serialize actions")
+    }
+    pub fn contains(&self, id: u64) -> bool {
+        self.pending.contains_key(&id) ||
+            self.history.iter().any(|a| a.id == id)
+    }
+    fn is_finished(&self, id: u64) -> bool {
+        id <= self.finished_up_to ||
+            self.history.iter().any(|a| a.id == id)
+    }
+    pub fn find_by_key(&self, key: &str) -> Option<&ActionRecord> {
+        let key = Some(key);
+        self.pending.values()
+            .chain(self.history.iter())
+            .find(|a| a.idempotency_key.as_ref().map(|x| &x[..]) == key)
+    }
+    pub fn get(&self, id: u64) -> Option<&ActionRecord> {
+        self.pending.get(&id)
+            .or_else(|| self.history.iter().find(|a| a.id == id))
+    }
+    /// Marks pending action as finished and moves it to the history
+    pub fn finish(&mut self, id: u64, result: Option<Json>, now: u64)
+        -> Option<&ActionRecord>
+    {
+        let mut action = self.pending.remove(&id)?;
+        action.finished = Some(now);
+        action.status = match result {
+            Some(_) => ActionStatus::Done,
+            None => ActionStatus::NoResponse,
+        };
+        action.result = result;
+        self.push_history(action);
+        self.history.back()
+    }
+    pub fn cancel(&mut self, id: u64, now: u64)
+        -> Result<&ActionRecord, CancelError>
+    {
+        if self.pending.get(&id).map(|a| a.started.is_some()) == Some(true) {
+            return Err(CancelError::AlreadyStarted);
+        }
+        let mut action = match self.pending.remove(&id) {
+            Some(action) => action,
+            None => match self.get(id) {
+                Some(action) => {
+                    return Err(CancelError::AlreadyFinished(action.status));
+                }
+                None => return Err(CancelError::NotFound),
+            },
+        };
+        action.finished = Some(now);
+        action.status = ActionStatus::Cancelled;
+        self.push_history(action);
+        Ok(self.history.back().expect("just pushed"))
+    }
+    fn push_history(&mut self, action: ActionRecord) {
+        self.history.push_back(action);
+        while self.history.len() > HISTORY_LIMIT {
+            let action = self.history.pop_front().expect("history is full");
+            self.forget(action.id);
+        }
+    }
+    /// Advances `finished_up_to` when action is removed from the history
+    fn forget(&mut self, id: u64) {
+        // lower ids may still be pending, if some later one is cancelled
+        let below_pending = self.pending.keys().next()
+            .map(|&first| first.saturating_sub(1))
+            .unwrap_or(::std::u64::MAX);
+        let mark = ::std::cmp::min(id, below_pending);
+        if mark > self.finished_up_to {
+            self.finished_up_to = mark;
+        }
+    }
+    /// Removes finished actions older than `HISTORY_RETENTION`
+    pub fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(HISTORY_RETENTION);
+        while self.history.front()
+            .map(|a| a.finished.unwrap_or(now) < cutoff).unwrap_or(false)
+        {
+            let action = self.history.pop_front().expect("not empty");
+            self.forget(action.id);
+        }
+        self.history.retain(|a| a.finished.unwrap_or(now) >= cutoff);
+    }
+    /// Pending actions followed by finished ones, newest first
+    pub fn list(&self, filter: &Filter) -> Vec<ActionRecord> {
+        self.pending.values().rev()
+            .chain(self.history.iter().rev())
+            .filter(|a| filter.matches(a))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned().collect()
+    }
+    /// Merges queue received from another node into this one
+    ///
+    /// Actions finished by any of the queues are considered finished,
+    /// pending actions are united.
+    pub fn merge(&mut self, other: Queue) {
+        if other.finished_up_to > self.finished_up_to {
+            self.finished_up_to = other.finished_up_to;
+            let stale = self.pending.keys()
+                .take_while(|&&id| id <= other.finished_up_to)
+                .cloned().collect::<Vec<_>>();
+            for id in stale {
+                self.pending.remove(&id);
+            }
+        }
+        let finished = self.history.iter().map(|a| a.id)
+            .collect::<HashSet<_>>();
+        let mut history = other.history.into_iter()
+            .filter(|a| !finished.contains(&a.id))
+            .filter(|a| a.id > self.finished_up_to)
+            .collect::<Vec<_>>();
+        for action in &history {
+            self.pending.remove(&action.id);
+        }
+        history.extend(self.history.drain(..));
+        history.sort_by_key(|a| (a.finished, a.id));
+        for action in history {
+            self.push_history(action);
+        }
+        for (id, action) in other.pending {
+            if !self.pending.contains_key(&id) && !self.is_finished(id) {
+                self.pending.insert(id, action);
+            }
+        }
+    }
+}
+
+impl Filter {
+    pub fn matches(&self, action: &ActionRecord) -> bool {
+        self.status.map(|s| s == action.status).unwrap_or(true) &&
+        self.submitter.as_ref()
+            .map(|s| s == &action.submitter).unwrap_or(true) &&
+        self.idempotency_key.as_ref()
+            .map(|k| Some(k) == action.idempotency_key.as_ref())
+            .unwrap_or(true) &&
+        self.since.map(|t| action.submitted >= t).unwrap_or(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use serde_json::Value as Json;
+    use super::{Queue, ActionRecord, ActionStatus, Filter, CancelError};
+    use super::{HISTORY_RETENTION, HISTORY_LIMIT};
+
+    fn action(id: u64) -> ActionRecord {
+        ActionRecord {
+            id,
+            submitter: "127.0.0.1:1234".into(),
+            submitted: id,
+            started: None,
+            finished: None,
+            status: ActionStatus::Pending,
+            data: Arc::new(Json::Null),
+            result: None,
+            idempotency_key: None,
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut local = Queue::new();
+        local.pending.insert(1, action(1));
+        local.pending.insert(2, action(2));
+        let mut remote = Queue::new();
+        remote.pending.insert(1, action(1));
+        remote.pending.insert(2, action(2));
+        remote.pending.insert(3, action(3));
+        remote.finish(1, Some(Json::Bool(true)), 10);
+        local.merge(remote);
+        assert_eq!(local.pending.keys().cloned().collect::<Vec<_>>(),
+                   vec![2, 3]);
+        assert_eq!(local.history.len(), 1);
+        assert_eq!(local.get(1).unwrap().status, ActionStatus::Done);
+    }
+
+    #[test]
+    fn test_merge_forgotten() {
+        let mut local = Queue::new();
+        let mut remote = Queue::new();
+        for id in 1..(HISTORY_LIMIT as u64 + 3) {
+            local.pending.insert(id, action(id));
+            if id == 1 || id == 2 {
+                remote.pending.insert(id, action(id));
+            }
+            local.finish(id, None, 10 + id);
+        }
+        assert!(local.get(2).is_none());
+        assert_eq!(local.finished_up_to, 2);
+        local.merge(remote.clone());
+        assert!(local.pending.is_empty());
+
+        // and the other way around
+        remote.merge(local);
+        assert!(remote.pending.is_empty());
+        assert_eq!(remote.finished_up_to, 2);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut queue = Queue::new();
+        queue.pending.insert(1, action(1));
+        queue.pending.insert(2, action(2));
+        assert_eq!(queue.cancel(1, 10).unwrap().status,
+                   ActionStatus::Cancelled);
+        assert!(queue.cancel(1, 10).is_err());
+        assert!(queue.cancel(3, 10).is_err());
+        queue.pending.get_mut(&2).unwrap().started = Some(5);
+        match queue.cancel(2, 10) {
+            Err(CancelError::AlreadyStarted) => {}
+            res => panic!("unexpected result {:?}", res),
+        }
+        assert!(queue.pending.contains_key(&2));
+        let filter = Filter {
+            status: Some(ActionStatus::Pending),
+            .. Filter::default()
+        };
+        assert_eq!(queue.list(&filter).len(), 1);
+        queue.prune(10 + HISTORY_RETENTION + 1);
+        assert!(queue.get(1).is_none());
+    }
+}
@@ -1,30 +1,49 @@
 use std::borrow::Cow;
-use std::collections::BTreeMap;
-use std::fs::{File, hard_link, remove_file};
-use std::io;
-use std::path::{Path, PathBuf};
-use std::process::{Command, ExitStatus, exit};
+use std::cmp::{max, min};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{File, read, read_to_string};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio, exit};
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use async_slot as slot;
-use capturing_glob::{glob_with, MatchOptions};
-use deflate::write::GzEncoder;
-use deflate::Compression;
-use failure::{Error, err_msg};
-use time::now_utc;
-use serde_json::{Value as Json};
+use config::RenderResult;
+use config::render_result::{Status, millis};
+use humantime::{parse_duration, format_duration};
+use libc;
+use nix::fcntl::OFlag;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{pipe2, Pid};
+use rand::{thread_rng, Rng};
+use scan_dir::ScanDir;
+use serde_millis;
+use serde_json::{Value as Json, from_slice};
 use indexed_log::Index;
 use futures::Stream;
 
+use backups;
 use scheduler::{SchedulerInput, Schedule};
-use fs_util::{write_file, safe_write};
+use fs_util::write_file;
+use hash::hash;
 use shared::{SharedState};
 use watchdog;
+use webhooks::Event;
 
-const HOURLY_SNAPSHOTS: usize = 36;
-const DAILY_SNAPSHOTS: usize = 14;
-const WEEKLY_SNAPSHOTS: usize = 12;
+/// Variables that change on every render and aren't compared
+const VOLATILE_VARS: &[&str] = &["timestamp", "deployment_id"];
+/// Maximum delay between retries of a failed role
+const MAX_RETRY_DELAY: u64 = 600_000;
+/// Seconds between SIGTERM and SIGKILL for timed out renderer
+const KILL_GRACE: u64 = 5;
+
+/// Exit status and result reported by `verwalter_render`
+type RenderStatus = Result<(ExitStatus, Option<RenderResult>), io::Error>;
 
 
 pub struct Settings {
@@ -34,12 +53,104 @@ pub struct Settings {
     pub log_dir: PathBuf,
     pub config_dir: PathBuf,
     pub schedule_dir: PathBuf,
+    /// Maximum number of roles rendered simultaneously
+    pub render_concurrency: usize,
+    /// Number of times failed role is retried (zero disables retries)
+    pub retry_attempts: u32,
+    /// Delay before the first retry, doubled for each next one
+    pub retry_delay: Duration,
+    /// Default time limit for rendering a single role
+    pub render_timeout: Duration,
+    pub backups: backups::Policy,
+}
+
+/// Retry state of a failed role
+#[derive(Serialize, Debug, Clone)]
+pub struct RoleRetry {
+    /// Number of failed renders since the role was changed
+    pub attempts: u32,
+    /// Hash of the variables the attempts were made with
+    #[serde(skip)]
+    pub vars_hash: String,
+    /// Time of the next retry, `None` if no more retries left
+    #[serde(with="serde_millis")]
+    pub next_attempt: Option<SystemTime>,
+}
+
+/// Role which was rolled back to the last good variables
+#[derive(Serialize, Debug, Clone)]
+pub struct Rollback {
+    /// Hash of the schedule which is effectively active for the role
+    pub active_schedule: String,
+    /// Hash of the schedule which failed to render
+    pub failed_schedule: String,
+    #[serde(with="serde_millis")]
+    pub timestamp: SystemTime,
+}
+
+/// Everything needed to render a role
+#[derive(Clone)]
+struct RoleInput {
+    schedule_hash: String,
+    vars_hash: String,
+    /// Serialized variables
+    vars: String,
+    /// Serialized schedule, shared by all roles of a deployment
+    schedule: Arc<String>,
+    timeout: Duration,
+    /// Roll back to the last good variables on failure
+    rollback: bool,
 }
 
 pub struct ApplyData {
     pub id: String,
     pub schedule: Arc<Schedule>,
     pub roles: BTreeMap<String, Json>,
+    /// Render roles even if their variables are unchanged
+    pub force: bool,
+}
+
+/// Hash of everything the render of a role depends on
+///
+/// Includes variables and the signature of the templates. If templates
+/// refer to `full_schedule`, the schedule hash is included too.
+fn vars_hash(vars: &Json, templates: &(String, bool), schedule_hash: &str)
+    -> String
+{
+    let mut vars = vars.clone();
+    if let Some(obj) = vars.as_object_mut() {
+        for key in VOLATILE_VARS {
+            obj.remove(*key);
+        }
+    }
+    let (ref signature, uses_schedule) = *templates;
+    let schedule_hash = if uses_schedule { schedule_hash } else { "" };
+    hash(format!("{}\n{}\n{}", vars, signature, schedule_hash))
+}
+
+/// Hashes names and contents of all files in the template dir
+///
+/// Also returns whether any of the files refers to `full_schedule`.
+fn template_signature(settings: &Settings, template: &str)
+    -> Result<(String, bool), String>
+{
+    let dir = settings.config_dir.join("templates").join(template);
+    let mut files = ScanDir::files().walk(&dir, |iter| {
+        iter.map(|(entry, _)| entry.path()).collect::<Vec<_>>()
+    }).map_err(|errs| format!("can't read {:?}: {:?}", dir, errs))?;
+    files.sort();
+    let mut buf = Vec::new();
+    let mut uses_schedule = false;
+    for path in files {
+        let data = read(&path)
+            .map_err(|e| format!("can't read {:?}: {}", path, e))?;
+        uses_schedule = uses_schedule ||
+            data.windows(13).any(|w| w == b"full_schedule");
+        buf.extend(path.to_string_lossy().as_bytes());
+        buf.extend(format!("\0{}\0", data.len()).as_bytes());
+        buf.extend(data);
+    }
+    Ok((hash(buf), uses_schedule))
 }
 
 fn decode_render_error(s: ExitStatus) -> Cow<'static, str> {
@@ -64,7 +175,9 @@ fn decode_render_error(s: ExitStatus) -> Cow<'static, str> {
 
 fn apply_schedule(hash: &String, is_new: bool,
     apply_task: ApplyData, settings: &Settings,
-    debug_info: Arc<Option<(SchedulerInput, String)>>, state: &SharedState)
+    debug_info: Arc<Option<(SchedulerInput, String)>>, state: &SharedState,
+    rendered: &mut HashMap<String, String>,
+    last_good: &mut HashMap<String, RoleInput>)
 {
     let mut index = Index::new(&settings.log_dir, settings.dry_run);
     let mut dlog = index.deployment(&apply_task.id, true);
@@ -89,179 +202,496 @@ fn apply_schedule(hash: &String, is_new: bool,
     }
 
     let ref id = apply_task.id;
-    let string_schedule = format!("{}", apply_task.schedule.data);
-    for (role_name, mut vars) in apply_task.roles {
-        vars.as_object_mut().map(|obj| {
-            obj.insert("deployment_id".into(), id.clone().into());
-            obj.insert("role".into(), role_name.clone().into());
-            obj.insert("verwalter_version".into(),
-                concat!("v", env!("CARGO_PKG_VERSION")).into());
+    let string_schedule = Arc::new(format!("{}", apply_task.schedule.data));
+    let force = apply_task.force;
+    let mut skipped = 0;
+    let mut pending = BTreeMap::new();
+    let mut signatures = HashMap::new();
+    for (role_name, vars) in apply_task.roles {
+        let template = vars.get("template").and_then(|x| x.as_str())
+            .unwrap_or("").to_string();
+        if !signatures.contains_key(&template) {
+            let sig = if template.is_empty() {
+                Some((String::new(), false))
+            } else {
+                template_signature(settings, &template).map_err(|e| {
+                    warn!("Can't compute template signature: {}", e);
+                }).ok()
+            };
+            signatures.insert(template.clone(), sig);
+        }
+        let vars_hash = match signatures[&template] {
+            Some(ref sig) => vars_hash(&vars, sig, hash),
+            // always rendered, as the hash never matches
+            None => String::new(),
+        };
+        if !force && !vars_hash.is_empty() &&
+            rendered.get(&role_name) == Some(&vars_hash)
+        {
+            debug!("Role {:?} is unchanged, skipping", role_name);
+            skipped += 1;
+            continue;
+        }
+        pending.insert(role_name, (vars, vars_hash));
+    }
+    if skipped > 0 {
+        info!("Skipped {} roles with unchanged variables", skipped);
+    }
+    let num_roles = pending.len();
+    if num_roles > 0 {
+        state.webhook(Event::DeploymentStarted {
+            deployment_id: id.clone(),
+            schedule: hash.clone(),
+            roles: pending.keys().cloned().collect(),
         });
-        let mut rlog = match dlog.role(&role_name, true) {
+    }
+    let mut failed_roles = BTreeSet::new();
+
+    let concurrency = max(settings.render_concurrency, 1);
+    let (tx, rx) = channel();
+    let mut running = HashSet::new();
+    let mut rollbacks = Vec::new();
+    loop {
+        while running.len() < concurrency {
+            if let Some((role_name, input)) = rollbacks.pop() {
+                running.insert(role_name.clone());
+                // files may be partially updated by the failed render
+                spawn_render(settings, &tx, role_name, input, None, true);
+                continue;
+            }
+            if pending.is_empty() {
+                break;
+            }
+            let next = pending.iter()
+                .find(|&(_, &(ref vars, _))| {
+                    render_after(vars).iter().all(|dep| {
+                        !pending.contains_key(dep) && !running.contains(dep)
+                    })
+                })
+                .map(|(name, _)| name.clone());
+            let role_name = match next {
+                Some(name) => name,
+                None if running.is_empty() => {
+                    // all the roles left depend on each other
+                    let name = pending.keys().next().unwrap().clone();
+                    warn!("Circular `render_after` dependency, \
+                        rendering {:?} first", name);
+                    name
+                }
+                None => break,
+            };
+            let (mut vars, vars_hash) = pending.remove(&role_name).unwrap();
+            vars.as_object_mut().map(|obj| {
+                obj.insert("deployment_id".into(), id.clone().into());
+                obj.insert("role".into(), role_name.clone().into());
+                obj.insert("verwalter_version".into(),
+                    concat!("v", env!("CARGO_PKG_VERSION")).into());
+            });
+            let input = RoleInput {
+                schedule_hash: hash.clone(),
+                vars_hash: vars_hash,
+                vars: format!("{}", vars),
+                schedule: string_schedule.clone(),
+                timeout: render_timeout(settings, &vars),
+                rollback: vars.get("rollback_on_failure")
+                    .and_then(|x| x.as_bool()).unwrap_or(false),
+            };
+            {
+                let mut rlog = match dlog.role(&role_name, true) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        error!("Can't create role log: {}", e);
+                        role_failed(state, settings, &role_name,
+                                    &input.vars_hash);
+                        failed_roles.insert(role_name.clone());
+                        continue;
+                    }
+                };
+                rlog.log(format_args!(
+                    "Template variables: {}\n", input.vars));
+                // render process appends to the role log itself
+                rlog.suspend();
+            }
+            running.insert(role_name.clone());
+            {
+                let previous = last_good.get(&role_name);
+                spawn_render(settings, &tx, role_name, input, previous, false);
+            }
+        }
+        if running.is_empty() {
+            break;
+        }
+        let (role_name, input, is_rollback, result) = rx.recv()
+            .expect("render threads never panic");
+        running.remove(&role_name);
+        let mut rlog = match dlog.role(&role_name, false) {
             Ok(l) => l,
             Err(e) => {
-                error!("Can't create role log: {}", e);
-                return;
+                error!("Can't open role log: {}", e);
+                if !is_rollback {
+                    role_failed(state, settings, &role_name,
+                                &input.vars_hash);
+                    failed_roles.insert(role_name.clone());
+                }
+                continue;
             }
         };
-        let vars = format!("{}", vars);
-        rlog.log(format_args!("Template variables: {}\n", vars));
-
-        let mut cmd = if settings.use_sudo {
-            let mut cmd = Command::new("sudo");
-            cmd.arg("verwalter_render");
-            cmd
-        } else {
-            Command::new("verwalter_render")
-        };
-        cmd.arg("--log-dir");
-        cmd.arg(&settings.log_dir);
-        cmd.arg("--config-dir");
-        cmd.arg(&settings.config_dir);
-
-        {
-            let fname = "/tmp/verwalter/vars-for-render.json";
-            match safe_write(fname.as_ref(), vars.as_bytes()) {
-                Ok(()) => {}
-                Err(e) => {
-                    error!("Can't write schedule file {:?}: {}",
-                        fname, e);
-                    return;
+        if is_rollback {
+            match result.map(|(status, _)| status) {
+                Ok(x) if x.success() => {
+                    rlog.log(format_args!(
+                        "Rolled back to schedule {} successfully\n",
+                        &input.schedule_hash[..8]));
+                    rendered.insert(role_name.clone(), input.vars_hash);
+                    state.set_role_rollback(&role_name, Rollback {
+                        active_schedule: input.schedule_hash,
+                        failed_schedule: hash.clone(),
+                        timestamp: SystemTime::now(),
+                    });
+                }
+                Ok(status) => {
+                    rlog.log(format_args!(
+                        "ERROR: Rollback failed. verwalter_render {}\n",
+                        status));
                 }
-            }
-            cmd.arg("--vars-file");
-            cmd.arg(fname);
-        }
-
-        {
-            let fname = "/tmp/verwalter/schedule-for-render.json";
-            match safe_write(fname.as_ref(), string_schedule.as_bytes()) {
-                Ok(()) => {}
                 Err(e) => {
-                    error!("Can't write schedule file {:?}: {}",
-                        fname, e);
-                    return;
+                    rlog.log(format_args!(
+                        "ERROR: Rollback failed. verwalter_render {}\n", e));
                 }
             }
-            cmd.arg("--schedule-file");
-            cmd.arg(fname);
+            rlog.finish();
+            continue;
         }
-
-        if settings.dry_run {
-            cmd.arg("--dry-run");
+        if let Ok((_, Some(ref result))) = result {
+            state.set_render_result(&role_name, result.clone());
         }
-        debug!("Running {:?}", cmd);
-        match cmd.status() {
+        let failed = match result.map(|(status, _)| status) {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                state.set_render_result(&role_name, RenderResult {
+                    status: Status::Failed,
+                    duration_ms: millis(input.timeout),
+                    error: Some(e.to_string()),
+                    actions: Vec::new(),
+                });
+                rlog.log(format_args!(
+                    "ERROR: Error rendering role. \
+                    verwalter_render {}\n", e));
+                true
+            }
             Ok(x) if x.success() => {
                 rlog.log(format_args!("Rendered successfully\n"));
                 state.reset_role_failure(&role_name);
+                false
             }
             Ok(status) => {
-                state.mark_role_failure(&role_name);
                 rlog.log(format_args!(
                     "ERROR: Error rendering role. \
                     verwalter_render {}\n", status));
                 rlog.log(format_args!(
                     "Decoded verwalter render failure: {}\n",
                     decode_render_error(status)));
+                true
             }
             Err(e) => {
-                state.mark_role_failure(&role_name);
                 rlog.log(format_args!(
                     "ERROR: Error rendering role. \
                     Can't run verwalter_render: {}\n", e));
+                true
+            }
+        };
+        if !failed {
+            rendered.insert(role_name.clone(), input.vars_hash.clone());
+            last_good.insert(role_name.clone(), input);
+            rlog.finish();
+            continue;
+        }
+        role_failed(state, settings, &role_name, &input.vars_hash);
+        failed_roles.insert(role_name.clone());
+        rendered.remove(&role_name);
+        let good = if input.rollback {
+            last_good.get(&role_name)
+                .filter(|good| good.vars_hash != input.vars_hash)
+                .cloned()
+        } else {
+            None
+        };
+        match good {
+            Some(good) => {
+                rlog.log(format_args!(
+                    "Rolling back to variables of schedule {}\n",
+                    &good.schedule_hash[..8]));
+                rlog.suspend();
+                rollbacks.push((role_name.clone(), good));
             }
+            None => rlog.finish(),
         }
     }
     for err in dlog.done() {
         error!("Logging error: {}", err);
     }
+    if num_roles > 0 {
+        state.webhook(Event::DeploymentFinished {
+            deployment_id: id.clone(),
+            schedule: hash.clone(),
+            roles: num_roles,
+            failed_roles: failed_roles.into_iter().collect(),
+        });
+    }
 }
 
-fn list_backups(pat: &str) -> Vec<(String, PathBuf)> {
-    glob_with(pat, &MatchOptions {
-        case_sensitive: true,
-        require_literal_separator: true,
-        require_literal_leading_dot: true,
-    })
-    .map(|iter| {
-        iter.flat_map(|entry| match entry {
-            Ok(e) => e.group(1)
-                .and_then(|x| x.to_str())
-                .map(|x| x.to_string())
-                .map(|x| (x, e.into())),
-            Err(e) => {
-                error!("Error listing backups: {}", e);
-                None
+/// Marks role as failed and schedules a retry if attempts are left
+///
+/// Attempts are counted from zero when variables of the role change.
+fn role_failed(state: &SharedState, settings: &Settings, role_name: &str,
+    vars_hash: &str)
+{
+    state.mark_role_failure(role_name);
+    let attempts = state.role_retries().get(role_name)
+        .filter(|r| r.vars_hash == vars_hash)
+        .map(|r| r.attempts + 1).unwrap_or(1);
+    let next_attempt = if attempts <= settings.retry_attempts {
+        let base = millis(settings.retry_delay)
+            .saturating_mul(1u64 << min(attempts - 1, 31))
+            .min(MAX_RETRY_DELAY);
+        let delay = (base as f64 * thread_rng().gen_range(0.75, 1.25)) as u64;
+        let time = SystemTime::now() + Duration::from_millis(delay);
+        let epoch = time.duration_since(UNIX_EPOCH)
+            .expect("time is after epoch");
+        info!("Role {:?} failed {} times, retrying in {} ms",
+            role_name, attempts, delay);
+        state.get_responder().schedule_render(role_name, millis(epoch));
+        Some(time)
+    } else {
+        if settings.retry_attempts > 0 {
+            warn!("Role {:?} failed {} times, no retries left",
+                role_name, attempts);
+        }
+        None
+    };
+    state.set_role_retry(role_name, RoleRetry {
+        attempts, next_attempt,
+        vars_hash: vars_hash.to_string(),
+    });
+}
+
+fn parse_timeout(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    value.parse().map(Duration::from_secs)
+        .or_else(|_| parse_duration(value).map_err(|e| e.to_string()))
+}
+
+/// Time limit for rendering a role
+///
+/// Taken from `render_timeout` variable (either seconds or a duration
+/// like `5min`), then from `render-timeout` file in the template dir,
+/// and finally from the command-line.
+fn render_timeout(settings: &Settings, vars: &Json) -> Duration {
+    let result = match vars.get("render_timeout") {
+        Some(&Json::Number(ref n)) => n.as_u64().map(Duration::from_secs)
+            .ok_or_else(|| format!("invalid number {}", n)),
+        Some(&Json::String(ref s)) => parse_timeout(s),
+        Some(_) => Err("must be a number or a string".into()),
+        None => {
+            let path = match vars.get("template").and_then(|x| x.as_str()) {
+                Some(t) => settings.config_dir.join("templates").join(t)
+                    .join("render-timeout"),
+                None => return settings.render_timeout,
+            };
+            match read_to_string(&path) {
+                Ok(data) => parse_timeout(&data)
+                    .map_err(|e| format!("{:?}: {}", path, e)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return settings.render_timeout;
+                }
+                Err(e) => Err(format!("can't read {:?}: {}", path, e)),
             }
-        })
-        .collect()
-    }).unwrap_or_else(|e| {
-        error!("Error listing backups: {}", e);
-        Vec::new()
+        }
+    };
+    result.unwrap_or_else(|e| {
+        warn!("Bad render timeout: {}, using default", e);
+        settings.render_timeout
     })
 }
 
-fn maintain_backups(dir: &Path) -> Result<(), Error> {
-    let dir_str = dir.to_str()
-        .ok_or_else(|| err_msg("storage dir is not valid utf-8"))?;
-    let mut hourly = list_backups(&format!("{}/hourly-(*).json.gz", dir_str));
-    let mut daily = list_backups(&format!("{}/daily-(*).json.gz", dir_str));
-    let mut weekly = list_backups(&format!("{}/weekly-(*).json.gz", dir_str));
-    let current_hour = now_utc().strftime("%Y%m%dT%H")?.to_string();
-    let current_day = now_utc().strftime("%Y%m%d")?.to_string();
-    let current_week = now_utc().strftime("%Yw%W")?.to_string();
-    let need_hour = hourly.last()
-        .map(|&(ref x, _)| x != &current_hour).unwrap_or(true);
-    let need_day = daily.last()
-        .map(|&(ref x, _)| x != &current_day).unwrap_or(true);
-    let need_week = weekly.last()
-        .map(|&(ref x, _)| x != &current_week).unwrap_or(true);
-    if !need_hour && !need_day && !need_week {
-        return Ok(());
+fn spawn_render(settings: &Settings,
+    tx: &Sender<(String, RoleInput, bool, RenderStatus)>,
+    role_name: String, input: RoleInput, previous: Option<&RoleInput>,
+    is_rollback: bool)
+{
+    // this is a `RenderInput`, but we avoid cloning the schedule
+    let data = format!(r#"{{"vars":{},"schedule":{},"previous_vars":{}}}"#,
+        input.vars, input.schedule,
+        previous.map(|p| &p.vars[..]).unwrap_or("null"));
+    let cmd = render_command(settings);
+    debug!("Running {:?}", cmd);
+    let use_sudo = settings.use_sudo;
+    let tx = tx.clone();
+    thread::spawn(move || {
+        let result = run_render(cmd, data.into_bytes(), input.timeout,
+                                use_sudo);
+        tx.send((role_name, input, is_rollback, result)).ok();
+    });
+}
+
+/// Roles that must be rendered before this one (`render_after` variable)
+fn render_after(vars: &Json) -> Vec<String> {
+    match vars.get("render_after") {
+        Some(&Json::String(ref name)) => vec![name.clone()],
+        Some(&Json::Array(ref names)) => {
+            names.iter()
+                .filter_map(|x| x.as_str().map(|x| x.to_string()))
+                .collect()
+        }
+        _ => Vec::new(),
     }
-    let infile = File::open(&dir.join("schedule.json"))?;
-    let tmp_path = dir.join("backup.tmp");
-    let outfile = GzEncoder::new(
-        File::create(&tmp_path)?,
-        Compression::Best);
-    io::copy(&mut {infile}, &mut {outfile})?;
-    if need_hour {
-        let dest = dir.join(&format!("hourly-{}.json.gz", current_hour));
-        hard_link(&tmp_path, &dest)
-            .map_err(|e| error!("Error hardlinking snapshot: {}", e)).ok();
-        hourly.push((current_hour, dest));
+}
+
+fn render_command(settings: &Settings) -> Command {
+    let mut cmd = if settings.use_sudo {
+        let mut cmd = Command::new("sudo");
+        cmd.arg("verwalter_render");
+        cmd
+    } else {
+        Command::new("verwalter_render")
+    };
+    cmd.arg("--log-dir");
+    cmd.arg(&settings.log_dir);
+    cmd.arg("--config-dir");
+    cmd.arg(&settings.config_dir);
+    cmd.arg("--input-stdin");
+    if settings.dry_run {
+        cmd.arg("--dry-run");
     }
-    if need_day {
-        let dest = dir.join(&format!("daily-{}.json.gz", current_day));
-        hard_link(&tmp_path, &dest)
-            .map_err(|e| error!("Error hardlinking snapshot: {}", e)).ok();
-        daily.push((current_day, dest));
+    return cmd;
+}
+
+/// Sends a signal to the process group of the renderer
+///
+/// With sudo the renderer and its commands run as root, so we can't
+/// signal them directly and use `sudo kill` instead.
+fn kill_group(group: Pid, signal: Signal, use_sudo: bool)
+    -> Result<(), String>
+{
+    if !use_sudo {
+        return killpg(group, signal).map_err(|e| e.to_string());
     }
-    if need_week {
-        let dest = dir.join(&format!("weekly-{}.json.gz", current_week));
-        hard_link(&tmp_path, &dest)
-            .map_err(|e| error!("Error hardlinking snapshot: {}", e)).ok();
-        weekly.push((current_week, dest));
+    let status = Command::new("sudo")
+        .arg("-n").arg("kill")
+        .arg(format!("-{}", signal as libc::c_int))
+        .arg("--").arg(format!("-{}", group))
+        .stdin(Stdio::null())
+        .status()
+        .map_err(|e| format!("can't run sudo kill: {}", e))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("sudo kill {}", status))
     }
-    remove_file(&tmp_path)
-        .map_err(|e| error!("Error removing {:?}: {}", tmp_path, e)).ok();
-    let del_hourly = hourly.len().saturating_sub(HOURLY_SNAPSHOTS);
-    for (_, fname) in hourly.drain(..del_hourly) {
-        remove_file(&fname)
-            .map_err(|e| error!("Error removing {:?}: {}", fname, e)).ok();
+}
+
+/// Runs renderer passing `input` on stdin and reading result from a pipe
+///
+/// Result is `None` if renderer didn't report it (e.g. when file
+/// descriptor is closed by sudo). Renderer is started in a new process
+/// group which is killed if it doesn't finish in `timeout`, in this case
+/// `TimedOut` error is returned.
+fn run_render(mut cmd: Command, input: Vec<u8>, timeout: Duration,
+    use_sudo: bool)
+    -> RenderStatus
+{
+    let (rd, wr) = pipe2(OFlag::O_CLOEXEC)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let result_pipe = unsafe { File::from_raw_fd(rd) };
+    let write_end = unsafe { File::from_raw_fd(wr) };
+    cmd.arg("--result-fd");
+    cmd.arg(wr.to_string());
+    cmd.stdin(Stdio::piped());
+    // only async-signal-safe calls are done in the child
+    unsafe {
+        cmd.pre_exec(move || {
+            // only the child process inherits the descriptor
+            if libc::fcntl(wr, libc::F_SETFD, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // so we can kill commands run by renderer too
+            if libc::setpgid(0, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
     }
-    let del_daily = daily.len().saturating_sub(DAILY_SNAPSHOTS);
-    for (_, fname) in daily.drain(..del_daily) {
-        remove_file(&fname)
-            .map_err(|e| error!("Error removing {:?}: {}", fname, e)).ok();
+    let mut child = cmd.spawn()?;
+    drop(write_end);
+    let group = Pid::from_raw(child.id() as libc::pid_t);
+    let (done_tx, done_rx) = channel::<()>();
+    // returns `None` if renderer finished in time, or result of the kill
+    let killer = thread::spawn(move || {
+        match done_rx.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {}
+            _ => return None,
+        }
+        let mut outcome = kill_group(group, Signal::SIGTERM, use_sudo);
+        let grace = Duration::from_secs(KILL_GRACE);
+        if done_rx.recv_timeout(grace) == Err(RecvTimeoutError::Timeout) {
+            outcome = kill_group(group, Signal::SIGKILL, use_sudo);
+        }
+        if let Err(ref e) = outcome {
+            error!("Can't kill timed out renderer: {}", e);
+        }
+        return Some(outcome);
+    });
+    // read in a thread, so that neither a large result nor a descriptor
+    // leaked to a long-running command can block us
+    let (result_tx, result_rx) = channel();
+    thread::spawn(move || {
+        let mut result_pipe = result_pipe;
+        let mut buf = Vec::new();
+        let res = result_pipe.read_to_end(&mut buf).map(|_| buf);
+        result_tx.send(res).ok();
+    });
+    if let Some(mut stdin) = child.stdin.take() {
+        // renderer may exit early without reading the input
+        stdin.write_all(&input)
+            .map_err(|e| debug!("Error writing render input: {}", e))
+            .ok();
     }
-    let del_weekly = weekly.len().saturating_sub(WEEKLY_SNAPSHOTS);
-    for (_, fname) in weekly.drain(..del_weekly) {
-        remove_file(&fname)
-            .map_err(|e| error!("Error removing {:?}: {}", fname, e)).ok();
+    let status = child.wait();
+    drop(done_tx);
+    let killed = killer.join().unwrap_or(None);
+    let grace = Duration::from_secs(KILL_GRACE);
+    let buf = match result_rx.recv_timeout(grace) {
+        Ok(Ok(buf)) => buf,
+        Ok(Err(e)) => {
+            error!("Error reading render result: {}", e);
+            Vec::new()
+        }
+        Err(_) => {
+            error!("Render result pipe is still open after renderer exited");
+            Vec::new()
+        }
+    };
+    match killed {
+        Some(Ok(())) => {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("timed out after {}, process group killed",
+                    format_duration(timeout))));
+        }
+        Some(Err(e)) => {
+            return Err(io::Error::new(io::ErrorKind::TimedOut,
+                format!("timed out after {}, can't kill renderer: {}",
+                    format_duration(timeout), e)));
+        }
+        None => {}
     }
-    Ok(())
+    let status = status?;
+    let result = if buf.is_empty() {
+        None
+    } else {
+        from_slice(&buf)
+            .map_err(|e| error!("Error decoding render result: {}", e))
+            .ok()
+    };
+    Ok((status, result))
 }
 
 pub fn run(state: SharedState, settings: Settings,
@@ -270,18 +700,32 @@ pub fn run(state: SharedState, settings: Settings,
 {
     let _guard = watchdog::ExitOnReturn(93);
     let mut prev_schedule = String::new();
+    // hashes of variables of successfully rendered roles
+    let mut rendered = HashMap::new();
+    // input of the last successful render of each role, for rollbacks
+    let mut last_good = HashMap::new();
     for task in tasks.wait() {
         let task = task.unwrap_or_else(|_| exit(93));
         let schedule = task.schedule.clone();
-        let _alarm = watchdog::Alarm::new(Duration::new(180, 0), "apply");
-        write_file(&settings.schedule_dir.join("schedule.json"),
-            &*schedule)
-            .map_err(|e| error!("Writing schedule failed: {:?}", e)).ok();
+        {
+            let _alarm = watchdog::Alarm::new(Duration::new(10, 0),
+                "write schedule");
+            write_file(&settings.schedule_dir.join("schedule.json"),
+                &*schedule)
+                .map_err(|e| error!("Writing schedule failed: {:?}", e)).ok();
+        }
+        // every role is limited by its own render timeout
         apply_schedule(&schedule.hash, prev_schedule != schedule.hash,
             task, &settings,
-            state.scheduler_debug_info(), &state);
-        maintain_backups(&settings.schedule_dir)
-            .map_err(|e| error!("Writing backup failed: {:?}", e)).ok();
+            state.scheduler_debug_info(), &state,
+            &mut rendered, &mut last_good);
+        {
+            let _alarm = watchdog::Alarm::new(Duration::new(30, 0),
+                "backups");
+            backups::maintain(&settings.schedule_dir, &schedule.hash,
+                    &settings.backups)
+                .map_err(|e| error!("Writing backup failed: {:?}", e)).ok();
+        }
         prev_schedule = schedule.hash.clone();
     }
     unreachable!();
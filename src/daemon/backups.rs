@@ -0,0 +1,460 @@
+//! Snapshots of the schedule kept in the storage dir
+//!
+//! Snapshots are gzipped copies of `schedule.json` named
+//! `<kind>-<stamp>-<hash>.json.gz`. Snapshots of the same schedule are
+//! hardlinked, so they take space only once. Backups made by older versions
+//! have no hash in the name.
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, hard_link, remove_file};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use capturing_glob::{glob_with, MatchOptions};
+use failure::{Error, err_msg};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use quire::validate::{Structure, Numeric, Scalar};
+use quire::{parse_config, parse_string, Options};
+use serde_json::from_reader;
+use serde_millis;
+use time::now_utc;
+
+use scheduler::{self, Schedule};
+
+
+const KINDS: &[&str] = &["change", "hourly", "daily", "weekly"];
+
+/// Retention policy, read from `backups.yaml` in the config dir
+#[derive(Deserialize, Debug, Clone)]
+pub struct Policy {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+    /// Number of snapshots made on every change of the schedule
+    pub per_change: usize,
+    /// Maximum total size of the backups in bytes (zero is unlimited)
+    pub max_size: u64,
+    pub compression: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Backup {
+    pub name: String,
+    pub kind: String,
+    /// Hash of the schedule, `None` for backups of older versions
+    pub hash: Option<String>,
+    pub size: u64,
+    /// Time when the contents were written
+    #[serde(with="serde_millis")]
+    pub timestamp: SystemTime,
+    #[serde(skip)]
+    stamp: String,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    inode: (u64, u64),
+}
+
+fn validator() -> Structure<'static> {
+    Structure::new()
+    .member("hourly", Numeric::new().min(0).default(36))
+    .member("daily", Numeric::new().min(0).default(14))
+    .member("weekly", Numeric::new().min(0).default(12))
+    .member("per_change", Numeric::new().min(0).default(0))
+    .member("max_size", Numeric::new().min(0).default(0))
+    .member("compression", Scalar::new().default("best"))
+}
+
+/// Reads `backups.yaml`, returns default policy if there is no such file
+pub fn read_policy(config_dir: &Path) -> Result<Policy, Error> {
+    let path = config_dir.join("backups.yaml");
+    let policy: Policy = if path.exists() {
+        parse_config(&path, &validator(), &Options::default())
+    } else {
+        parse_string("<default>", "{}", &validator(), &Options::default())
+    }.map_err(|e| format_err!("error reading {:?}: {}", path, e))?;
+    compression(&policy)?;
+    Ok(policy)
+}
+
+fn compression(policy: &Policy) -> Result<Compression, Error> {
+    match &policy.compression[..] {
+        "fast" => Ok(Compression::fast()),
+        "default" => Ok(Compression::default()),
+        "best" => Ok(Compression::best()),
+        _ => bail!("invalid compression {:?}, expected fast, default \
+            or best", policy.compression),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|x| x.is_alphanumeric() || x == '-')
+}
+
+fn parse_name(name: &str) -> Option<(&str, &str, Option<&str>)> {
+    let mut parts = name.splitn(3, '-');
+    let kind = parts.next()?;
+    let stamp = parts.next()?;
+    if !KINDS.contains(&kind) || stamp.is_empty() {
+        return None;
+    }
+    Some((kind, stamp, parts.next()))
+}
+
+/// Lists backups sorted by kind and time
+pub fn list(dir: &Path) -> Vec<Backup> {
+    let pattern = format!("{}/(*-*).json.gz",
+        dir.to_str().expect("schedule dir is utf-8"));
+    let entries = match glob_with(&pattern, &MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: true,
+    }) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Error listing backups: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut result = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("Error listing backups: {}", e);
+                continue;
+            }
+        };
+        let name = match entry.group(1).and_then(|x| x.to_str()) {
+            Some(name) if is_valid_name(name) => name.to_string(),
+            _ => continue,
+        };
+        let (kind, stamp, hash) = match parse_name(&name) {
+            Some((kind, stamp, hash)) => {
+                (kind.to_string(), stamp.to_string(),
+                 hash.map(|x| x.to_string()))
+            }
+            None => continue,
+        };
+        let path: PathBuf = entry.into();
+        let meta = match path.metadata() {
+            Ok(meta) => meta,
+            Err(e) => {
+                error!("Error reading backup {:?}: {}", path, e);
+                continue;
+            }
+        };
+        result.push(Backup {
+            name, kind, hash, stamp,
+            size: meta.len(),
+            timestamp: meta.modified().unwrap_or(SystemTime::now()),
+            inode: (meta.dev(), meta.ino()),
+            path,
+        });
+    }
+    result.sort_by(|a, b| (&a.kind, &a.stamp).cmp(&(&b.kind, &b.stamp)));
+    return result;
+}
+
+/// Reads a backup, e.g. `daily-20180901-<hash>`
+pub fn read(dir: &Path, name: &str) -> Result<Schedule, Error> {
+    if !is_valid_name(name) {
+        bail!("invalid backup name {:?}", name);
+    }
+    let file = File::open(&dir.join(format!("{}.json.gz", name)))?;
+    let json = from_reader(GzDecoder::new(file))?;
+    let schedule = scheduler::from_json(json).map_err(err_msg)?;
+    Ok(schedule)
+}
+
+/// Identity of the backup contents
+fn key(backup: &Backup) -> String {
+    match backup.hash {
+        Some(ref hash) => hash.clone(),
+        None => format!("inode:{}:{}", backup.inode.0, backup.inode.1),
+    }
+}
+
+/// Days since epoch of the date in proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Start of the period of the snapshot in seconds since epoch
+///
+/// Stamps are `%Y%m%dT%H%M%S`, `%Y%m%dT%H`, `%Y%m%d` or `%Yw%W`, so they
+/// can't be compared as strings across kinds. Modification time can't be
+/// used either as snapshots of the same schedule are hardlinked.
+fn stamp_time(stamp: &str) -> Option<i64> {
+    fn num(s: &str) -> Option<i64> {
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    }
+    if !stamp.is_ascii() {
+        return None;
+    }
+    if let Some(pos) = stamp.find('w') {
+        let year = num(&stamp[..pos])?;
+        let week = num(&stamp[pos+1..])?;
+        // week 1 starts on the first monday, days before it are week 0
+        let jan1 = days_from_civil(year, 1, 1);
+        let weekday = ((jan1 + 3) % 7 + 7) % 7;  // zero is monday
+        let monday = jan1 + (7 - weekday) % 7;
+        let day = if week == 0 { jan1 } else { monday + (week - 1) * 7 };
+        return Some(day * 86400);
+    }
+    if stamp.len() < 8 {
+        return None;
+    }
+    let day = days_from_civil(num(&stamp[..4])?, num(&stamp[4..6])?,
+                              num(&stamp[6..8])?);
+    let time = &stamp[8..];
+    let secs = match time.len() {
+        0 => 0,
+        3 if time.starts_with('T') => num(&time[1..3])? * 3600,
+        7 if time.starts_with('T') => {
+            num(&time[1..3])? * 3600 + num(&time[3..5])? * 60 +
+                num(&time[5..7])?
+        }
+        _ => return None,
+    };
+    Some(day * 86400 + secs)
+}
+
+fn limit(policy: &Policy, kind: &str) -> usize {
+    match kind {
+        "change" => policy.per_change,
+        "hourly" => policy.hourly,
+        "daily" => policy.daily,
+        "weekly" => policy.weekly,
+        _ => unreachable!(),
+    }
+}
+
+/// Returns backups that should be deleted
+///
+/// Backups above the limit of their kind are deleted, except the last
+/// backup of a schedule. Then the oldest backups are deleted until the total
+/// size (hardlinks are counted once) fits `max_size`, the newest backup and
+/// the last backup of a schedule are always kept.
+fn prune(backups: &[Backup], policy: &Policy) -> Vec<usize> {
+    let mut deleted = HashSet::new();
+    let mut copies = HashMap::new();
+    for b in backups {
+        *copies.entry(key(b)).or_insert(0) += 1;
+    }
+    let mut candidates = Vec::new();
+    for kind in KINDS {
+        let items = backups.iter().enumerate()
+            .filter(|&(_, b)| b.kind == *kind)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let num = items.len().saturating_sub(limit(policy, kind));
+        candidates.extend(&items[..num]);
+    }
+    candidates.sort_by_key(|&i| stamp_time(&backups[i].stamp));
+    for i in candidates {
+        let num = copies.get_mut(&key(&backups[i])).unwrap();
+        if *num > 1 {
+            *num -= 1;
+            deleted.insert(i);
+        }
+    }
+    if policy.max_size > 0 {
+        let mut links = HashMap::new();
+        let mut sizes = HashMap::new();
+        for (i, b) in backups.iter().enumerate() {
+            if !deleted.contains(&i) {
+                *links.entry(b.inode).or_insert(0) += 1;
+                sizes.insert(b.inode, b.size);
+            }
+        }
+        let mut total: u64 = sizes.values().sum();
+        let mut by_age = (0..backups.len())
+            .filter(|i| !deleted.contains(i))
+            .collect::<Vec<_>>();
+        by_age.sort_by_key(|&i| stamp_time(&backups[i].stamp));
+        by_age.pop();  // newest backup is never deleted
+        for i in by_age {
+            if total <= policy.max_size {
+                break;
+            }
+            let num = copies.get_mut(&key(&backups[i])).unwrap();
+            if *num <= 1 {
+                continue;
+            }
+            *num -= 1;
+            let num_links = links.get_mut(&backups[i].inode).unwrap();
+            *num_links -= 1;
+            if *num_links == 0 {
+                total -= backups[i].size;
+            }
+            deleted.insert(i);
+        }
+        if total > policy.max_size {
+            warn!("Backups take {} bytes which is more than max_size of {} \
+                bytes, but the rest are the last backups of their schedules",
+                total, policy.max_size);
+        }
+    }
+    let mut result = deleted.into_iter().collect::<Vec<_>>();
+    result.sort();
+    return result;
+}
+
+/// Makes snapshots of `schedule.json` which are due and prunes old ones
+pub fn maintain(dir: &Path, hash: &str, policy: &Policy)
+    -> Result<(), Error>
+{
+    let backups = list(dir);
+    let now = now_utc();
+    let mut needed = Vec::new();
+    for kind in KINDS {
+        if limit(policy, kind) == 0 {
+            continue;
+        }
+        let last = backups.iter().filter(|b| b.kind == *kind).last();
+        let stamp = match *kind {
+            "change" => {
+                if last.and_then(|b| b.hash.as_ref())
+                    .map(|h| h == hash).unwrap_or(false)
+                {
+                    continue;
+                }
+                now.strftime("%Y%m%dT%H%M%S")?.to_string()
+            }
+            "hourly" => now.strftime("%Y%m%dT%H")?.to_string(),
+            "daily" => now.strftime("%Y%m%d")?.to_string(),
+            "weekly" => now.strftime("%Yw%W")?.to_string(),
+            _ => unreachable!(),
+        };
+        if last.map(|b| b.stamp == stamp).unwrap_or(false) {
+            continue;
+        }
+        needed.push(dir.join(format!("{}-{}-{}.json.gz", kind, stamp, hash)));
+    }
+    if !needed.is_empty() {
+        // schedules are the same, so reuse the file if possible
+        let existing = backups.iter()
+            .find(|b| b.hash.as_ref().map(|h| h == hash).unwrap_or(false))
+            .map(|b| b.path.clone());
+        let tmp_path = dir.join("backup.tmp");
+        let source = match existing {
+            Some(path) => path,
+            None => {
+                let infile = File::open(&dir.join("schedule.json"))?;
+                let mut outfile = GzEncoder::new(
+                    File::create(&tmp_path)?,
+                    compression(policy)?);
+                io::copy(&mut {infile}, &mut outfile)?;
+                outfile.finish()?;
+                tmp_path.clone()
+            }
+        };
+        for dest in &needed {
+            hard_link(&source, dest)
+                .map_err(|e| error!("Error hardlinking snapshot: {}", e)).ok();
+        }
+        if source == tmp_path {
+            remove_file(&tmp_path)
+                .map_err(|e| error!("Error removing {:?}: {}", tmp_path, e))
+                .ok();
+        }
+    }
+    let backups = list(dir);
+    for i in prune(&backups, policy) {
+        let ref path = backups[i].path;
+        debug!("Removing backup {:?}", path);
+        remove_file(path)
+            .map_err(|e| error!("Error removing {:?}: {}", path, e)).ok();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::time::{UNIX_EPOCH, Duration};
+    use super::{Backup, Policy, prune, stamp_time};
+
+    fn backup(kind: &str, time: u64, hash: &str, inode: u64) -> Backup {
+        let stamp = format!("20180101T{:02}", time);
+        Backup {
+            name: format!("{}-{}-{}", kind, stamp, hash),
+            kind: kind.to_string(),
+            hash: Some(hash.to_string()),
+            size: 100,
+            // mtime is shared by hardlinks, so it's not used for ordering
+            timestamp: UNIX_EPOCH + Duration::from_secs(100 - time),
+            stamp: stamp,
+            path: PathBuf::from("/nonexistent"),
+            inode: (1, inode),
+        }
+    }
+
+    fn policy(limit: usize, max_size: u64) -> Policy {
+        Policy {
+            hourly: limit,
+            daily: limit,
+            weekly: limit,
+            per_change: limit,
+            max_size: max_size,
+            compression: "best".into(),
+        }
+    }
+
+    #[test]
+    fn keeps_last_copy_of_schedule() {
+        let backups = vec![
+            backup("hourly", 1, "a", 1),
+            backup("hourly", 2, "b", 2),
+            backup("hourly", 3, "b", 2),
+            backup("hourly", 4, "c", 3),
+        ];
+        assert_eq!(prune(&backups, &policy(1, 0)), vec![1]);
+    }
+
+    #[test]
+    fn max_size() {
+        let backups = vec![
+            backup("daily", 2, "b", 2),
+            backup("hourly", 1, "a", 1),
+            backup("hourly", 2, "b", 2),
+            backup("hourly", 3, "c", 3),
+            // a copy which is not hardlinked
+            backup("weekly", 1, "a", 4),
+        ];
+        // hardlinks of "b" take space once
+        assert_eq!(prune(&backups, &policy(10, 400)), Vec::<usize>::new());
+        assert_eq!(prune(&backups, &policy(10, 300)), vec![1]);
+        // the last backups of "a" and "b" are kept over the limit
+        assert_eq!(prune(&backups, &policy(10, 200)), vec![0, 1]);
+        assert_eq!(prune(&backups, &policy(10, 1)), vec![0, 1]);
+    }
+
+    #[test]
+    fn stamps() {
+        assert_eq!(stamp_time("19700101"), Some(0));
+        assert_eq!(stamp_time("19700102T01"), Some(90000));
+        assert_eq!(stamp_time("19700102T010203"), Some(90123));
+        // 2018 starts on monday, so it's the first day of week 1
+        assert_eq!(stamp_time("2018w01"), stamp_time("20180101"));
+        assert_eq!(stamp_time("2018w35"), stamp_time("20180827"));
+        // 1970 starts on thursday
+        assert_eq!(stamp_time("1970w00"), Some(0));
+        assert_eq!(stamp_time("1970w01"), stamp_time("19700105"));
+        assert!(stamp_time("2018w35") < stamp_time("20180901"));
+        assert_eq!(stamp_time("1"), None);
+        assert_eq!(stamp_time("20180101T1"), None);
+    }
+}
@@ -0,0 +1,118 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use futures::sync::oneshot;
+use juniper::{FieldError, Value, InputValue};
+use serde_json::{Value as Json, from_str};
+
+use actions::{ActionRecord, ActionStatus, Filter};
+use frontend::graphql::{Timestamp, ContextRef};
+use frontend::status::convert_ref;
+use shared::{PushActionError, CancelActionError};
+
+
+pub struct Action(pub ActionRecord);
+pub struct ActionData(Json);
+
+fn timestamp(millis: u64) -> Timestamp {
+    Timestamp(UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+graphql_object!(Action: () as "Action" |&self| {
+    description: "Action submitted to the leader"
+    field id() -> String {
+        self.0.id.to_string()
+    }
+    field submitter() -> &str {
+        &self.0.submitter
+    }
+    field submitted() -> Timestamp {
+        timestamp(self.0.submitted)
+    }
+    field started() -> Option<Timestamp> {
+        self.0.started.map(timestamp)
+    }
+    field finished() -> Option<Timestamp> {
+        self.0.finished.map(timestamp)
+    }
+    field status() -> ActionStatus {
+        self.0.status
+    }
+    field idempotency_key() -> Option<&str> {
+        self.0.idempotency_key.as_ref().map(|x| &x[..])
+    }
+    field data() -> ActionData {
+        ActionData((*self.0.data).clone())
+    }
+    field result() -> Option<ActionData> {
+        self.0.result.clone().map(ActionData)
+    }
+});
+
+graphql_scalar!(ActionData as "ActionData" {
+    description: "arbitrary json data of the action or its result"
+    resolve(&self) -> Value {
+        convert_ref(&self.0)
+    }
+    from_input_value(_val: &InputValue) -> Option<ActionData> {
+        unimplemented!();
+    }
+});
+
+pub fn get(ctx: &ContextRef, id: &str)
+    -> Result<Option<Action>, FieldError>
+{
+    let id = id.parse().map_err(|_| "invalid action id")?;
+    Ok(ctx.state.action(id).map(Action))
+}
+
+pub fn list(ctx: &ContextRef, status: Option<ActionStatus>,
+    submitter: Option<String>, idempotency_key: Option<String>,
+    since: Option<f64>, limit: Option<i32>)
+    -> Vec<Action>
+{
+    let filter = Filter {
+        status,
+        submitter,
+        idempotency_key,
+        since: since.map(|x| x as u64),
+        limit: limit.map(|x| x.max(0) as usize),
+    };
+    ctx.state.actions(&filter).into_iter().map(Action).collect()
+}
+
+pub fn push(ctx: &ContextRef, data: &str, idempotency_key: Option<String>)
+    -> Result<Action, FieldError>
+{
+    let data = from_str::<Json>(data)
+        .map_err(|e| format!("data must be valid json: {}", e))?;
+    info!("Received action from graphql: {}", data);
+    let (tx, _) = oneshot::channel();
+    let id = match ctx.state.push_action(data, "graphql".into(),
+                                         idempotency_key, tx)
+    {
+        Ok(id) => id,
+        Err(PushActionError::TooManyRequests) => {
+            return Err("too many requests".into());
+        }
+        Err(PushActionError::NotALeader) => {
+            return Err("not a leader".into());
+        }
+    };
+    ctx.state.action(id).map(Action)
+        .ok_or_else(|| "action is lost".into())
+}
+
+pub fn cancel(ctx: &ContextRef, id: &str) -> Result<Action, FieldError> {
+    let id = id.parse().map_err(|_| "invalid action id")?;
+    match ctx.state.cancel_action(id) {
+        Ok(action) => Ok(Action(action)),
+        Err(CancelActionError::NotALeader) => Err("not a leader".into()),
+        Err(CancelActionError::NotFound) => Err("action not found".into()),
+        Err(CancelActionError::AlreadyStarted) => {
+            Err("action is already started".into())
+        }
+        Err(CancelActionError::AlreadyFinished(status)) => {
+            Err(format!("action is already finished: {:?}", status).into())
+        }
+    }
+}
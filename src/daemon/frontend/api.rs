@@ -1,11 +1,12 @@
 use std::collections::{HashMap, HashSet};
-use std::io::{Write, BufWriter};
+use std::io::{self, Write, BufWriter};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{SystemTime, Duration};
 
 use futures::future::{FutureResult, ok, Future};
 use futures::future::{loop_fn, Loop::{Break, Continue}};
+use failure;
 use futures::sync::oneshot;
 use gron::json_to_gron;
 use juniper;
@@ -15,9 +16,13 @@ use serde_millis;
 use tk_easyloop::timeout;
 use tk_http::Status::{self, NotFound, PermanentRedirect};
 use tk_http::Status::{TooManyRequests, ServiceUnavailable, InternalServerError};
+use tk_http::Status::{Conflict};
 use tk_http::server::{Codec as CodecTrait};
 use tk_http::server::{Encoder, EncoderDone, Error};
 
+use apply::{RoleRetry, Rollback};
+use backups;
+use config::RenderResult;
 use elect::{ElectionState};
 use fetch;
 use frontend::error_page::{error_page};
@@ -26,7 +31,8 @@ use frontend::to_json::ToJson;
 use frontend::{Config, reply, read_json};
 use id::Id;
 use query::QueryData;
-use shared::{SharedState, PushActionError};
+use scheduler::Pin;
+use shared::{SharedState, PushActionError, CancelActionError};
 
 
 pub type Request<S> = Box<CodecTrait<S, ResponseFuture=Reply<S>>>;
@@ -38,6 +44,12 @@ lazy_static! {
 }
 
 
+fn is_not_found(err: &failure::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .map(|e| e.kind() == io::ErrorKind::NotFound)
+        .unwrap_or(false)
+}
+
 fn get_metrics() -> HashMap<&'static str, Value>
 {
     use scheduler::main as S;
@@ -214,6 +226,9 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                     num_errors: usize,
                     errors: &'a HashMap<&'static str, String>,
                     failed_roles: &'a HashSet<String>,
+                    render_results: &'a HashMap<String, Arc<RenderResult>>,
+                    role_retries: &'a HashMap<String, RoleRetry>,
+                    role_rollbacks: &'a HashMap<String, Rollback>,
                     debug_force_leader: bool,
                     //self_report: Option<self_meter::Report>,
                     //threads_report: HashMap<String, self_meter::ThreadReport>,
@@ -222,6 +237,7 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                     election_state: &'a Arc<ElectionState>,
                     schedule_id: Option<&'a String>,
                     schedule_status: &'a str,
+                    pinned: Option<&'a Pin>,
                     default_frontend: &'a str,
                 }
                 let peers = state.peers();
@@ -271,6 +287,9 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                 };
                 let errors = state.errors();
                 let failed_roles = state.failed_roles();
+                let render_results = state.render_results();
+                let role_retries = state.role_retries();
+                let role_rollbacks = state.role_rollbacks();
                 //let (me, thr) = {
                 //    let meter = meter.lock().unwrap();
                 //    (meter.report(),
@@ -291,6 +310,9 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                     num_errors: errors.len() + failed_roles.len(),
                     errors: &*errors,
                     failed_roles: &*failed_roles,
+                    render_results: &*render_results,
+                    role_retries: &*role_retries,
+                    role_rollbacks: &*role_rollbacks,
                     debug_force_leader: state.debug_force_leader(),
                     //self_report: me,
                     //threads_report: thr,
@@ -299,6 +321,8 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                     election_state: &election,
                     schedule_id: stable_schedule.as_ref().map(|x| &x.hash),
                     schedule_status: status,
+                    pinned: stable_schedule.as_ref()
+                        .and_then(|x| x.pinned.as_ref()),
                     default_frontend: &config.default_frontend,
                 }))
             }))
@@ -379,6 +403,24 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                 }
             }))
         }
+        SchedulerShadow => {
+            Ok(reply(move |e| {
+                match state.shadow_scheduler() {
+                    Some(status) => Box::new(respond(e, format, &*status)),
+                    None => Box::new(error_page(NotFound, e)),
+                }
+            }))
+        }
+        PromoteShadowScheduler => {
+            let found = state.request_shadow_promotion();
+            Ok(reply(move |e| {
+                if found {
+                    Box::new(respond(e, format, "ok"))
+                } else {
+                    Box::new(error_page(NotFound, e))
+                }
+            }))
+        }
         Election => {
             Ok(reply(move |e| {
                 Box::new(respond(e, format, &*state.election()))
@@ -389,17 +431,53 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                 Box::new(respond(e, format, &state.pending_actions()))
             }))
         }
+        PinBackup(ref name) => {
+            let result = if state.election().is_leader {
+                backups::read(&config.schedule_dir, name)
+                    .map(|schedule| Some(state.pin_schedule(name, schedule)))
+            } else {
+                Ok(None)
+            };
+            Ok(reply(move |e| {
+                match result {
+                    Ok(Some(true)) => Box::new(respond(e, format, "ok")),
+                    Ok(Some(false)) | Ok(None) => {
+                        Box::new(error_page(ServiceUnavailable, e))
+                    }
+                    Err(ref err) if is_not_found(err) => {
+                        Box::new(error_page(NotFound, e))
+                    }
+                    Err(err) => {
+                        error!("Can't read backup: {}", err);
+                        Box::new(error_page(InternalServerError, e))
+                    }
+                }
+            }))
+        }
+        UnpinSchedule => {
+            let found = state.unpin_schedule();
+            Ok(reply(move |e| {
+                if found {
+                    Box::new(respond(e, format, "ok"))
+                } else {
+                    Box::new(error_page(ServiceUnavailable, e))
+                }
+            }))
+        }
         ForceRenderAll => {
             state.force_render();
             Ok(reply(move |e| {
                 Box::new(respond(e, format, "ok"))
             }))
         }
-        PushAction => {
+        PushAction(ref key) => {
+            let key = key.clone();
             Ok(read_json(move |input: Value, e| {
                 actions::log(source, &input);
                 let (tx, _) = oneshot::channel();
-                match state.push_action(input, tx) {
+                match state.push_action(input, source.to_string(),
+                                        key.clone(), tx)
+                {
                     Ok(id) => {
                         #[derive(Serialize)]
                         struct Registered {
@@ -420,11 +498,14 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                 }
             }))
         }
-        WaitAction => {
+        WaitAction(ref key) => {
+            let key = key.clone();
             Ok(read_json(move |input: Value, e| {
                 actions::log(source, &input);
                 let (tx, rx) = oneshot::channel();
-                match state.push_action(input, tx) {
+                match state.push_action(input, source.to_string(),
+                                        key.clone(), tx)
+                {
                     Ok(_id) => {
                         use shared::ActionError::*;
                         Box::new(
@@ -436,6 +517,9 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                                 Ok(Err(NoResponse)) => {
                                     respond_204(e)
                                 }
+                                Ok(Err(Cancelled)) => {
+                                    error_page(Conflict, e)
+                                }
                                 Err(_) => {
                                     error_page(ServiceUnavailable, e)
                                 }
@@ -452,6 +536,38 @@ pub fn serve<S: 'static>(state: &SharedState, config: &Arc<Config>,
                 }
             }))
         }
+        Action(id) => {
+            Ok(reply(move |e| {
+                match state.action(id) {
+                    Some(action) => Box::new(respond(e, format, &action)),
+                    None => Box::new(error_page(NotFound, e)),
+                }
+            }))
+        }
+        Actions(ref filter) => {
+            let filter = filter.clone();
+            Ok(reply(move |e| {
+                Box::new(respond(e, format, &state.actions(&filter)))
+            }))
+        }
+        CancelAction(id) => {
+            let result = state.cancel_action(id);
+            Ok(reply(move |e| {
+                match result {
+                    Ok(ref action) => Box::new(respond(e, format, action)),
+                    Err(CancelActionError::NotALeader) => {
+                        Box::new(error_page(ServiceUnavailable, e))
+                    }
+                    Err(CancelActionError::NotFound) => {
+                        Box::new(error_page(NotFound, e))
+                    }
+                    Err(CancelActionError::AlreadyStarted) |
+                    Err(CancelActionError::AlreadyFinished(..)) => {
+                        Box::new(error_page(Conflict, e))
+                    }
+                }
+            }))
+        }
         ActionIsPending(id) => {
             Ok(reply(move |e| {
                 #[derive(Serialize)]
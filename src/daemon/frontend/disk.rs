@@ -1,7 +1,6 @@
 use std::path::{PathBuf, Path};
 use std::sync::Arc;
 
-use capturing_glob::{glob_with, MatchOptions};
 use futures::{Future, Async};
 use futures::future::{ok, FutureResult, Either, loop_fn, Loop};
 use futures_cpupool::{CpuPool, CpuFuture};
@@ -10,6 +9,7 @@ use tk_http::server;
 use tk_http::Status;
 use http_file_headers::{Input, Output, Config};
 
+use backups;
 use frontend::Request;
 use frontend::routing::Format;
 use frontend::quick_reply::{reply};
@@ -255,35 +255,10 @@ pub fn list_backups<S>(schedule_dir: &Path, format: Format)
     -> Result<Request<S>, server::Error>
     where S: AsyncWrite + Send + 'static
 {
-    let dir = schedule_dir.to_str()
-        .expect("schedule_dir is utf-8")
-        .to_string();
+    let dir = schedule_dir.to_path_buf();
     Ok(reply(move |e| {
         Box::new(POOL.spawn_fn(move || {
-            let items = glob_with(
-                &format!("{}/(*-*).json.gz", dir), &MatchOptions {
-                    case_sensitive: true,
-                    require_literal_separator: true,
-                    require_literal_leading_dot: true,
-                })
-                .map(|items| {
-                    items.filter_map(|e| {
-                        e.map_err(|e| {
-                            error!("Error listing backups: {}", e);
-                        }).ok()
-                    })
-                    .filter_map(|e| {
-                        e.group(1)
-                        .and_then(|x| x.to_str())
-                        .map(|x| x.to_string())
-                    })
-                    .collect::<Vec<_>>()
-                })
-                .unwrap_or_else(|e| {
-                    error!("Error listing backups: {}", e);
-                    Vec::new()
-                });
-            Ok(items)
+            Ok(backups::list(&dir))
         })
         .and_then(move |items| respond(e, format, items)))
     }))
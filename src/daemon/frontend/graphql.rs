@@ -15,6 +15,9 @@ use frontend::routing::Format;
 use frontend::error_page::{error_page};
 use frontend::api::{respond};
 use frontend::status;
+use frontend::actions;
+use backups;
+use actions::ActionStatus;
 use shared::SharedState;
 
 
@@ -68,12 +71,54 @@ graphql_object!(<'a> &'a Query: ContextRef<'a> as "Query" |&self| {
     field status(&executor) -> Result<status::GData, FieldError> {
         status::graph(executor.context())
     }
+    field action(&executor, id: String)
+        -> Result<Option<actions::Action>, FieldError>
+    {
+        actions::get(executor.context(), &id)
+    }
+    field actions(&executor, status: Option<ActionStatus>,
+        submitter: Option<String>, idempotency_key: Option<String>,
+        since: Option<f64>, limit: Option<i32>)
+        -> Vec<actions::Action>
+    {
+        actions::list(executor.context(),
+            status, submitter, idempotency_key, since, limit)
+    }
 });
 
 graphql_object!(<'a> &'a Mutation: ContextRef<'a> as "Mutation" |&self| {
     field noop(&executor) -> Result<Okay, FieldError> {
         Ok(Okay { ok: true })
     }
+    field push_action(&executor, data: String,
+        idempotency_key: Option<String>)
+        -> Result<actions::Action, FieldError>
+    {
+        actions::push(executor.context(), &data, idempotency_key)
+    }
+    field cancel_action(&executor, id: String)
+        -> Result<actions::Action, FieldError>
+    {
+        actions::cancel(executor.context(), &id)
+    }
+    field pin_backup(&executor, name: String) -> Result<Okay, FieldError> {
+        let ctx = executor.context();
+        if !ctx.state.election().is_leader {
+            return Err("not a leader".into());
+        }
+        let schedule = backups::read(&ctx.config.schedule_dir, &name)
+            .map_err(|e| format!("can't read backup: {}", e))?;
+        if !ctx.state.pin_schedule(&name, schedule) {
+            return Err("not a leader".into());
+        }
+        Ok(Okay { ok: true })
+    }
+    field unpin_schedule(&executor) -> Result<Okay, FieldError> {
+        if !executor.context().state.unpin_schedule() {
+            return Err("not a leader".into());
+        }
+        Ok(Okay { ok: true })
+    }
 });
 
 graphql_scalar!(Timestamp {
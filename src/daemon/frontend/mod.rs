@@ -10,6 +10,7 @@ use tokio_io::{AsyncRead, AsyncWrite};
 
 use shared::{SharedState};
 
+mod actions;
 mod api;
 mod log;
 mod disk;
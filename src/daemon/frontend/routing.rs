@@ -3,6 +3,8 @@ use std::path::Component::Normal;
 
 use tk_http::server::{Head, WebsocketHandshake};
 
+use actions::{Filter, ActionStatus};
+
 #[derive(Clone, Debug)]
 pub struct Query {
     pub path: String,
@@ -18,11 +20,19 @@ pub enum ApiRoute {
     Schedule,
     SchedulerInput,
     SchedulerDebugInfo,
+    SchedulerShadow,
+    PromoteShadowScheduler,
     Election,
     Backups,
     Backup(String),
-    PushAction,
-    WaitAction,
+    PinBackup(String),
+    UnpinSchedule,
+    /// Argument is the value of `Idempotency-Key` header
+    PushAction(Option<String>),
+    WaitAction(Option<String>),
+    Action(u64),
+    CancelAction(u64),
+    Actions(Filter),
     ActionIsPending(u64),
     PendingActions,
     RolesData,
@@ -121,8 +131,51 @@ fn parse_log_route(path: &str) -> Option<LogRoute> {
     }
 }
 
-fn parse_api(path: &str, content_type: Option<&[u8]>,
-    ws: Option<WebsocketHandshake>)
+fn decode_component(s: &str) -> Option<String> {
+    let mut buf = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => buf.push(b' '),
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                let hex = ::std::str::from_utf8(&hex).ok()?;
+                buf.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b => buf.push(b),
+        }
+    }
+    String::from_utf8(buf).ok()
+}
+
+fn parse_filter(query: &str) -> Option<Filter> {
+    let mut filter = Filter::default();
+    for pair in query.split('&').filter(|x| !x.is_empty()) {
+        let mut pair = pair.splitn(2, '=');
+        let key = pair.next().unwrap_or("");
+        let value = decode_component(pair.next().unwrap_or(""))?;
+        match key {
+            "status" => {
+                filter.status = Some(match &value[..] {
+                    "pending" => ActionStatus::Pending,
+                    "done" => ActionStatus::Done,
+                    "no_response" => ActionStatus::NoResponse,
+                    "cancelled" => ActionStatus::Cancelled,
+                    _ => return None,
+                });
+            }
+            "submitter" => filter.submitter = Some(value),
+            "idempotency_key" => filter.idempotency_key = Some(value),
+            "since" => filter.since = Some(value.parse().ok()?),
+            "limit" => filter.limit = Some(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some(filter)
+}
+
+fn parse_api(path: &str, query: &str, content_type: Option<&[u8]>,
+    idempotency_key: Option<String>, ws: Option<WebsocketHandshake>)
     -> Option<Route>
 {
     use self::Route::*;
@@ -142,22 +195,45 @@ fn parse_api(path: &str, content_type: Option<&[u8]>,
         ("schedule", "") => Some(Api(Schedule, api_suffix(path))),
         ("scheduler_input", "") => Some(Api(SchedulerInput, api_suffix(path))),
         ("scheduler_debug_info", "") => Some(Api(SchedulerDebugInfo, Plain)),
+        ("scheduler_shadow", "") => {
+            Some(Api(SchedulerShadow, api_suffix(path)))
+        }
+        ("scheduler_shadow", "promote") => {
+            Some(Api(PromoteShadowScheduler, Plain))
+        }
         ("election", "") => Some(Api(Election, api_suffix(path))),
         ("backups", "") => Some(Api(Backups, api_suffix(path))),
-        ("backup", name) => {
-            if name.chars().all(|x| x.is_alphanumeric() || x == '-') {
+        ("backup", tail) => match path_component(tail) {
+            (name, _) if name.is_empty() ||
+                !name.chars().all(|x| x.is_alphanumeric() || x == '-')
+            => None,
+            (name, "") => {
                 Some(Api(Backup(name.to_string()), api_suffix(path)))
-            } else {
-                None
             }
-        }
+            (name, "pin") => Some(Api(PinBackup(name.to_string()), Plain)),
+            _ => None,
+        },
+        ("unpin_schedule", "") => Some(Api(UnpinSchedule, Plain)),
 
         ("action", "") if content_type == Some(b"application/json")
-            => Some(Api(PushAction, api_suffix(path))),
+            => Some(Api(PushAction(idempotency_key), api_suffix(path))),
         ("action", "") => Some(Route::BadContentType),
+        ("action", tail) => match path_component(tail) {
+            (id, "cancel") => {
+                id.parse().map(|x| Api(CancelAction(x), Plain)).ok()
+            }
+            (id, "") => {
+                id.parse().map(|x| Api(Action(x), api_suffix(path))).ok()
+            }
+            _ => None,
+        },
+        ("actions", "") => match parse_filter(query) {
+            Some(filter) => Some(Api(Actions(filter), api_suffix(path))),
+            None => Some(Route::BadRequest),
+        },
 
         ("wait_action", "") if content_type == Some(b"application/json")
-            => Some(Api(WaitAction, api_suffix(path))),
+            => Some(Api(WaitAction(idempotency_key), api_suffix(path))),
         ("wait_action", "") => Some(Route::BadContentType),
 
         ("force_render_all", "") => Some(Api(ForceRenderAll, Plain)),
@@ -189,9 +265,9 @@ pub fn route(head: &Head) -> Route {
     } else {
         return Route::NotFound;
     };
-    let path = match path.find('?') {
-        Some(x) => &path[..x],
-        None => path,
+    let (path, query) = match path.find('?') {
+        Some(x) => (&path[..x], &path[x+1..]),
+        None => (path, ""),
     };
     let route = match path_component(&path[..]) {
         ("", _) => Some(CommonIndex),
@@ -204,13 +280,18 @@ pub fn route(head: &Head) -> Route {
                 }
             };
             let mut content_type = None;
+            let mut idempotency_key = None;
             for (name, value) in head.headers() {
                 if name.eq_ignore_ascii_case("Content-Type") {
                     content_type = Some(value);
-                    break;
+                } else if name.eq_ignore_ascii_case("Idempotency-Key") {
+                    match String::from_utf8(value.to_vec()) {
+                        Ok(key) => idempotency_key = Some(key),
+                        Err(_) => return Route::BadRequest,
+                    }
                 }
             }
-            parse_api(suffix, content_type, up)
+            parse_api(suffix, query, content_type, idempotency_key, up)
         }
         (dir, suffix) if dir.starts_with("~") => {
             if !validate_path(&path[2..]) {
@@ -6,6 +6,8 @@ use juniper::{FieldError};
 use self_meter_http::Meter;
 use serde_json;
 
+use apply;
+use config::render_result::{RenderResult, ActionResult, CommandResult};
 use id::Id;
 use peer;
 use elect::ElectionState;
@@ -35,8 +37,14 @@ pub struct Peer(Arc<peer::Peer>);
 pub struct Election(Arc<ElectionState>);
 pub struct Schedule(Arc<scheduler::Schedule>);
 pub struct ScheduleData(Arc<scheduler::Schedule>);
+pub struct Pin(scheduler::Pin);
 pub struct FetchState(Arc<fetch::PublicState>);
 pub struct Roles<'a>(&'a ContextRef<'a>);
+pub struct RoleRender(String, Arc<RenderResult>);
+pub struct RoleRetry(String, apply::RoleRetry);
+pub struct RoleRollback(String, apply::Rollback);
+pub struct RenderAction(ActionResult);
+pub struct RenderCommand(CommandResult);
 
 graphql_object!(<'a> GData<'a>: () as "Status" |&self| {
     description: "Status data for the verwalter itself"
@@ -176,6 +184,75 @@ graphql_object!(<'a> Roles<'a>: () as "Roles" |&self| {
         vec.sort_unstable();
         return vec;
     }
+    field retries() -> Vec<RoleRetry> {
+        let mut vec = self.0.state.role_retries().iter()
+            .map(|(name, retry)| RoleRetry(name.clone(), retry.clone()))
+            .collect::<Vec<_>>();
+        vec.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        return vec;
+    }
+    field rollbacks() -> Vec<RoleRollback> {
+        let mut vec = self.0.state.role_rollbacks().iter()
+            .map(|(name, r)| RoleRollback(name.clone(), r.clone()))
+            .collect::<Vec<_>>();
+        vec.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        return vec;
+    }
+    field render_results() -> Vec<RoleRender> {
+        let mut vec = self.0.state.render_results().iter()
+            .map(|(name, res)| RoleRender(name.clone(), res.clone()))
+            .collect::<Vec<_>>();
+        vec.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        return vec;
+    }
+});
+
+graphql_object!(RoleRetry: () as "RoleRetry" |&self| {
+    description: "Retry state of a failed role"
+    field role() -> &str { &self.0 }
+    field attempts() -> i32 { self.1.attempts as i32 }
+    field next_attempt() -> Option<Timestamp> {
+        self.1.next_attempt.map(Timestamp)
+    }
+});
+
+graphql_object!(RoleRollback: () as "RoleRollback" |&self| {
+    description: "Role rolled back to the variables of an older schedule"
+    field role() -> &str { &self.0 }
+    field active_schedule() -> &str { &self.1.active_schedule }
+    field failed_schedule() -> &str { &self.1.failed_schedule }
+    field timestamp() -> Timestamp { Timestamp(self.1.timestamp) }
+});
+
+graphql_object!(RoleRender: () as "RoleRender" |&self| {
+    description: "Result of the last render of the role"
+    field role() -> &str { &self.0 }
+    field status() -> &str { self.1.status.as_str() }
+    field duration_ms() -> f64 { self.1.duration_ms as f64 }
+    field error() -> Option<&str> { self.1.error.as_ref().map(|x| &x[..]) }
+    field actions() -> Vec<RenderAction> {
+        self.1.actions.iter().cloned().map(RenderAction).collect()
+    }
+});
+
+graphql_object!(RenderAction: () as "RenderAction" |&self| {
+    field name() -> &str { &self.0.name }
+    field status() -> &str { self.0.status.as_str() }
+    field duration_ms() -> f64 { self.0.duration_ms as f64 }
+    field files() -> Vec<String> {
+        self.0.files.iter().map(|x| x.display().to_string()).collect()
+    }
+    field changed() -> bool { self.0.changed }
+    field commands() -> Vec<RenderCommand> {
+        self.0.commands.iter().cloned().map(RenderCommand).collect()
+    }
+});
+
+graphql_object!(RenderCommand: () as "RenderCommand" |&self| {
+    field command() -> &str { &self.0.command }
+    field status() -> &str { self.0.status.as_str() }
+    field duration_ms() -> f64 { self.0.duration_ms as f64 }
+    field error() -> Option<&str> { self.0.error.as_ref().map(|x| &x[..]) }
 });
 
 graphql_object!(LeaderInfo: () as "Leader" |&self| {
@@ -227,6 +304,16 @@ graphql_object!(Schedule: () as "Schedule" |&self| {
     field origin() -> &Id {
         &self.0.origin
     }
+    field pinned() -> Option<Pin> {
+        self.0.pinned.clone().map(Pin)
+    }
+});
+
+graphql_object!(Pin: () as "Pin" |&self| {
+    description: "Backup pinned as the active schedule"
+    field backup() -> &str { &self.0.backup }
+    field hash() -> &str { &self.0.hash }
+    field since() -> Timestamp { Timestamp(self.0.since) }
 });
 
 graphql_object!(FetchState: () as "Fetch" |&self| {
@@ -261,7 +348,7 @@ fn convert(val: serde_json::Value) -> ::juniper::Value {
     }
 }
 
-fn convert_ref(val: &serde_json::Value) -> ::juniper::Value {
+pub fn convert_ref(val: &serde_json::Value) -> ::juniper::Value {
     use serde_json::Value as I;
     use juniper::Value as O;
     match val {
@@ -4,8 +4,8 @@ extern crate async_slot;
 extern crate capturing_glob;
 extern crate cbor;
 extern crate crossbeam;
-extern crate deflate;
 extern crate env_logger;
+extern crate flate2;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate gron;
@@ -21,6 +21,7 @@ extern crate libcantal;
 extern crate nix;
 extern crate ns_router;
 extern crate ns_std_threaded;
+extern crate quire;
 extern crate rand;
 extern crate regex;
 extern crate scan_dir;
@@ -30,6 +31,7 @@ extern crate serde_cbor;
 extern crate serde_json;
 extern crate serde_millis;
 extern crate sha1;
+extern crate sha2;
 extern crate tempfile;
 extern crate time;
 extern crate tk_bufstream;
@@ -56,10 +58,12 @@ extern crate yaml_rust;
 extern crate indexed_log;
 extern crate verwalter_config as config;
 
+use std::collections::BTreeMap;
 use std::io::{stderr, Write};
 use std::path::PathBuf;
 use std::process::exit;
 use std::thread;
+use std::time::Duration;
 
 use futures::sync::mpsc::unbounded;
 use time::now_utc;
@@ -69,7 +73,9 @@ use shared::SharedState;
 use config::Sandbox;
 use id::Id;
 
+mod actions;
 mod apply;
+mod backups;
 mod cantal;
 mod elect;
 mod frontend;
@@ -85,13 +91,14 @@ mod scheduler;
 mod shared;
 mod time_util;
 mod watchdog;
+mod webhooks;
 mod wasm;
 mod fetch;
 mod failures;
 mod query;
 
 use argparse::{ArgumentParser, Parse, ParseOption, StoreOption, StoreTrue};
-use argparse::{Print, Store};
+use argparse::{Print, Store, Collect};
 
 #[derive(Clone)]
 pub struct Options {
@@ -109,6 +116,13 @@ pub struct Options {
     debug_force_leader: bool,
     allow_minority: bool,
     default_frontend: String,
+    shadow_scheduler_rounds: u32,
+    shadow_diff_hook: Option<PathBuf>,
+    labels: Vec<String>,
+    render_concurrency: usize,
+    render_retry_attempts: u32,
+    render_retry_delay: u64,
+    render_timeout: u64,
 }
 
 fn init_logging(id: &Id, log_id: bool) {
@@ -160,6 +174,13 @@ fn main() {
         debug_force_leader: false,
         allow_minority: false,
         default_frontend: "common".into(),
+        shadow_scheduler_rounds: 0,
+        shadow_diff_hook: None,
+        labels: Vec::new(),
+        render_concurrency: 1,
+        render_retry_attempts: 0,
+        render_retry_delay: 10,
+        render_timeout: 180,
     };
     {
         let mut ap = ArgumentParser::new();
@@ -222,6 +243,42 @@ fn main() {
         ap.refer(&mut options.default_frontend)
             .add_option(&["--default-frontend"], Store, "
                 Use this frontend as default one (default `common`).");
+        ap.refer(&mut options.shadow_scheduler_rounds)
+            .add_option(&["--shadow-scheduler-rounds"], Parse, "
+                When scheduler code changes, run new scheduler in shadow
+                mode (next to the current one) for this number of rounds
+                before promoting it. Zero (default) means use new code
+                immediately.");
+        ap.refer(&mut options.shadow_diff_hook)
+            .add_option(&["--shadow-diff-hook"], ParseOption, "
+                Executable that receives differences between current and
+                shadow scheduler as JSON on stdin. Shadow scheduler is
+                promoted automatically only if it exits successfully.");
+        ap.refer(&mut options.labels)
+            .add_option(&["--label"], Collect, "
+                Label of this node in form `key=value`, may be specified
+                multiple times. Labels are used to match `groups` of nodes
+                in the schedule.");
+        ap.refer(&mut options.render_concurrency)
+            .add_option(&["--render-concurrency"], Parse, "
+                Maximum number of roles rendered simultaneously (default 1).
+                Use `render_after` variable of a role to declare roles
+                it must be rendered after.");
+        ap.refer(&mut options.render_retry_attempts)
+            .add_option(&["--render-retry-attempts"], Parse, "
+                Number of times failed role is rendered again with the same
+                schedule (default 0, i.e. wait for the next schedule).");
+        ap.refer(&mut options.render_retry_delay)
+            .add_option(&["--render-retry-delay"], Parse, "
+                Delay in seconds before the first retry of a failed role
+                (default 10). Each next retry waits twice as long (up to
+                10 minutes), with a random jitter of 25%.");
+        ap.refer(&mut options.render_timeout)
+            .add_option(&["--render-timeout"], Parse, "
+                Default time limit for rendering a single role in seconds
+                (default 180). May be overriden by `render_timeout`
+                variable or by `render-timeout` file in template dir.
+                Process group of the renderer is killed on timeout.");
         ap.parse_args_or_exit();
     }
 
@@ -244,6 +301,21 @@ fn main() {
         }
     };
 
+    let mut labels = BTreeMap::new();
+    for label in &options.labels {
+        let mut pair = label.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) if !key.is_empty() => {
+                labels.insert(key.to_string(), value.to_string());
+            }
+            _ => {
+                writeln!(&mut stderr(),
+                    "Label {:?} must be in form `key=value`", label).ok();
+                exit(3);
+            }
+        }
+    }
+
     init_logging(&id, options.log_id);
 
     let meter = self_meter_http::Meter::new();
@@ -286,6 +358,7 @@ fn main() {
         query::Settings {
             id: id.clone(),
             hostname: hostname.clone(),
+            labels,
             config_dir: options.config_dir.clone(),
         });
 
@@ -294,10 +367,37 @@ fn main() {
         meter.spawn_scanner(&tk_easyloop::handle());
 
         let (front_tx, front_rx) = frontend::channel::new();
+        let hooks = match webhooks::read_hooks(&options.config_dir) {
+            Ok(hooks) => hooks,
+            Err(e) => {
+                error!("Can't read webhooks: {}", e);
+                exit(3);
+            }
+        };
+        let (hooks_tx, hooks_rx) = webhooks::channel();
         let state = SharedState::new(&id, &name, &hostname,
             options.clone(), sandbox, old_schedule, &responder,
-            tk_easyloop::handle().remote(), &meter, &front_tx);
+            tk_easyloop::handle().remote(), &meter, &front_tx, hooks_tx);
+
+        let webhooks_config = webhooks::Config {
+            node: id.to_string(),
+            hostname: hostname.clone(),
+            hooks,
+        };
+        let m1 = meter.clone();
+        thread::Builder::new().name(String::from("webhooks")).spawn(move || {
+            m1.track_current_thread_by_name();
+            webhooks::run(webhooks_config, hooks_rx);
+        }).expect("webhooks thread starts");
 
+        let backup_policy = match backups::read_policy(&options.config_dir)
+        {
+            Ok(policy) => policy,
+            Err(e) => {
+                error!("Can't read backup policy: {}", e);
+                exit(3);
+            }
+        };
         let apply_settings = apply::Settings {
             dry_run: options.dry_run,
             use_sudo: options.use_sudo,
@@ -305,6 +405,11 @@ fn main() {
             log_dir: options.log_dir.clone(),
             config_dir: options.config_dir.clone(),
             schedule_dir: schedule_dir.clone(),
+            render_concurrency: options.render_concurrency,
+            retry_attempts: options.render_retry_attempts,
+            retry_delay: Duration::from_secs(options.render_retry_delay),
+            render_timeout: Duration::from_secs(options.render_timeout),
+            backups: backup_policy,
         };
         let apply_state = state.clone();
         let m1 = meter.clone();
@@ -331,6 +436,8 @@ fn main() {
             id: id.clone(),
             hostname: hostname.clone(),
             config_dir: options.config_dir.clone(),
+            shadow_rounds: options.shadow_scheduler_rounds,
+            shadow_hook: options.shadow_diff_hook.clone(),
         };
 
         let m1 = meter.clone();
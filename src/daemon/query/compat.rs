@@ -1,11 +1,13 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use humantime::format_rfc3339;
+use capturing_glob::Pattern;
+use humantime::{format_rfc3339, parse_rfc3339};
 use failure::{Error, err_msg};
 use itertools::Itertools;
 use query::Settings;
+use regex::Regex;
 use serde_json::{Value as Json, Map};
 use scheduler::{Schedule};
 
@@ -15,6 +17,7 @@ use query::{RolesResult, QueryData};
 pub struct Responder {
     schedule: Arc<Schedule>,
     hostname: String,
+    labels: BTreeMap<String, String>,
 }
 
 impl Responder {
@@ -22,6 +25,7 @@ impl Responder {
         Responder {
             schedule: schedule.clone(),
             hostname: settings.hostname.clone(),
+            labels: settings.labels.clone(),
         }
     }
     pub fn render_roles(&self, _id: &str, _prev: Option<&Schedule>)
@@ -39,14 +43,38 @@ impl Responder {
             .and_then(|x| x.get("vars"))
             .and_then(|x| x.as_object())
             .unwrap_or(&empty);
+        let groups = self.schedule.data.as_object()
+            .and_then(|x| x.get("groups"))
+            .and_then(|y| y.as_object())
+            .unwrap_or(&empty);
+        let mut matched = Vec::new();
+        for (name, group) in groups {
+            let group = match group.as_object() {
+                Some(group) => group,
+                None => {
+                    warn!("Group {:?} must be an object, skipping", name);
+                    continue;
+                }
+            };
+            match match_group(&self.hostname, &self.labels, group) {
+                Ok(Some(reason)) => {
+                    debug!("Group {:?} matches: {}", name, reason);
+                    matched.push((name, group));
+                }
+                Ok(None) => debug!("Group {:?} doesn't match", name),
+                Err(e) => warn!("Group {:?} is skipped: {}", name, e),
+            }
+        }
         let node = self.schedule.data.as_object()
             .and_then(|x| x.get("nodes"))
             .and_then(|y| y.as_object())
             .and_then(|x| x.get(&self.hostname))
             .and_then(|y| y.as_object())
             .unwrap_or_else(|| {
-                warn!("Can't find `nodes[{}]` key in schedule\n",
-                    self.hostname);
+                if matched.is_empty() {
+                    warn!("Can't find `nodes[{}]` key in schedule \
+                        and no groups match\n", self.hostname);
+                }
                 &empty
             });
         let node_vars = node.get("vars")
@@ -55,37 +83,87 @@ impl Responder {
         let node_roles = node.get("roles")
             .and_then(|x| x.as_object())
             .unwrap_or(&empty);
+        let group_vars = matched.iter()
+            .map(|&(name, group)| (name, group.get("vars")
+                .and_then(|x| x.as_object())
+                .unwrap_or(&empty)))
+            .collect::<Vec<_>>();
+        let group_roles = matched.iter()
+            .map(|&(name, group)| (name, group.get("roles")
+                .and_then(|x| x.as_object())
+                .unwrap_or(&empty)))
+            .collect::<Vec<_>>();
 
+        let now = SystemTime::now();
         let mut to_render = BTreeMap::new();
-        for (role_name, ref node_role_vars) in node_roles.iter() {
-            let node_role_vars = node_role_vars.as_object().unwrap_or(&empty);
-            let role_vars = roles.get(role_name)
+        let mut next_render = BTreeMap::new();
+        let role_names = node_roles.keys()
+            .chain(group_roles.iter().flat_map(|&(_, r)| r.keys()))
+            .collect::<BTreeSet<_>>();
+        for role_name in role_names {
+            // Sources of variables, the former override the latter
+            let mut sources = Vec::new();
+            let mut trace = Vec::new();
+            if let Some(v) = node_roles.get(role_name)
+                .and_then(|x| x.as_object())
+            {
+                sources.push(v.iter());
+                trace.push(format!("nodes.{}.roles", self.hostname));
+            }
+            // Groups are sorted by name, so the last group wins
+            for &(name, roles) in group_roles.iter().rev() {
+                if let Some(v) = roles.get(role_name)
+                    .and_then(|x| x.as_object())
+                {
+                    sources.push(v.iter());
+                    trace.push(format!("groups.{}.roles", name));
+                }
+            }
+            sources.push(node_vars.iter());
+            trace.push(format!("nodes.{}.vars", self.hostname));
+            for &(name, vars) in group_vars.iter().rev() {
+                sources.push(vars.iter());
+                trace.push(format!("groups.{}.vars", name));
+            }
+            if let Some(v) = roles.get(role_name)
                 .and_then(|x| x.as_object())
-                .unwrap_or(&empty);
-            let mut cur_vars = merge_vars(vec![
-                node_role_vars.iter(),
-                node_vars.iter(),
-                role_vars.iter(),
-                vars.iter(),
-            ].into_iter());
+            {
+                sources.push(v.iter());
+                trace.push(format!("roles.{}", role_name));
+            }
+            sources.push(vars.iter());
+            trace.push(String::from("vars"));
+            debug!("Role {:?} variables from: {}",
+                role_name, trace.join(", "));
+            let mut cur_vars = merge_vars(sources.into_iter());
             cur_vars.insert(String::from("role"),
                 Json::String(role_name.clone()));
             if !cur_vars.contains_key("node") {
                 cur_vars.insert(String::from("node"),
                     Json::String(self.hostname.clone()));
             }
+            if let Some(time) = cur_vars.get("next_render")
+                .and_then(|x| next_render_time(x, now))
+            {
+                next_render.insert(role_name.clone(), time);
+            }
             cur_vars.insert(String::from("timestamp"),
-                Json::String(format_rfc3339(SystemTime::now()).to_string()));
+                Json::String(format_rfc3339(now).to_string()));
             to_render.insert(role_name.clone(), Json::Object(cur_vars));
         }
         let all_roles = roles.keys().cloned()
             .chain(node_roles.values()
                 .flat_map(|x| x.as_object())
                 .flat_map(|x| x.keys().cloned()))
+            .chain(groups.values()
+                .flat_map(|x| x.get("roles"))
+                .flat_map(|x| x.as_object())
+                .flat_map(|x| x.keys().cloned()))
             .collect();
         Ok(RolesResult {
             all_roles,
             to_render,
+            next_render,
         })
     }
     pub fn schedule(&self) -> Arc<Schedule> {
@@ -97,6 +175,85 @@ impl Responder {
 }
 
 
+/// Checks whether node matches `match` conditions of the group
+///
+/// Returns description of the match for logging. All the conditions
+/// must match.
+fn match_group(hostname: &str, labels: &BTreeMap<String, String>,
+    group: &Map<String, Json>)
+    -> Result<Option<String>, String>
+{
+    let cond = group.get("match")
+        .and_then(|x| x.as_object())
+        .ok_or("`match` must be an object")?;
+    if cond.is_empty() {
+        return Err("`match` has no conditions".into());
+    }
+    let mut reasons = Vec::new();
+    for (key, value) in cond {
+        match &key[..] {
+            "hostname" => {
+                let pat = value.as_str()
+                    .ok_or("`hostname` must be a string")?;
+                let glob = Pattern::new(pat)
+                    .map_err(|e| format!("bad glob {:?}: {}", pat, e))?;
+                if !glob.matches(hostname) {
+                    return Ok(None);
+                }
+                reasons.push(format!("hostname matches {:?}", pat));
+            }
+            "hostname_regex" => {
+                let pat = value.as_str()
+                    .ok_or("`hostname_regex` must be a string")?;
+                let regex = Regex::new(pat)
+                    .map_err(|e| format!("bad regex {:?}: {}", pat, e))?;
+                if !regex.is_match(hostname) {
+                    return Ok(None);
+                }
+                reasons.push(format!("hostname matches regex {:?}", pat));
+            }
+            "labels" => {
+                let required = value.as_object()
+                    .ok_or("`labels` must be an object")?;
+                for (name, value) in required {
+                    let value = value.as_str()
+                        .ok_or("label values must be strings")?;
+                    if labels.get(name).map(|x| &x[..]) != Some(value) {
+                        return Ok(None);
+                    }
+                    reasons.push(format!("label {}={}", name, value));
+                }
+            }
+            _ => return Err(format!("unknown condition {:?}", key)),
+        }
+    }
+    Ok(Some(reasons.join(", ")))
+}
+
+fn to_millis(time: SystemTime) -> u64 {
+    let dur = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    dur.as_secs() * 1000 + dur.subsec_nanos() as u64 / 1_000_000
+}
+
+/// Finds earliest time in the future in the `next_render` variable
+///
+/// Variable may contain a timestamp in milliseconds, an RFC3339 date or
+/// a list of those.
+fn next_render_time(value: &Json, now: SystemTime) -> Option<u64> {
+    let now = to_millis(now);
+    let single = |value: &Json| match *value {
+        Json::Number(ref n) => n.as_u64(),
+        Json::String(ref s) => parse_rfc3339(s).ok().map(to_millis),
+        _ => None,
+    };
+    match *value {
+        Json::Array(ref items) => {
+            items.iter().filter_map(&single).filter(|&t| t > now).min()
+        }
+        ref value => single(value).filter(|&t| t > now),
+    }
+}
+
 fn merge_vars<'x, I, J>(iter: I) -> Map<String, Json>
     where I: Iterator<Item=J>, J: Iterator<Item=(&'x String, &'x Json)>
 {
@@ -153,7 +310,9 @@ fn merge_vars<'x, I, J>(iter: I) -> Map<String, Json>
 mod tests {
     use serde_json::Value as Json;
     use serde_json::from_str;
-    use super::merge_vars;
+    use std::collections::BTreeMap;
+    use std::time::{UNIX_EPOCH, Duration};
+    use super::{merge_vars, next_render_time, match_group};
 
     fn parse_str(s: &str) -> Json {
         from_str(s).unwrap()
@@ -180,4 +339,34 @@ mod tests {
             ].into_iter())), parse_str(
         r#"{"a": {"lamp": "yellow", "table": "green", "chair": "black"}}"#));
     }
+
+    #[test]
+    fn test_next_render() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(next_render_time(&parse_str("2000000"), now),
+            Some(2000000));
+        assert_eq!(next_render_time(&parse_str("1000"), now), None);
+        assert_eq!(next_render_time(&parse_str(
+            r#"[500, "1970-01-01T00:30:00Z", 3000000]"#), now),
+            Some(1800000));
+    }
+
+    #[test]
+    fn test_match_group() {
+        let mut labels = BTreeMap::new();
+        labels.insert("dc".to_string(), "eu".to_string());
+        let group = |s: &str| parse_str(s).as_object().unwrap().clone();
+        assert!(match_group("web-1", &labels, &group(
+            r#"{"match": {"hostname": "web-*"}}"#)).unwrap().is_some());
+        assert!(match_group("db-1", &labels, &group(
+            r#"{"match": {"hostname_regex": "^web-\\d+$"}}"#))
+            .unwrap().is_none());
+        assert!(match_group("db-1", &labels, &group(
+            r#"{"match": {"hostname": "db-*", "labels": {"dc": "eu"}}}"#))
+            .unwrap().is_some());
+        assert!(match_group("db-1", &labels, &group(
+            r#"{"match": {"labels": {"dc": "us"}}}"#)).unwrap().is_none());
+        assert!(match_group("db-1", &labels, &group(
+            r#"{"match": {"ip": "1.2.3.4"}}"#)).is_err());
+    }
 }
@@ -0,0 +1,55 @@
+use std::sync::Arc;
+use std::path::Path;
+
+use failure::Error;
+use serde_json::{Value as Json, from_value};
+
+use query::Settings;
+use query::wasm::{QueryInit, RolesQuery};
+use query::{QueryData, RolesResult};
+use scheduler::{Schedule, LuaModule, read_lua_module};
+
+
+/// Query module written in lua
+///
+/// Functions accept same structures as `query.wasm` and return them
+/// encoded as JSON strings. Errors are raised using `error()`.
+pub struct Responder {
+    schedule: Arc<Schedule>,
+    module: LuaModule,
+}
+
+impl Responder {
+    pub fn new(schedule: &Arc<Schedule>, settings: &Settings,
+               dir: &Path)
+        -> Result<Responder, Error>
+    {
+        let mut module = read_lua_module(dir, "query.lua")?;
+        module.call("init", &QueryInit {
+            schedule: &*schedule,
+            hostname: &settings.hostname,
+        })?;
+        Ok(Responder {
+            schedule: schedule.clone(),
+            module,
+        })
+    }
+
+    pub fn render_roles(&mut self, id: &str, prev: Option<&Schedule>)
+        -> Result<RolesResult, Error>
+    {
+        let result = self.module.call("render_roles", &RolesQuery {
+            deployment_id: id,
+            previous_schedule: prev,
+        })?;
+        return Ok(from_value(result)?);
+    }
+
+    pub fn query(&mut self, data: QueryData) -> Result<Json, Error> {
+        self.module.call("query", &data)
+    }
+
+    pub fn schedule(&self) -> Arc<Schedule> {
+        self.schedule.clone()
+    }
+}
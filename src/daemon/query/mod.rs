@@ -1,7 +1,9 @@
 use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use async_slot as slot;
 use failure::{Error, err_msg};
@@ -21,6 +23,7 @@ use watchdog;
 
 
 mod compat;
+mod lua;
 mod wasm;
 
 
@@ -32,6 +35,17 @@ pub enum Request {
     RolesData(oneshot::Sender<Result<RolesResult, Error>>),
     Query(QueryData, oneshot::Sender<Result<Json, Error>>),
     ForceRerender,
+    /// Sent by timer when `next_render` time for roles is reached
+    RerenderRoles(Vec<String>),
+    /// Render role at specified time (milliseconds), used for retries
+    ScheduleRender(String, u64),
+}
+
+enum TimerMessage {
+    /// Replaces all `next_render` times
+    NextRender(BTreeMap<String, u64>),
+    /// Adds one-time render of a role
+    Once(String, u64),
 }
 
 #[derive(Debug, Clone)]
@@ -47,9 +61,10 @@ pub struct QueryData {
 pub struct RolesResult {
     to_render: BTreeMap<String, Json>,
     all_roles: HashSet<String>,
-    // TODO(tailhook)
-    //#[serde(with="::serde_millis")]
-    //next_render: Option<SystemTime>,
+    /// Time (milliseconds since epoch) when role should be rendered again
+    /// even if schedule doesn't change
+    #[serde(default)]
+    next_render: BTreeMap<String, u64>,
 }
 
 #[derive(Debug)]
@@ -60,6 +75,8 @@ struct Internal {
 
 pub struct ResponderInit {
     rx: UnboundedReceiver<Request>,
+    // used by timer to wake up responder
+    tx: UnboundedSender<Request>,
     // coalesce subsequent schedules, prioritize over requests
     schedule_rx: slot::Receiver<Arc<Schedule>>,
     apply_tx: slot::Sender<ApplyData>,
@@ -69,12 +86,14 @@ pub struct ResponderInit {
 pub struct Settings {
     pub id: Id,
     pub hostname: String,
+    pub labels: BTreeMap<String, String>,
     pub config_dir: PathBuf,
 }
 
 enum Impl {
     Empty,
     Compat(self::compat::Responder),
+    Lua(self::lua::Responder),
     Wasm(self::wasm::Responder),
 }
 
@@ -85,11 +104,12 @@ impl Responder {
         let (tx, rx) = unbounded();
         let (schedule_tx, schedule_rx) = slot::channel();
         let resp = Responder(Arc::new(Internal {
-            tx,
+            tx: tx.clone(),
             schedule_tx,
         }));
         let init = ResponderInit {
             rx,
+            tx,
             apply_tx,
             schedule_rx,
             settings,
@@ -104,6 +124,12 @@ impl Responder {
         self.0.tx.unbounded_send(Request::ForceRerender)
             .expect("responder channel works");
     }
+    /// Renders role at `time` (milliseconds since epoch)
+    pub fn schedule_render(&self, role: &str, time: u64) {
+        self.0.tx.unbounded_send(
+            Request::ScheduleRender(role.to_string(), time))
+            .expect("responder channel works");
+    }
     pub fn get_roles_data(&self)
         -> impl Future<Item=Result<RolesResult, Error>, Error=Void>+Send
     {
@@ -132,8 +158,17 @@ pub fn run(init: ResponderInit, shared: SharedState) {
     let _guard = watchdog::ExitOnReturn(83);
     let stream = init.schedule_rx.map(Request::NewSchedule)
         .select(init.rx);
-    let query_file = init.settings.config_dir
-        .join("scheduler/v1/query.wasm");
+    let query_dir = init.settings.config_dir.join("scheduler/v1");
+    let query_file = query_dir.join("query.wasm");
+    let lua_file = query_dir.join("query.lua");
+    let timer_tx = {
+        let (timer_tx, timer_rx) = channel();
+        let tx = init.tx.clone();
+        thread::Builder::new().name(String::from("query_timer"))
+            .spawn(move || timer(timer_rx, tx))
+            .expect("can start query timer thread");
+        timer_tx
+    };
     let mut responder = Impl::Empty;
     for request in stream.wait() {
         let request = request.expect("stream not closed");
@@ -160,6 +195,19 @@ pub fn run(init: ResponderInit, shared: SharedState) {
                             continue;
                         }
                     }
+                } else if lua_file.exists() {
+                    let new = lua::Responder::new(&schedule,
+                        &init.settings, &query_dir);
+                    match new {
+                        Ok(new) => {
+                            debug!("Initialized lua query engine");
+                            Impl::Lua(new)
+                        }
+                        Err(e) => {
+                            error!("Error initializing query module: {}", e);
+                            continue;
+                        }
+                    }
                 } else {
                     let new = compat::Responder::new(&schedule, &init.settings);
                     debug!("Initialized compatibility query engine");
@@ -177,11 +225,14 @@ pub fn run(init: ResponderInit, shared: SharedState) {
                             {} total, in {:?}",
                             data.to_render.len(), data.all_roles.len(),
                             elapsed);
-                        init.apply_tx.swap(ApplyData {
+                        timer_tx.send(TimerMessage::NextRender(
+                            data.next_render)).ok();
+                        send_apply(&init.apply_tx, ApplyData {
                             id,
                             schedule: schedule.clone(),
                             roles: data.to_render,
-                        }).ok();
+                            force: false,
+                        });
                     }
                     Err(e) => {
                         error!("Can't compute render roles: {}", e);
@@ -201,19 +252,56 @@ pub fn run(init: ResponderInit, shared: SharedState) {
                 match responder.render_roles(&id, None) {
                     Ok(data) => {
                         shared.update_role_list(&data.all_roles);
-                        init.apply_tx
-                            .swap(ApplyData {
+                        timer_tx.send(TimerMessage::NextRender(
+                            data.next_render)).ok();
+                        send_apply(&init.apply_tx, ApplyData {
+                            id,
+                            schedule,
+                            roles: data.to_render,
+                            force: true,
+                        });
+                    }
+                    Err(e) => {
+                        error!("Can't compute render roles: {}", e);
+                    }
+                }
+            }
+            Request::RerenderRoles(roles) => {
+                debug!("Incoming request RerenderRoles: {:?}", roles);
+                let schedule = if let Some(s) = responder.schedule() {
+                    s.clone()
+                } else {
+                    continue;
+                };
+                let id: String = thread_rng().sample_iter(&Alphanumeric)
+                    .take(24).collect();
+                match responder.render_roles(&id, None) {
+                    Ok(mut data) => {
+                        data.to_render.retain(|name, _| roles.contains(name));
+                        info!("Scheduled re-render of {} roles: {:?}",
+                            data.to_render.len(),
+                            data.to_render.keys().collect::<Vec<_>>());
+                        timer_tx.send(TimerMessage::NextRender(
+                            data.next_render)).ok();
+                        if !data.to_render.is_empty() {
+                            send_apply(&init.apply_tx, ApplyData {
                                 id,
                                 schedule,
                                 roles: data.to_render,
-                            })
-                            .ok();
+                                force: true,
+                            });
+                        }
                     }
                     Err(e) => {
                         error!("Can't compute render roles: {}", e);
                     }
                 }
             }
+            Request::ScheduleRender(role, time) => {
+                debug!("Incoming request ScheduleRender: {:?} at {}",
+                    role, time);
+                timer_tx.send(TimerMessage::Once(role, time)).ok();
+            }
             Request::RolesData(chan) => {
                 debug!("Incoming request RolesData");
                 // maybe store previous id?
@@ -233,6 +321,102 @@ pub fn run(init: ResponderInit, shared: SharedState) {
     }
 }
 
+/// Sends data to apply thread, keeping roles of the data not applied yet
+///
+/// This is needed because re-render of a few roles may otherwise replace
+/// a full render of the new schedule in the slot.
+fn send_apply(tx: &slot::Sender<ApplyData>, data: ApplyData) {
+    let id = data.id.clone();
+    let schedule = data.schedule.clone();
+    let roles = data.roles.clone();
+    let force = data.force;
+    match tx.swap(data) {
+        Ok(Some(old)) => {
+            // roles of the previous schedule are stale anyway
+            if old.schedule.hash == schedule.hash {
+                let mut merged = old.roles;
+                merged.extend(roles);
+                tx.swap(ApplyData {
+                    id, schedule,
+                    roles: merged,
+                    force: force || old.force,
+                }).ok();
+            }
+        }
+        Ok(None) | Err(_) => {}
+    }
+}
+
+fn to_system_time(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+fn take_due(timers: &mut BTreeMap<String, u64>, now: SystemTime)
+    -> Vec<String>
+{
+    let due = timers.iter()
+        .filter(|&(_, &time)| to_system_time(time) <= now)
+        .map(|(role, _)| role.clone())
+        .collect::<Vec<String>>();
+    for role in &due {
+        timers.remove(role);
+    }
+    return due;
+}
+
+/// Drops timers which are already due
+///
+/// Query modules may return `next_render` in the past, firing it would
+/// return the same time again and so rerender roles in a busy loop.
+fn future_only(timers: BTreeMap<String, u64>, now: SystemTime)
+    -> BTreeMap<String, u64>
+{
+    timers.into_iter()
+        .filter(|&(_, time)| to_system_time(time) > now)
+        .collect()
+}
+
+/// Wakes up responder when `next_render` of some roles is reached
+///
+/// Each `NextRender` message replaces the whole set of timers returned
+/// by query engine. Single renders are kept until fired.
+fn timer(rx: Receiver<TimerMessage>, tx: UnboundedSender<Request>) {
+    let mut next_render = BTreeMap::new();
+    let mut once = BTreeMap::new();
+    loop {
+        let now = SystemTime::now();
+        let mut due = take_due(&mut next_render, now);
+        for role in take_due(&mut once, now) {
+            if !due.contains(&role) {
+                due.push(role);
+            }
+        }
+        if !due.is_empty() {
+            if tx.unbounded_send(Request::RerenderRoles(due)).is_err() {
+                return;
+            }
+        }
+        let timeout = next_render.values().chain(once.values())
+            .map(|&time| to_system_time(time).duration_since(now)
+                         .unwrap_or(Duration::new(0, 0)))
+            .min();
+        let result = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match result {
+            Ok(TimerMessage::NextRender(new)) => {
+                next_render = future_only(new, SystemTime::now());
+            }
+            Ok(TimerMessage::Once(role, time)) => {
+                once.insert(role, time);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
 impl Impl {
     fn render_roles(&mut self, id: &str, prev: Option<&Schedule>)
         -> Result<RolesResult, Error>
@@ -241,6 +425,7 @@ impl Impl {
         match self {
             Empty => Err(err_msg("no schedule yet")),
             Compat(resp) => resp.render_roles(id, prev),
+            Lua(resp) => resp.render_roles(id, prev),
             Wasm(resp) => resp.render_roles(id, prev),
         }
     }
@@ -251,6 +436,7 @@ impl Impl {
         match self {
             Empty => Err(err_msg("no schedule yet")),
             Compat(resp) => resp.query(query),
+            Lua(resp) => resp.query(query),
             Wasm(resp) => resp.query(query),
         }
     }
@@ -259,7 +445,30 @@ impl Impl {
         match self {
             Empty => None,
             Compat(resp) => Some(resp.schedule()),
+            Lua(resp) => Some(resp.schedule()),
             Wasm(resp) => Some(resp.schedule()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+    use super::{future_only, take_due};
+
+    #[test]
+    fn past_next_render() {
+        let now = UNIX_EPOCH + Duration::from_millis(10000);
+        let mut timers = BTreeMap::new();
+        timers.insert("past".to_string(), 5000);
+        timers.insert("now".to_string(), 10000);
+        timers.insert("future".to_string(), 15000);
+        let mut timers = future_only(timers, now);
+        assert_eq!(timers.keys().collect::<Vec<_>>(), vec!["future"]);
+        assert!(take_due(&mut timers, now).is_empty());
+        let later = UNIX_EPOCH + Duration::from_millis(15000);
+        assert_eq!(take_due(&mut timers, later), vec!["future"]);
+        assert!(timers.is_empty());
+    }
+}
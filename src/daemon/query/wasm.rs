@@ -17,14 +17,14 @@ pub struct Responder {
 
 #[derive(Debug, Serialize)]
 pub struct QueryInit<'a> {
-    schedule: &'a Schedule,
-    hostname: &'a str,
+    pub schedule: &'a Schedule,
+    pub hostname: &'a str,
 }
 
 #[derive(Debug, Serialize)]
 pub struct RolesQuery<'a> {
-    deployment_id: &'a str,
-    previous_schedule: Option<&'a Schedule>,
+    pub deployment_id: &'a str,
+    pub previous_schedule: Option<&'a Schedule>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash)]
@@ -60,6 +60,7 @@ impl Responder {
         -> Result<Responder, Error>
     {
         let mut wasm = Program::read(file)?;
+        wasm.set_runtime_dir(&settings.config_dir.join("runtime"));
         let init_res: Result<(), CatchAllError> = wasm.json_call("init",
             &QueryInit {
                 schedule: &*schedule,
@@ -80,12 +81,18 @@ impl Responder {
             deployment_id: id,
             previous_schedule: prev,
         })?;
+        for line in self.wasm.take_log().lines() {
+            info!("Query module: {}", line);
+        }
         return Ok(result?);
     }
 
     pub fn query(&mut self, data: QueryData) -> Result<Json, Error> {
         let result: Result<_, CatchAllError>;
         result = self.wasm.json_call("query", &data)?;
+        for line in self.wasm.take_log().lines() {
+            info!("Query module: {}", line);
+        }
         return Ok(result?);
     }
 
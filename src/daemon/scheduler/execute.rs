@@ -3,9 +3,9 @@ use std::collections::{VecDeque, HashMap};
 use failure;
 use lua::{ThreadStatus, Type, Serde, GcOption};
 use serde::Serialize;
-use serde_json::{from_str};
+use serde_json::{Value as Json, from_str};
 
-use scheduler::luatic::Scheduler;
+use scheduler::luatic::{Scheduler, Module};
 use scheduler::main::SchedulerResult;
 
 #[derive(Debug, Fail)]
@@ -93,3 +93,51 @@ impl Scheduler {
         })
     }
 }
+
+impl Module {
+    /// Calls function exported by the module
+    ///
+    /// Function receives `input` as a table and must return JSON-encoded
+    /// string or nil (which is converted to `null`).
+    pub fn call<S: Serialize>(&mut self, name: &'static str, input: &S)
+        -> Result<Json, failure::Error>
+    {
+        let top = self.lua.get_top();
+        let result = self.call_inner(name, input);
+        self.lua.set_top(top);
+        self.lua.gc(GcOption::Step, 0);
+        return result;
+    }
+    fn call_inner<S: Serialize>(&mut self, name: &'static str, input: &S)
+        -> Result<Json, failure::Error>
+    {
+        self.lua.get_global("debug");
+        self.lua.get_field(-1, "traceback");
+        let error_handler = self.lua.get_top();
+
+        self.lua.get_global("_VERWALTER_MAIN");
+        match self.lua.get_field(-1, name) {
+            Type::Function => {}
+            typ => return Err(Error::FunctionNotFound(name, typ).into()),
+        }
+        self.lua.push(Serde(input));
+        match self.lua.pcall(1, 1, error_handler) {
+            ThreadStatus::Ok => {}
+            ThreadStatus::Yield => {
+                return Err(Error::UnexpectedYield.into());
+            }
+            err => {
+                let txt = self.lua.to_str(-1).unwrap_or("undefined")
+                          .to_string();
+                return Err(Error::Lua(err, txt).into());
+            }
+        }
+        if self.lua.is_nil(-1) {
+            return Ok(Json::Null);
+        }
+        match self.lua.to_type::<String>(-1) {
+            Some(ref x) => from_str(x).map_err(|_| Error::Conversion.into()),
+            None => Err(Error::Conversion.into()),
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Curated standard library for lua schedulers
+//!
+//! We only expose functions that can't touch filesystem or anything outside
+//! of the lua state. Helpers that are slow or error-prone to write in lua
+//! itself are exposed in the global `verwalter` table.
+use std::cmp::Ordering;
+use std::time::{UNIX_EPOCH, Duration};
+
+use hex;
+use humantime::format_rfc3339;
+use lua::{State as Lua, ThreadStatus, Type, Serde};
+use serde_json::{Value as Json, Map, Number, from_str, to_string};
+use serde_json::to_string_pretty;
+use sha1::Sha1;
+use sha2::{Sha256, Digest};
+
+
+/// Maximum nesting of tables serialized by `verwalter.json_encode`
+///
+/// This mostly protects against recursive tables.
+const MAX_JSON_DEPTH: usize = 128;
+
+/// Milliseconds of `9999-12-31T23:59:59.999Z`, the last time that can be
+/// formatted by `verwalter.rfc3339`
+const MAX_RFC3339_MILLIS: f64 = 253_402_300_799_999.;
+
+/// Restricts environment after standard libraries are loaded
+///
+/// 1. Only `debug.traceback` is kept from the debug library
+/// 2. No functions that load code by path or from bytecode, `require`
+///    only works through the searcher restricted to scheduler directory
+/// 3. Patterns are limited in size, as well as strings made by `string.rep`
+const SANDBOX: &str = r#"
+    debug = {traceback=debug.traceback}
+    package.loaded.debug = debug
+    load = nil
+    loadfile = nil
+    dofile = nil
+    string.dump = nil
+
+    package.loadlib = nil
+    package.searchpath = nil
+    package.cpath = ""
+    package.path = ""
+    for i = #package.searchers, 2, -1 do
+        package.searchers[i] = nil
+    end
+
+    local MAX_PATTERN = 256
+    local MAX_REP = 16777216
+    for _, name in ipairs({"find", "match", "gmatch", "gsub"}) do
+        local original = string[name]
+        string[name] = function(s, pattern, ...)
+            if type(pattern) == "string" and #pattern > MAX_PATTERN then
+                error("string pattern is too long (limit is "
+                      .. MAX_PATTERN .. ")", 2)
+            end
+            return original(s, pattern, ...)
+        end
+    end
+    local rep = string.rep
+    string.rep = function(s, n, sep)
+        local total = (#tostring(s) + #(sep or "")) * (tonumber(n) or 0)
+        if total > MAX_REP then
+            error("string.rep result is too long (limit is "
+                  .. MAX_REP .. ")", 2)
+        end
+        return rep(s, n, sep)
+    end
+"#;
+
+
+fn abs_index(lua: &mut Lua, idx: i32) -> i32 {
+    if idx < 0 {
+        lua.get_top() + idx + 1
+    } else {
+        idx
+    }
+}
+
+fn push_error(lua: &mut Lua, msg: &str) -> i32 {
+    lua.push_nil();
+    lua.push_string(msg);
+    return 2;
+}
+
+fn to_json(lua: &mut Lua, idx: i32, depth: usize) -> Result<Json, String> {
+    let idx = abs_index(lua, idx);
+    if depth > MAX_JSON_DEPTH {
+        return Err("table nesting is too deep (recursive table?)".into());
+    }
+    match lua.type_of(idx) {
+        None | Some(Type::Nil) => Ok(Json::Null),
+        Some(Type::Boolean) => Ok(Json::Bool(lua.to_bool(idx))),
+        Some(Type::Number) => {
+            if lua.is_integer(idx) {
+                Ok(Json::from(lua.to_integer(idx)))
+            } else {
+                Number::from_f64(lua.to_number(idx))
+                    .map(Json::Number)
+                    .ok_or_else(|| String::from("can't encode nan or inf"))
+            }
+        }
+        Some(Type::String) => {
+            Ok(Json::String(lua.to_str(idx).unwrap_or("").to_string()))
+        }
+        Some(Type::Table) => {
+            let len = lua.raw_len(idx) as i64;
+            lua.push_nil();
+            let empty = !lua.next(idx);
+            if !empty {
+                lua.pop(2);
+            }
+            if len > 0 || empty {
+                // Like most lua json libraries, empty table is an array
+                let mut items = Vec::with_capacity(len as usize);
+                for i in 1..len+1 {
+                    lua.raw_geti(idx, i);
+                    let item = to_json(lua, -1, depth+1);
+                    lua.pop(1);
+                    items.push(item?);
+                }
+                return Ok(Json::Array(items));
+            }
+            let mut map = Map::new();
+            lua.push_nil();
+            while lua.next(idx) {
+                // Note: we must not call `to_str` on keys that are
+                // not strings, because it breaks `next` iteration
+                if !matches!(lua.type_of(-2), Some(Type::String)) {
+                    lua.pop(2);
+                    return Err("only string keys are supported \
+                        in objects".into());
+                }
+                let key = lua.to_str(-2).unwrap_or("").to_string();
+                let value = match to_json(lua, -1, depth+1) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        lua.pop(2);
+                        return Err(e);
+                    }
+                };
+                map.insert(key, value);
+                lua.pop(1);
+            }
+            Ok(Json::Object(map))
+        }
+        Some(typ) => Err(format!("can't encode value of type {:?}", typ)),
+    }
+}
+
+fn lua_json_encode(lua: &mut Lua) -> i32 {
+    let pretty = lua.to_bool(2);
+    match to_json(lua, 1, 0) {
+        Ok(value) => {
+            let text = if pretty {
+                to_string_pretty(&value)
+            } else {
+                to_string(&value)
+            };
+            lua.push_string(&text.expect("can always serialize json"));
+            return 1;
+        }
+        Err(e) => push_error(lua, &e),
+    }
+}
+
+fn lua_json_decode(lua: &mut Lua) -> i32 {
+    let value = match lua.to_str(1).map(|s| from_str::<Json>(s)) {
+        Some(Ok(value)) => value,
+        Some(Err(e)) => return push_error(lua, &e.to_string()),
+        None => return push_error(lua, "argument must be a string"),
+    };
+    lua.push(Serde(&value));
+    return 1;
+}
+
+fn lua_sha1(lua: &mut Lua) -> i32 {
+    let digest = match lua.to_str(1) {
+        Some(s) => {
+            let mut sha = Sha1::new();
+            sha.update(s.as_bytes());
+            sha.digest().to_string()
+        }
+        None => return push_error(lua, "argument must be a string"),
+    };
+    lua.push_string(&digest);
+    return 1;
+}
+
+fn lua_sha256(lua: &mut Lua) -> i32 {
+    let digest = match lua.to_str(1) {
+        Some(s) => {
+            let mut sha = Sha256::default();
+            sha.input(s.as_bytes());
+            hex::encode(sha.result())
+        }
+        None => return push_error(lua, "argument must be a string"),
+    };
+    lua.push_string(&digest);
+    return 1;
+}
+
+/// Formats time in milliseconds (same units as `now` in scheduler input)
+fn lua_rfc3339(lua: &mut Lua) -> i32 {
+    if !matches!(lua.type_of(1), Some(Type::Number)) {
+        return push_error(lua, "argument must be a number of milliseconds");
+    }
+    let millis = lua.to_number(1);
+    if !(millis >= 0.) {
+        return push_error(lua, "timestamp must be non-negative");
+    }
+    // humantime panics on years after 9999, and panic must not unwind
+    // through lua
+    if millis > MAX_RFC3339_MILLIS {
+        return push_error(lua, "timestamp is after year 9999");
+    }
+    let time = UNIX_EPOCH + Duration::from_millis(millis as u64);
+    lua.push_string(&format_rfc3339(time).to_string());
+    return 1;
+}
+
+fn parse_semver(s: &str) -> Option<(Vec<u64>, Option<&str>)> {
+    let s = s.trim();
+    let s = if s.starts_with('v') { &s[1..] } else { s };
+    // build metadata doesn't participate in comparison
+    let s = s.splitn(2, '+').next().unwrap_or("");
+    let mut pair = s.splitn(2, '-');
+    let version = pair.next().unwrap_or("");
+    let pre = pair.next();
+    let nums = version.split('.')
+        .map(|x| x.parse().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if nums.is_empty() || nums.len() > 3 {
+        return None;
+    }
+    Some((nums, pre))
+}
+
+fn compare_prerelease(a: &str, b: &str) -> Ordering {
+    for (x, y) in a.split('.').zip(b.split('.')) {
+        let ord = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            (Ok(_), Err(_)) => Ordering::Less,
+            (Err(_), Ok(_)) => Ordering::Greater,
+            (Err(_), Err(_)) => x.cmp(y),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    a.split('.').count().cmp(&b.split('.').count())
+}
+
+/// Compares two versions, missing components are treated as zeros
+fn semver_compare(a: &str, b: &str) -> Option<Ordering> {
+    let (mut anum, apre) = parse_semver(a)?;
+    let (mut bnum, bpre) = parse_semver(b)?;
+    anum.resize(3, 0);
+    bnum.resize(3, 0);
+    Some(anum.cmp(&bnum).then_with(|| match (apre, bpre) {
+        (None, None) => Ordering::Equal,
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (Some(a), Some(b)) => compare_prerelease(a, b),
+    }))
+}
+
+fn lua_semver_compare(lua: &mut Lua) -> i32 {
+    let a = lua.to_str(1).map(|x| x.to_string());
+    let b = lua.to_str(2).map(|x| x.to_string());
+    let result = match (a, b) {
+        (Some(a), Some(b)) => match semver_compare(&a, &b) {
+            Some(ord) => ord,
+            None => {
+                return push_error(lua,
+                    &format!("invalid version {:?} or {:?}", a, b));
+            }
+        },
+        _ => return push_error(lua, "arguments must be strings"),
+    };
+    lua.push_integer(match result {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    });
+    return 1;
+}
+
+/// Removes unsafe functions from global environment
+///
+/// Must be called after all the standard libraries are loaded
+pub(in scheduler) fn restrict(lua: &mut Lua) -> Result<(), String> {
+    let status = match lua.load_string(SANDBOX) {
+        ThreadStatus::Ok => lua.pcall(0, 0, 0),
+        err => err,
+    };
+    match status {
+        ThreadStatus::Ok => Ok(()),
+        _ => {
+            let msg = lua.to_str(-1).unwrap_or("no message").to_string();
+            lua.pop(1);
+            Err(msg)
+        }
+    }
+}
+
+/// Registers the `verwalter` global with helper functions
+pub(in scheduler) fn open(lua: &mut Lua) {
+    lua.new_table();
+    lua.push_closure(lua_func!(lua_json_encode), 0);
+    lua.set_field(-2, "json_encode");
+    lua.push_closure(lua_func!(lua_json_decode), 0);
+    lua.set_field(-2, "json_decode");
+    lua.push_closure(lua_func!(lua_sha1), 0);
+    lua.set_field(-2, "sha1");
+    lua.push_closure(lua_func!(lua_sha256), 0);
+    lua.set_field(-2, "sha256");
+    lua.push_closure(lua_func!(lua_rfc3339), 0);
+    lua.set_field(-2, "rfc3339");
+    lua.push_closure(lua_func!(lua_semver_compare), 0);
+    lua.set_field(-2, "semver_compare");
+    lua.set_global("verwalter");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering::*;
+    use super::semver_compare;
+
+    #[test]
+    fn test_semver_simple() {
+        assert_eq!(semver_compare("1.2.3", "1.2.3"), Some(Equal));
+        assert_eq!(semver_compare("v1.2.3", "1.10.0"), Some(Less));
+        assert_eq!(semver_compare("2.0", "1.99.99"), Some(Greater));
+        assert_eq!(semver_compare("1", "1.0.0+build5"), Some(Equal));
+    }
+
+    #[test]
+    fn test_semver_prerelease() {
+        assert_eq!(semver_compare("1.0.0-alpha", "1.0.0"), Some(Less));
+        assert_eq!(semver_compare("1.0.0-alpha.1", "1.0.0-alpha"),
+                   Some(Greater));
+        assert_eq!(semver_compare("1.0.0-alpha.2", "1.0.0-alpha.10"),
+                   Some(Less));
+        assert_eq!(semver_compare("1.0.0-rc.1", "1.0.0-beta.11"),
+                   Some(Greater));
+    }
+
+    #[test]
+    fn test_semver_invalid() {
+        assert_eq!(semver_compare("1.x", "1.0"), None);
+        assert_eq!(semver_compare("", "1.0"), None);
+    }
+}
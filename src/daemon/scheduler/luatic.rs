@@ -1,13 +1,20 @@
-use std::path::{Path, PathBuf};
+use std::path::{Path, PathBuf, Component};
 
 use lua::{State as Lua, ThreadStatus, Library};
 use lua::ffi::{lua_upvalueindex};
 
+use scheduler::lualib;
+
 
 pub(in scheduler) struct Scheduler {
     pub lua: Lua,
 }
 
+/// Lua file loaded in a sandbox, used for query module
+pub struct Module {
+    pub(in scheduler) lua: Lua,
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum ReadError {
@@ -18,6 +25,9 @@ quick_error! {
         File(err: ThreadStatus, msg: String, path: PathBuf) {
             display("cound not read file {:?} ->  {:?}: {}", path, err, msg)
         }
+        Sandbox(msg: String) {
+            display("error initializing lua sandbox: {}", msg)
+        }
     }
 }
 
@@ -31,6 +41,11 @@ fn lua_load_file(lua: &mut Lua) -> i32 {
         }
     };
     match lua.to_str(1) {
+        Some(ref s) if !is_module_name(s) => {
+            error!("Invalid module name {:?}, only relative paths \
+                inside scheduler directory are allowed", s);
+            return 0;
+        }
         Some(ref s) => path.push(s),
         None => {
             error!("No module name. (`require` without arguments?)");
@@ -58,6 +73,14 @@ fn lua_load_file(lua: &mut Lua) -> i32 {
     return 1;
 }
 
+fn is_module_name(name: &str) -> bool {
+    let path = Path::new(name);
+    !name.is_empty() && path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
 fn load_package(lua: &mut Lua, dir: &Path) {
     let tbl = lua.open_package();
     lua.get_field(tbl, "searchers");
@@ -76,10 +99,21 @@ fn load_package(lua: &mut Lua, dir: &Path) {
     lua.push_nil();
     lua.set_table(srch);
     lua.pop(1);
+    // make the table visible, so that sandbox could clean it up
+    lua.push_value(tbl);
+    lua.set_global("package");
 }
 
 pub(in scheduler) fn read(dir: &Path)
     -> Result<Scheduler, ReadError>
+{
+    read_module(dir, "main.lua").map(|m| Scheduler { lua: m.lua })
+}
+
+/// Loads `file_name` from `dir`, value returned by the file is stored
+/// for calling its functions later
+pub fn read_module(dir: &Path, file_name: &str)
+    -> Result<Module, ReadError>
 {
     let mut lua = Lua::new();
 
@@ -89,8 +123,10 @@ pub(in scheduler) fn read(dir: &Path)
     lua.load_library(Library::String);
     lua.load_library(Library::Utf8);
     lua.load_library(Library::Math);
-    // TODO(tailhook) include debug.traceback but not interactive functions
+    // only `debug.traceback` is left after `restrict()`
     lua.load_library(Library::Debug);
+    lualib::restrict(&mut lua).map_err(ReadError::Sandbox)?;
+    lualib::open(&mut lua);
 
     lua.get_global("debug");
     lua.get_field(-1, "traceback");
@@ -99,7 +135,7 @@ pub(in scheduler) fn read(dir: &Path)
     lua.new_table();
     lua.set_global("_VERWALTER_ERRORS");
 
-    let path = dir.join("main.lua");
+    let path = dir.join(file_name);
     let result = {
         let strpath = match path.to_str() {
             Some(x) => x,
@@ -154,7 +190,51 @@ pub(in scheduler) fn read(dir: &Path)
 
     lua.pop(2); // error handler, and global
 
-    result.map(|()| Scheduler {
+    result.map(|()| Module {
         lua: lua,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+
+    use tempfile::tempdir;
+
+    use super::read_module;
+
+    fn check(code: &str) -> bool {
+        let dir = tempdir().unwrap();
+        File::create(dir.path().join("main.lua")).unwrap()
+            .write_all(code.as_bytes()).unwrap();
+        read_module(dir.path(), "main.lua").is_ok()
+    }
+
+    #[test]
+    fn sandbox_package() {
+        assert!(check("assert(package.loadlib == nil); return {}"));
+        assert!(check("assert(package.searchpath == nil); return {}"));
+        assert!(check("assert(package.cpath == ''); return {}"));
+        assert!(check("assert(#package.searchers == 1); return {}"));
+        assert!(!check("assert(package.loadlib ~= nil); return {}"));
+    }
+
+    #[test]
+    fn sandbox_debug() {
+        assert!(check("assert(debug.traceback ~= nil); return {}"));
+        assert!(check("assert(debug.sethook == nil); return {}"));
+        assert!(check("assert(require('debug').sethook == nil); \
+            return {}"));
+        assert!(check("assert(package.loaded.debug.getregistry == nil); \
+            return {}"));
+    }
+
+    #[test]
+    fn sandbox_rfc3339() {
+        assert!(check("assert(verwalter.rfc3339(0) == \
+            '1970-01-01T00:00:00Z'); return {}"));
+        assert!(check("local v, err = verwalter.rfc3339(1e300); \
+            assert(v == nil and err); return {}"));
+    }
+}
@@ -5,6 +5,7 @@ use std::sync::Arc;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime, Instant};
 
+use humantime::format_rfc3339;
 use serde;
 use serde_json::{Value as Json};
 use serde_millis;
@@ -16,7 +17,8 @@ use config;
 use hash::hash;
 use id::Id;
 use peer::Peer;
-use scheduler::{self, Schedule};
+use scheduler::{self, Schedule, PipelineError};
+use scheduler::shadow::Shadow;
 use shared::{SharedState};
 use time_util::ToMsec;
 use watchdog::{self, Alarm};
@@ -64,6 +66,10 @@ pub struct Settings {
     pub id: Id,
     pub hostname: String,
     pub config_dir: PathBuf,
+    /// Run reloaded scheduler in shadow mode for this number of rounds
+    /// (zero disables shadow mode)
+    pub shadow_rounds: u32,
+    pub shadow_hook: Option<PathBuf>,
 }
 
 fn watch_dir(notify: &mut Inotify, path: &Path) {
@@ -108,11 +114,19 @@ pub fn main(state: SharedState, settings: Settings) -> !
             }
         }
     };
+    let mut schema = match scheduler::read_schema(&settings.config_dir) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Scheduler schema load failed: {}", e);
+            exit(4);
+        }
+    };
     let mut runtime = {
         let _alarm = Alarm::new(Duration::new(5, 0), "runtime load");
         watch_dir(&mut inotify, &settings.config_dir.join("runtime"));
         config::read_runtime(&settings.config_dir.join("runtime"))
     };
+    let mut shadow: Option<Shadow> = None;
     loop {
         sleep(Duration::new(5, 0));
         let mut cookie = if let Some(cookie) = state.leader_cookie() {
@@ -157,6 +171,16 @@ pub fn main(state: SharedState, settings: Settings) -> !
                     let _alarm = Alarm::new(Duration::new(10, 0),
                                             "scheduler reload");
                     match scheduler::read(&settings.config_dir) {
+                        Ok(s) if settings.shadow_rounds > 0 => {
+                            info!("Scheduler reloaded, running it in \
+                                shadow mode for {} rounds",
+                                settings.shadow_rounds);
+                            let new = Shadow::new(s, settings.shadow_rounds);
+                            state.set_shadow_scheduler(
+                                Some(new.status.clone()));
+                            shadow = Some(new);
+                            state.clear_error("scheduler_load");
+                        }
                         Ok(s) => {
                             scheduler = s;
                             state.clear_error("scheduler_load");
@@ -167,6 +191,18 @@ pub fn main(state: SharedState, settings: Settings) -> !
                                 Using the old one.", e);
                         }
                     }
+                    match scheduler::read_schema(&settings.config_dir) {
+                        Ok(s) => {
+                            schema = s;
+                            state.clear_error("scheduler_schema_load");
+                        }
+                        Err(e) => {
+                            state.set_error("scheduler_schema_load",
+                                format!("{}", e));
+                            error!("Scheduler schema load failed: {}. \
+                                Using the old one.", e);
+                        }
+                    }
                 }
                 {
                     let _alarm = Alarm::new(Duration::new(5, 0),
@@ -207,8 +243,43 @@ pub fn main(state: SharedState, settings: Settings) -> !
                 */
             };
 
+            if let Some(pinned) = state.pinned_schedule() {
+                drop(_alarm);
+                let pin = pinned.pinned.clone()
+                    .expect("pinned schedule has a pin");
+                // actions are left pending (and not marked as started)
+                // until schedule is unpinned, the cookie may still have
+                // some if the schedule was pinned after it's refreshed
+                cookie.actions.clear();
+                let log = format!("Schedule is pinned to backup {:?} \
+                    since {}, scheduler is paused\n",
+                    pin.backup, format_rfc3339(pin.since));
+                let backup = pin.backup.clone();
+                // data is published unchanged, so the hash matches the pin
+                let published = state.set_schedule_by_leader(cookie,
+                    Schedule {
+                        timestamp: timestamp.to_msec(),
+                        hash: pinned.hash.clone(),
+                        data: pinned.data.clone(),
+                        origin: settings.id.clone(),
+                        pinned: Some(pin),
+                        actions: None,
+                    }, input, log, HashMap::new());
+                if let Some(hash) = published {
+                    info!("Schedule {} pinned to backup {:?}", hash, backup);
+                }
+                break;
+            }
+
+            if shadow.is_some() && state.take_shadow_promotion() {
+                info!("Shadow scheduler is promoted by API request");
+                scheduler = shadow.take().unwrap().scheduler;
+                state.set_shadow_scheduler(None);
+            }
+
             let result = scheduler.execute(&input);
             SCHEDULING_TIME.set((Instant::now() - instant).to_msec() as i64);
+            drop(_alarm);
 
             let result = match result {
                 Ok(res @ SchedulerResult { schedule: Json::Object(_), .. })
@@ -228,22 +299,67 @@ pub fn main(state: SharedState, settings: Settings) -> !
                 Err(e) => {
                     error!("Scheduling failed: {}", e);
                     state.set_error("scheduler", e.to_string());
-                    state.set_schedule_debug_info(input, e.to_string());
+                    let log = match e.downcast_ref::<PipelineError>() {
+                        Some(e) => format!("{}\n{}", e.message, e.log),
+                        None => e.to_string(),
+                    };
+                    state.set_schedule_debug_info(input, log);
                     SCHEDULER_FAILED.incr(1);
                     sleep(Duration::from_secs(1));
                     continue;
                 }
             };
 
+            if let Some(ref schema) = schema {
+                let errors = schema.validate(&result.schedule);
+                if !errors.is_empty() {
+                    error!("Schedule doesn't match schema: {}",
+                        errors.join("; "));
+                    state.set_error("scheduler", format!(
+                        "schedule doesn't match schema: {}",
+                        errors.join("; ")));
+                    let mut log = result.log;
+                    log.push_str("===== Schema errors =====\n");
+                    for e in &errors {
+                        log.push_str(e);
+                        log.push('\n');
+                    }
+                    state.set_schedule_debug_info(input, log);
+                    SCHEDULER_FAILED.incr(1);
+                    sleep(Duration::from_secs(1));
+                    continue;
+                }
+            }
+
+            let mut result = result;
+            let mut promote = false;
+            if let Some(ref mut shadow) = shadow {
+                let _alarm = Alarm::new(Duration::new(20, 0), "shadow");
+                shadow.round(&input, &result.schedule, &mut result.log);
+                promote = shadow.check(settings.shadow_hook.as_ref(),
+                                       &mut result.log);
+                state.set_shadow_scheduler(Some(shadow.status.clone()));
+            }
+            if promote {
+                info!("Shadow scheduler is promoted automatically");
+                result.log.push_str("Shadow scheduler is promoted\n");
+                scheduler = shadow.take().unwrap().scheduler;
+                state.set_shadow_scheduler(None);
+            }
+
             let hash = hash(result.schedule.to_string());
-            info!("New schedule {}, done in {} ms", hash,
-                SCHEDULING_TIME.get());
-            state.set_schedule_by_leader(cookie, Schedule {
+            let published = state.set_schedule_by_leader(cookie, Schedule {
                 timestamp: timestamp.to_msec(),
                 hash: hash,
                 data: result.schedule,
                 origin: settings.id.clone(),
+                pinned: None,
+                actions: None,
             }, input, result.log, result.actions);
+            if let Some(hash) = published {
+                info!("New schedule {}, done in {} ms", hash,
+                    SCHEDULING_TIME.get());
+            }
             break;
         }
     }
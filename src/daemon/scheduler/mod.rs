@@ -1,40 +1,63 @@
 use std::path::Path;
 
 use failure::Error;
+use serde::Serialize;
 
 mod execute;
 mod state;
 pub mod main;  // pub for making counters visible
 mod luatic;
+mod lualib;
 mod wasm;
+mod pipeline;
+pub mod shadow;
+mod schema;
 
-pub use self::state::{Schedule, ScheduleId, from_json};
+pub use self::state::{Schedule, ScheduleId, Pin, from_json};
 pub use self::main::{main as run, Settings, SchedulerInput, SchedulerResult};
+pub use self::pipeline::PipelineError;
+pub use self::shadow::ShadowStatus;
+pub use self::schema::{read as read_schema};
+pub use self::luatic::{Module as LuaModule, read_module as read_lua_module};
 
 enum Scheduler {
     Lua(self::luatic::Scheduler),
     Wasm(self::wasm::Scheduler),
+    Pipeline(self::pipeline::Pipeline),
 }
 
 impl Scheduler {
-    fn execute(&mut self, input: &SchedulerInput)
+    fn execute<S: Serialize>(&mut self, input: &S)
         -> Result<SchedulerResult, Error>
     {
         use self::Scheduler::*;
         match *self {
-            Lua(ref mut scheduler) => scheduler.execute(&input),
-            Wasm(ref mut scheduler) => scheduler.execute(&input),
+            Lua(ref mut scheduler) => scheduler.execute(input),
+            Wasm(ref mut scheduler) => scheduler.execute(input),
+            Pipeline(ref mut scheduler) => scheduler.execute(input),
         }
     }
 }
 
-pub(in scheduler) fn read(base_dir: &Path)
+/// Reads a single (non-pipeline) scheduler from the directory
+pub(in scheduler) fn read_dir(dir: &Path, runtime_dir: &Path)
     -> Result<Scheduler, Error>
 {
-    let ref dir = &base_dir.join("scheduler/v1");
     if dir.join("scheduler.wasm").exists() {
-        Ok(Scheduler::Wasm(self::wasm::Scheduler::read(dir)?))
+        Ok(Scheduler::Wasm(self::wasm::Scheduler::read(dir, runtime_dir)?))
     } else {
         Ok(Scheduler::Lua(self::luatic::read(dir)?))
     }
 }
+
+pub(in scheduler) fn read(base_dir: &Path)
+    -> Result<Scheduler, Error>
+{
+    let ref dir = &base_dir.join("scheduler/v1");
+    let ref runtime_dir = base_dir.join("runtime");
+    if dir.join("pipeline.yaml").exists() {
+        Ok(Scheduler::Pipeline(self::pipeline::read(dir, runtime_dir)?))
+    } else {
+        read_dir(dir, runtime_dir)
+    }
+}
@@ -0,0 +1,609 @@
+//! Scheduler composed of multiple sub-schedulers
+//!
+//! Enabled by `scheduler/v1/pipeline.yaml`. Every sub-scheduler lives in
+//! its own directory (so it can be versioned independently), owns a
+//! namespace of roles and sees parent schedules as if it was the only
+//! scheduler. Results are merged into a single schedule.
+//!
+//! Each sub-scheduler is loaded and run in its own thread, so that
+//! independent ones can run in parallel.
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::{Path, PathBuf, Component};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+
+use failure::{Error, err_msg};
+use quire::validate::{Structure, Sequence, Scalar};
+use quire::{parse_config, Options};
+use serde::Serialize;
+use serde_json::{Value as Json, Map, to_value};
+
+use scheduler;
+use scheduler::main::SchedulerResult;
+
+
+/// Key in the schedule where extra keys of sub-schedulers are stored
+const PIPELINE_KEY: &str = "pipeline";
+
+#[derive(Deserialize)]
+struct Config {
+    mode: String,
+    schedulers: Vec<SubConfig>,
+}
+
+#[derive(Deserialize)]
+struct SubConfig {
+    name: String,
+    path: PathBuf,
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Sub-schedulers are run in order, each one sees results of the
+    /// previous ones
+    Sequential,
+    /// Sub-schedulers get independent inputs, none of them sees results of
+    /// others, and they are run in parallel
+    Independent,
+}
+
+/// Name and roles owned by a sub-scheduler
+struct Namespace {
+    name: String,
+    roles: Vec<String>,
+}
+
+/// Thread owning a scheduler, executes one input at a time
+///
+/// Thread exits when the worker is dropped.
+struct Worker {
+    tx: Sender<Json>,
+    rx: Receiver<Result<SchedulerResult, Error>>,
+}
+
+struct Sub {
+    ns: Namespace,
+    worker: Worker,
+}
+
+pub(in scheduler) struct Pipeline {
+    mode: Mode,
+    subs: Vec<Sub>,
+}
+
+/// Error of the pipeline, contains logs of all sub-schedulers executed
+#[derive(Debug, Fail)]
+#[fail(display="{}", message)]
+pub struct PipelineError {
+    pub message: String,
+    pub log: String,
+}
+
+fn validator() -> Structure<'static> {
+    Structure::new()
+    .member("mode", Scalar::new().default("sequential"))
+    .member("schedulers", Sequence::new(
+        Structure::new()
+        .member("name", Scalar::new())
+        .member("path", Scalar::new())
+        .member("roles", Sequence::new(Scalar::new()))))
+}
+
+fn is_relative(path: &Path) -> bool {
+    path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+/// Role pattern is either a full role name or a prefix ending with `*`
+fn matches_role(pattern: &str, role: &str) -> bool {
+    if pattern.ends_with('*') {
+        role.starts_with(&pattern[..pattern.len()-1])
+    } else {
+        pattern == role
+    }
+}
+
+impl Namespace {
+    fn owns(&self, role: &str) -> bool {
+        self.roles.iter().any(|p| matches_role(p, role))
+    }
+}
+
+pub(in scheduler) fn read(dir: &Path, runtime_dir: &Path)
+    -> Result<Pipeline, Error>
+{
+    let path = dir.join("pipeline.yaml");
+    let config: Config = parse_config(&path,
+        &validator(), &Options::default())
+        .map_err(|e| format_err!("error reading {:?}: {}", path, e))?;
+    let mode = match &config.mode[..] {
+        "sequential" => Mode::Sequential,
+        "independent" => Mode::Independent,
+        _ => bail!("invalid pipeline mode {:?}, \
+            expected sequential or independent", config.mode),
+    };
+    let mut subs: Vec<Sub> = Vec::new();
+    for sub in config.schedulers {
+        if subs.iter().any(|s| s.ns.name == sub.name) {
+            bail!("duplicate sub-scheduler name {:?}", sub.name);
+        }
+        if sub.path.as_os_str().is_empty() || !is_relative(&sub.path) {
+            bail!("sub-scheduler {:?}: path must be relative to {:?}",
+                sub.name, dir);
+        }
+        let worker = Worker::spawn(&sub.name, dir.join(&sub.path),
+                runtime_dir.to_path_buf())
+            .map_err(|e| format_err!("sub-scheduler {:?}: {}", sub.name, e))?;
+        subs.push(Sub {
+            ns: Namespace {
+                name: sub.name,
+                roles: sub.roles,
+            },
+            worker,
+        });
+    }
+    if subs.is_empty() {
+        bail!("no sub-schedulers in {:?}", path);
+    }
+    Ok(Pipeline { mode, subs })
+}
+
+impl Worker {
+    /// Starts a thread and reads scheduler there
+    ///
+    /// Lua and wasm schedulers can't be moved between threads, so the
+    /// scheduler never leaves its thread, only inputs and results do.
+    fn spawn(name: &str, dir: PathBuf, runtime_dir: PathBuf)
+        -> Result<Worker, Error>
+    {
+        let (tx, input_rx) = channel::<Json>();
+        let (result_tx, rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        thread::Builder::new().name(format!("scheduler:{}", name))
+            .spawn(move || {
+                let mut scheduler = match scheduler::read_dir(&dir,
+                                                              &runtime_dir)
+                {
+                    Ok(scheduler) => {
+                        ready_tx.send(Ok(())).ok();
+                        scheduler
+                    }
+                    Err(e) => {
+                        ready_tx.send(Err(e)).ok();
+                        return;
+                    }
+                };
+                for input in input_rx {
+                    if result_tx.send(scheduler.execute(&input)).is_err() {
+                        return;
+                    }
+                }
+            })?;
+        ready_rx.recv()
+            .map_err(|_| err_msg("scheduler thread exited while loading"))??;
+        Ok(Worker { tx, rx })
+    }
+
+    fn start(&self, input: Json) {
+        // if thread is dead, error is reported by `wait()`
+        self.tx.send(input).ok();
+    }
+
+    fn wait(&self) -> Result<SchedulerResult, Error> {
+        self.rx.recv()
+            .map_err(|_| err_msg("scheduler thread exited unexpectedly"))?
+    }
+}
+
+/// Input of a sub-scheduler built from the input of the pipeline
+fn sub_input(input: &Json, parents: &[Json], ns: &Namespace, mode: Mode,
+    previous: &Map<String, Json>)
+    -> Json
+{
+    let mut sub_input = input.clone();
+    let sub_parents = parents.iter()
+        .map(|p| parent_view(ns, p)).collect();
+    let mut info = Map::new();
+    info.insert("name".into(), Json::String(ns.name.clone()));
+    info.insert("mode".into(), Json::String(match mode {
+        Mode::Sequential => "sequential",
+        Mode::Independent => "independent",
+    }.into()));
+    info.insert("previous".into(), Json::Object(previous.clone()));
+    if let Some(obj) = sub_input.as_object_mut() {
+        obj.insert("parents".into(), Json::Array(sub_parents));
+        obj.insert(PIPELINE_KEY.into(), Json::Object(info));
+    }
+    sub_input
+}
+
+/// Makes parent schedule look like it was produced by this sub-scheduler
+///
+/// Only roles owned by the sub-scheduler are kept. Vars are kept as is,
+/// and its own extra keys are moved back to the top level.
+fn parent_view(sub: &Namespace, parent: &Json) -> Json {
+    let parent = match parent.as_object() {
+        Some(parent) => parent,
+        None => return Json::Null,
+    };
+    let mut out = Map::new();
+    if let Some(roles) = parent.get("roles").and_then(|x| x.as_object()) {
+        out.insert("roles".into(), Json::Object(roles.iter()
+            .filter(|&(name, _)| sub.owns(name))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()));
+    }
+    if let Some(nodes) = parent.get("nodes").and_then(|x| x.as_object()) {
+        let mut new_nodes = Map::new();
+        for (name, node) in nodes {
+            let mut node = node.clone();
+            if let Some(roles) = node.get_mut("roles")
+                .and_then(|x| x.as_object_mut())
+            {
+                let names = roles.keys()
+                    .filter(|name| !sub.owns(name))
+                    .cloned().collect::<Vec<_>>();
+                for name in names {
+                    roles.remove(&name);
+                }
+            }
+            new_nodes.insert(name.clone(), node);
+        }
+        out.insert("nodes".into(), Json::Object(new_nodes));
+    }
+    if let Some(vars) = parent.get("vars") {
+        out.insert("vars".into(), vars.clone());
+    }
+    if let Some(extra) = parent.get(PIPELINE_KEY)
+        .and_then(|x| x.get(&sub.name))
+        .and_then(|x| x.as_object())
+    {
+        for (k, v) in extra {
+            out.insert(k.clone(), v.clone());
+        }
+    }
+    Json::Object(out)
+}
+
+/// Inserts a key, returns an error if there is a different value already
+fn merge_key(map: &mut Map<String, Json>, key: &str, value: Json,
+    path: &str, owners: &mut BTreeMap<String, String>, sub: &str,
+    errors: &mut Vec<String>)
+{
+    let full_path = format!("{}.{}", path, key);
+    match map.get(key) {
+        Some(old) if old != &value => {
+            errors.push(format!("{}: value from {:?} conflicts with \
+                value from {:?}",
+                full_path, sub,
+                owners.get(&full_path).map(|x| &x[..]).unwrap_or("?")));
+            return;
+        }
+        Some(_) => return,
+        None => {}
+    }
+    owners.insert(full_path, sub.to_string());
+    map.insert(key.to_string(), value);
+}
+
+fn merge_roles(sub: &Namespace, dest: &mut Map<String, Json>, roles: Json,
+    path: &str, errors: &mut Vec<String>)
+{
+    let roles = match roles {
+        Json::Object(roles) => roles,
+        _ => {
+            errors.push(format!("{}: {:?} returned non-object",
+                path, sub.name));
+            return;
+        }
+    };
+    for (role, value) in roles {
+        if !sub.owns(&role) {
+            errors.push(format!("{}.{}: role is outside of the \
+                namespace of {:?}", path, role, sub.name));
+        } else if dest.contains_key(&role) {
+            errors.push(format!("{}.{}: role is returned by multiple \
+                sub-schedulers", path, role));
+        } else {
+            dest.insert(role, value);
+        }
+    }
+}
+
+fn merge<'a, I>(results: I) -> Result<Json, Vec<String>>
+    where I: IntoIterator<Item=(&'a Namespace, Json)>
+{
+    let mut errors = Vec::new();
+    let mut owners = BTreeMap::new();
+    let mut roles = Map::new();
+    let mut nodes = Map::new();
+    let mut vars = Map::new();
+    let mut changes = Vec::new();
+    let mut extra = Map::new();
+    for (sub, schedule) in results {
+        let schedule = match schedule {
+            Json::Object(schedule) => schedule,
+            other => {
+                errors.push(format!("{:?} returned {} instead of object",
+                    sub.name, other));
+                continue;
+            }
+        };
+        let mut sub_extra = Map::new();
+        for (key, value) in schedule {
+            match &key[..] {
+                "roles" => {
+                    merge_roles(sub, &mut roles, value, "roles", &mut errors);
+                }
+                "nodes" => {
+                    let value = match value {
+                        Json::Object(value) => value,
+                        _ => {
+                            errors.push(format!("nodes: {:?} returned \
+                                non-object", sub.name));
+                            continue;
+                        }
+                    };
+                    for (name, node) in value {
+                        let path = format!("nodes.{}", name);
+                        let node = match node {
+                            Json::Object(node) => node,
+                            _ => {
+                                errors.push(format!("{}: {:?} returned \
+                                    non-object", path, sub.name));
+                                continue;
+                            }
+                        };
+                        let dest = nodes.entry(name)
+                            .or_insert_with(|| Json::Object(Map::new()))
+                            .as_object_mut().expect("node is an object");
+                        for (nkey, nvalue) in node {
+                            match (&nkey[..], nvalue) {
+                                ("roles", nvalue) => {
+                                    let dest = dest.entry("roles")
+                                        .or_insert_with(||
+                                            Json::Object(Map::new()))
+                                        .as_object_mut()
+                                        .expect("roles is an object");
+                                    merge_roles(sub, dest, nvalue,
+                                        &format!("{}.roles", path),
+                                        &mut errors);
+                                }
+                                ("vars", Json::Object(nvars)) => {
+                                    let dest = dest.entry("vars")
+                                        .or_insert_with(||
+                                            Json::Object(Map::new()))
+                                        .as_object_mut()
+                                        .expect("vars is an object");
+                                    for (k, v) in nvars {
+                                        merge_key(dest, &k, v,
+                                            &format!("{}.vars", path),
+                                            &mut owners, &sub.name,
+                                            &mut errors);
+                                    }
+                                }
+                                (_, nvalue) => {
+                                    merge_key(dest, &nkey, nvalue, &path,
+                                        &mut owners, &sub.name, &mut errors);
+                                }
+                            }
+                        }
+                    }
+                }
+                "vars" => match value {
+                    Json::Object(value) => {
+                        for (k, v) in value {
+                            merge_key(&mut vars, &k, v, "vars",
+                                &mut owners, &sub.name, &mut errors);
+                        }
+                    }
+                    _ => {
+                        errors.push(format!("vars: {:?} returned \
+                            non-object", sub.name));
+                    }
+                },
+                "changes" => match value {
+                    Json::Array(value) => changes.extend(value),
+                    _ => {
+                        errors.push(format!("changes: {:?} returned \
+                            non-array", sub.name));
+                    }
+                },
+                _ => {
+                    sub_extra.insert(key, value);
+                }
+            }
+        }
+        extra.insert(sub.name.clone(), Json::Object(sub_extra));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    let mut result = Map::new();
+    result.insert("roles".into(), Json::Object(roles));
+    result.insert("nodes".into(), Json::Object(nodes));
+    result.insert("vars".into(), Json::Object(vars));
+    result.insert("changes".into(), Json::Array(changes));
+    result.insert(PIPELINE_KEY.into(), Json::Object(extra));
+    Ok(Json::Object(result))
+}
+
+impl Pipeline {
+    pub fn execute<S: Serialize>(&mut self, input: &S)
+        -> Result<SchedulerResult, Error>
+    {
+        let input = to_value(input)?;
+        let parents = input.get("parents")
+            .and_then(|x| x.as_array()).cloned()
+            .unwrap_or_else(Vec::new);
+        let mut outcomes = Vec::with_capacity(self.subs.len());
+        match self.mode {
+            Mode::Sequential => {
+                let mut previous = Map::new();
+                for sub in &self.subs {
+                    sub.worker.start(sub_input(&input, &parents, &sub.ns,
+                        self.mode, &previous));
+                    let outcome = sub.worker.wait();
+                    let failed = match outcome {
+                        Ok(ref res) => {
+                            previous.insert(sub.ns.name.clone(),
+                                res.schedule.clone());
+                            false
+                        }
+                        Err(_) => true,
+                    };
+                    outcomes.push(outcome);
+                    if failed {
+                        break;
+                    }
+                }
+            }
+            Mode::Independent => {
+                for sub in &self.subs {
+                    sub.worker.start(sub_input(&input, &parents, &sub.ns,
+                        self.mode, &Map::new()));
+                }
+                // wait for all of them, so no stale results are left
+                for sub in &self.subs {
+                    outcomes.push(sub.worker.wait());
+                }
+            }
+        }
+        let mut log = String::new();
+        let mut results = Vec::with_capacity(self.subs.len());
+        let mut actions = HashMap::new();
+        for (sub, outcome) in self.subs.iter().zip(outcomes) {
+            writeln!(&mut log, "===== Sub-scheduler {:?} =====",
+                sub.ns.name).ok();
+            match outcome {
+                Ok(res) => {
+                    log.push_str(&res.log);
+                    if !log.ends_with('\n') {
+                        log.push('\n');
+                    }
+                    for (id, response) in res.actions {
+                        if actions.contains_key(&id) {
+                            writeln!(&mut log, "Response to action {} \
+                                is ignored, it's already answered by \
+                                another sub-scheduler", id).ok();
+                        } else {
+                            actions.insert(id, response);
+                        }
+                    }
+                    results.push(res.schedule);
+                }
+                Err(e) => {
+                    writeln!(&mut log, "Error: {}", e).ok();
+                    return Err(PipelineError {
+                        message: format!("sub-scheduler {:?} failed: {}",
+                            sub.ns.name, e),
+                        log,
+                    }.into());
+                }
+            }
+        }
+        match merge(self.subs.iter().map(|s| &s.ns).zip(results)) {
+            Ok(schedule) => Ok(SchedulerResult { schedule, log, actions }),
+            Err(errors) => {
+                writeln!(&mut log, "===== Merge errors =====").ok();
+                for e in &errors {
+                    writeln!(&mut log, "{}", e).ok();
+                }
+                Err(PipelineError {
+                    message: format!("conflicts merging schedules: {}",
+                        errors.join("; ")),
+                    log,
+                }.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value as Json, from_str};
+    use super::{matches_role, merge, Namespace};
+
+    fn ns(name: &str, roles: &[&str]) -> Namespace {
+        Namespace {
+            name: name.into(),
+            roles: roles.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    fn json(s: &str) -> Json {
+        from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_role_patterns() {
+        assert!(matches_role("web", "web"));
+        assert!(!matches_role("web", "web-admin"));
+        assert!(matches_role("web-*", "web-admin"));
+        assert!(!matches_role("web-*", "db"));
+        assert!(matches_role("*", "anything"));
+    }
+
+    #[test]
+    fn test_merge_independent() {
+        let web = ns("web", &["web-*"]);
+        let db = ns("db", &["db"]);
+        let result = merge(vec![
+            (&web, json(r#"{
+                "roles": {"web-front": {"x": 1}},
+                "nodes": {"n1": {"roles": {"web-front": {}},
+                                 "vars": {"dc": "one"}}},
+                "vars": {"dc": "one"},
+                "changes": ["web"],
+                "state": 1
+            }"#)),
+            (&db, json(r#"{
+                "roles": {"db": {}},
+                "nodes": {"n1": {"roles": {"db": {}},
+                                 "vars": {"dc": "one"}}},
+                "vars": {"dc": "one", "db": "pg"},
+                "changes": ["db"]
+            }"#)),
+        ]).unwrap();
+        assert_eq!(result, json(r#"{
+            "roles": {"web-front": {"x": 1}, "db": {}},
+            "nodes": {"n1": {"roles": {"web-front": {}, "db": {}},
+                             "vars": {"dc": "one"}}},
+            "vars": {"dc": "one", "db": "pg"},
+            "changes": ["web", "db"],
+            "pipeline": {"web": {"state": 1}, "db": {}}
+        }"#));
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let web = ns("web", &["web-*"]);
+        let db = ns("db", &["db", "web-*"]);
+        let errors = merge(vec![
+            (&web, json(r#"{
+                "roles": {"web-front": {}},
+                "nodes": {"n1": {"vars": {"dc": "one"}}},
+                "vars": {"dc": "one"}
+            }"#)),
+            (&db, json(r#"{
+                "roles": {"web-front": {}, "cache": {}},
+                "nodes": {"n1": {"vars": {"dc": "two"}}},
+                "vars": {"dc": "two"}
+            }"#)),
+        ]).unwrap_err();
+        assert_eq!(errors, vec![
+            String::from("nodes.n1.vars.dc: value from \"db\" conflicts \
+                with value from \"web\""),
+            String::from("roles.cache: role is outside of the \
+                namespace of \"db\""),
+            String::from("roles.web-front: role is returned by \
+                multiple sub-schedulers"),
+            String::from("vars.dc: value from \"db\" conflicts \
+                with value from \"web\""),
+        ]);
+    }
+}
@@ -0,0 +1,308 @@
+//! Validation of scheduler output
+//!
+//! Schema is put at `scheduler/v1/schema.json` and uses a subset of
+//! JSON Schema: `type`, `properties`, `required`, `additionalProperties`,
+//! `items`, `enum`, `minimum`, `maximum`, `minItems`, `maxItems`. Other
+//! keywords are ignored with a warning.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+
+use failure::Error;
+use serde_json::{Value as Json, from_reader};
+
+
+/// Maximum number of errors reported for a single schedule
+const MAX_ERRORS: usize = 20;
+
+/// Keywords that don't affect validation, so are ignored silently
+const ANNOTATIONS: &[&str] = &[
+    "$schema", "$id", "$comment", "title", "description",
+    "default", "examples",
+];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="lowercase")]
+enum Type {
+    Object,
+    Array,
+    String,
+    Number,
+    Integer,
+    Boolean,
+    Null,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Types {
+    One(Type),
+    Many(Vec<Type>),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum Additional {
+    Allowed(bool),
+    Schema(Box<Schema>),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Schema {
+    #[serde(rename="type")]
+    types: Option<Types>,
+    #[serde(default)]
+    properties: BTreeMap<String, Schema>,
+    #[serde(default)]
+    required: Vec<String>,
+    #[serde(rename="additionalProperties")]
+    additional: Option<Additional>,
+    items: Option<Box<Schema>>,
+    #[serde(rename="enum")]
+    variants: Option<Vec<Json>>,
+    minimum: Option<f64>,
+    maximum: Option<f64>,
+    #[serde(rename="minItems")]
+    min_items: Option<usize>,
+    #[serde(rename="maxItems")]
+    max_items: Option<usize>,
+    /// Keywords that are not supported (or annotations)
+    #[serde(flatten)]
+    unknown: BTreeMap<String, Json>,
+}
+
+fn type_of(value: &Json) -> &'static str {
+    match *value {
+        Json::Null => "null",
+        Json::Bool(_) => "boolean",
+        Json::Number(_) => "number",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+    }
+}
+
+fn matches_type(typ: Type, value: &Json) -> bool {
+    match (typ, value) {
+        (Type::Object, &Json::Object(_)) => true,
+        (Type::Array, &Json::Array(_)) => true,
+        (Type::String, &Json::String(_)) => true,
+        (Type::Number, &Json::Number(_)) => true,
+        (Type::Integer, &Json::Number(ref n)) => {
+            n.is_i64() || n.is_u64() ||
+                n.as_f64().map(|x| x.fract() == 0.0).unwrap_or(false)
+        }
+        (Type::Boolean, &Json::Bool(_)) => true,
+        (Type::Null, &Json::Null) => true,
+        _ => false,
+    }
+}
+
+fn display_path(path: &str) -> &str {
+    if path.is_empty() { "<root>" } else { path }
+}
+
+fn subpath(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+impl Schema {
+    /// Returns paths of keywords which are ignored by validator
+    fn unsupported(&self, path: &str, out: &mut Vec<String>) {
+        for key in self.unknown.keys() {
+            if !ANNOTATIONS.contains(&&key[..]) {
+                out.push(subpath(path, key));
+            }
+        }
+        for (key, schema) in &self.properties {
+            schema.unsupported(&subpath(path, &format!("properties.{}", key)),
+                out);
+        }
+        if let Some(Additional::Schema(ref schema)) = self.additional {
+            schema.unsupported(&subpath(path, "additionalProperties"), out);
+        }
+        if let Some(ref schema) = self.items {
+            schema.unsupported(&subpath(path, "items"), out);
+        }
+    }
+    fn check(&self, path: &str, value: &Json, errors: &mut Vec<String>) {
+        if errors.len() >= MAX_ERRORS {
+            return;
+        }
+        let type_ok = match self.types {
+            None => true,
+            Some(Types::One(typ)) => matches_type(typ, value),
+            Some(Types::Many(ref types)) => {
+                types.iter().any(|&t| matches_type(t, value))
+            }
+        };
+        if !type_ok {
+            errors.push(format!("{}: unexpected {}",
+                display_path(path), type_of(value)));
+            return;
+        }
+        if let Some(ref variants) = self.variants {
+            if !variants.contains(value) {
+                errors.push(format!("{}: value {} is not allowed",
+                    display_path(path), value));
+            }
+        }
+        match *value {
+            Json::Number(ref n) => {
+                let n = n.as_f64().unwrap_or(0.);
+                if self.minimum.map(|m| n < m).unwrap_or(false) ||
+                   self.maximum.map(|m| n > m).unwrap_or(false)
+                {
+                    errors.push(format!("{}: value {} is out of range",
+                        display_path(path), n));
+                }
+            }
+            Json::Array(ref items) => {
+                if self.min_items.map(|m| items.len() < m).unwrap_or(false)
+                   || self.max_items.map(|m| items.len() > m).unwrap_or(false)
+                {
+                    errors.push(format!("{}: unexpected number of items {}",
+                        display_path(path), items.len()));
+                }
+                if let Some(ref schema) = self.items {
+                    for (idx, item) in items.iter().enumerate() {
+                        schema.check(&format!("{}[{}]", path, idx),
+                            item, errors);
+                    }
+                }
+            }
+            Json::Object(ref map) => {
+                for key in &self.required {
+                    if !map.contains_key(key) {
+                        errors.push(format!("{}: required key is missing",
+                            subpath(path, key)));
+                    }
+                }
+                for (key, item) in map {
+                    let item_path = subpath(path, key);
+                    match (self.properties.get(key), &self.additional) {
+                        (Some(schema), _) => {
+                            schema.check(&item_path, item, errors);
+                        }
+                        (None, &Some(Additional::Allowed(false))) => {
+                            errors.push(format!("{}: unexpected key",
+                                item_path));
+                        }
+                        (None, &Some(Additional::Schema(ref schema))) => {
+                            schema.check(&item_path, item, errors);
+                        }
+                        (None, _) => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    /// Returns list of errors, each prefixed by path of failing value
+    pub fn validate(&self, value: &Json) -> Vec<String> {
+        let mut errors = Vec::new();
+        self.check("", value, &mut errors);
+        errors.truncate(MAX_ERRORS);
+        return errors;
+    }
+}
+
+/// Reads schema of scheduler output if it exists
+pub fn read(base_dir: &Path) -> Result<Option<Schema>, Error> {
+    let path = base_dir.join("scheduler/v1/schema.json");
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = File::open(&path)
+        .map_err(|e| format_err!("can't open {:?}: {}", path, e))?;
+    let schema: Schema = from_reader(file)
+        .map_err(|e| format_err!("error parsing {:?}: {}", path, e))?;
+    let mut unsupported = Vec::new();
+    schema.unsupported("", &mut unsupported);
+    if !unsupported.is_empty() {
+        warn!("Schema {:?}: unsupported keywords are ignored: {}",
+            path, unsupported.join(", "));
+    }
+    Ok(Some(schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_str;
+    use super::Schema;
+
+    fn schema() -> Schema {
+        from_str(r#"{
+            "type": "object",
+            "required": ["roles", "nodes"],
+            "properties": {
+                "roles": {"type": "object"},
+                "nodes": {
+                    "type": "object",
+                    "additionalProperties": {
+                        "type": "object",
+                        "properties": {
+                            "roles": {"type": "object"},
+                            "vars": {"type": "object"}
+                        },
+                        "additionalProperties": false
+                    }
+                }
+            }
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let schema: Schema = from_str(r#"{
+            "$schema": "http://json-schema.org/draft-07/schema#",
+            "description": "scheduler output",
+            "type": "object",
+            "properties": {
+                "roles": {"$ref": "#/definitions/roles"},
+                "name": {"type": "string", "pattern": "^[a-z]+$",
+                         "minLength": 1}
+            },
+            "definitions": {"roles": {"type": "object"}}
+        }"#).unwrap();
+        let mut unsupported = Vec::new();
+        schema.unsupported("", &mut unsupported);
+        assert_eq!(unsupported, vec![
+            "definitions",
+            "properties.name.minLength",
+            "properties.name.pattern",
+            "properties.roles.$ref",
+        ]);
+        let value = from_str(r#"{"name": "x", "roles": []}"#).unwrap();
+        assert_eq!(schema.validate(&value), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_valid() {
+        let value = from_str(r#"{"roles": {}, "nodes": {
+            "alpha": {"roles": {}, "vars": {}}}}"#).unwrap();
+        assert_eq!(schema().validate(&value), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_nodes_list() {
+        let value = from_str(r#"{"roles": {}, "nodes": []}"#).unwrap();
+        assert_eq!(schema().validate(&value),
+            vec!["nodes: unexpected array"]);
+    }
+
+    #[test]
+    fn test_nested() {
+        let value = from_str(r#"{"nodes": {
+            "alpha": {"roles": [], "other": 1}}}"#).unwrap();
+        assert_eq!(schema().validate(&value), vec![
+            "roles: required key is missing",
+            "nodes.alpha.other: unexpected key",
+            "nodes.alpha.roles: unexpected array",
+        ]);
+    }
+}
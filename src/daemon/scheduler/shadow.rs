@@ -0,0 +1,232 @@
+//! Shadow mode for reloaded scheduler code
+//!
+//! When enabled, new scheduler code is not used right away. Instead it
+//! runs on the same input as the current scheduler for a number of rounds
+//! and differences between schedules are recorded. New scheduler is
+//! promoted either explicitly by API or automatically when required number
+//! of rounds passed and diff-check hook (if any) does not object.
+use std::fmt::Write as FmtWrite;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant, SystemTime};
+
+use serde::Serialize;
+use serde_json::{Value as Json, to_vec};
+use serde_millis;
+
+use scheduler::Scheduler;
+
+
+/// Maximum number of differences stored per round
+const MAX_DIFFS: usize = 100;
+/// Maximum time diff-check hook may run
+const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Difference {
+    pub path: String,
+    pub current: Option<Json>,
+    pub shadow: Option<Json>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ShadowStatus {
+    #[serde(with="serde_millis")]
+    pub loaded: SystemTime,
+    pub rounds: u32,
+    pub required_rounds: u32,
+    pub rounds_with_differences: u32,
+    pub last_error: Option<String>,
+    pub differences: Vec<Difference>,
+    /// True if number of differences exceeds `MAX_DIFFS`
+    pub differences_truncated: bool,
+    pub hook_objection: Option<String>,
+}
+
+pub(in scheduler) struct Shadow {
+    pub(in scheduler) scheduler: Scheduler,
+    pub(in scheduler) status: ShadowStatus,
+}
+
+fn subpath(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn diff(path: &str, current: Option<&Json>, shadow: Option<&Json>,
+    out: &mut Vec<Difference>) -> bool
+{
+    match (current, shadow) {
+        (Some(&Json::Object(ref a)), Some(&Json::Object(ref b))) => {
+            for (key, value) in a {
+                if !diff(&subpath(path, key), Some(value), b.get(key), out) {
+                    return false;
+                }
+            }
+            for (key, value) in b {
+                if !a.contains_key(key) {
+                    if !diff(&subpath(path, key), None, Some(value), out) {
+                        return false;
+                    }
+                }
+            }
+            true
+        }
+        (a, b) if a == b => true,
+        (a, b) => {
+            if out.len() >= MAX_DIFFS {
+                return false;
+            }
+            out.push(Difference {
+                path: path.to_string(),
+                current: a.cloned(),
+                shadow: b.cloned(),
+            });
+            true
+        }
+    }
+}
+
+fn run_hook(hook: &Path, status: &ShadowStatus) -> Result<(), String> {
+    let mut child = Command::new(hook)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("can't run {:?}: {}", hook, e))?;
+    let data = to_vec(status).expect("can serialize status");
+    // Status may be larger than pipe buffer, so write it in a thread to
+    // keep timeout working when hook doesn't read its input. The write
+    // fails with broken pipe as soon as the hook exits or is killed.
+    let writer = child.stdin.take().map(|mut stdin| {
+        thread::spawn(move || stdin.write_all(&data))
+    });
+    let deadline = Instant::now() + HOOK_TIMEOUT;
+    let result = loop {
+        match child.try_wait() {
+            Ok(Some(st)) if st.success() => break Ok(()),
+            Ok(Some(st)) => break Err(format!("{:?} {}", hook, st)),
+            Ok(None) if Instant::now() > deadline => {
+                child.kill().ok();
+                child.wait().ok();
+                break Err(format!("{:?} timed out", hook));
+            }
+            Ok(None) => sleep(Duration::from_millis(50)),
+            Err(e) => {
+                child.kill().ok();
+                child.wait().ok();
+                break Err(format!("error waiting {:?}: {}", hook, e));
+            }
+        }
+    };
+    let written = writer.map(|w| w.join().expect("writer doesn't panic"));
+    match written {
+        // hook is free to exit without reading the status
+        Some(Err(ref e)) if e.kind() != io::ErrorKind::BrokenPipe => {
+            result.and(Err(format!("can't write to {:?}: {}", hook, e)))
+        }
+        _ => result,
+    }
+}
+
+impl Shadow {
+    pub fn new(scheduler: Scheduler, required_rounds: u32) -> Shadow {
+        Shadow {
+            scheduler,
+            status: ShadowStatus {
+                loaded: SystemTime::now(),
+                rounds: 0,
+                required_rounds,
+                rounds_with_differences: 0,
+                last_error: None,
+                differences: Vec::new(),
+                differences_truncated: false,
+                hook_objection: None,
+            },
+        }
+    }
+    /// Runs shadow scheduler and compares result with the current one
+    ///
+    /// Summary of the differences is appended to the `log`
+    pub fn round<S: Serialize>(&mut self, input: &S, current: &Json,
+        log: &mut String)
+    {
+        let st = &mut self.status;
+        st.rounds += 1;
+        writeln!(log, "===== Shadow scheduler, round {}/{} =====",
+            st.rounds, st.required_rounds).ok();
+        match self.scheduler.execute(input) {
+            Ok(res) => {
+                st.last_error = None;
+                let mut diffs = Vec::new();
+                st.differences_truncated = !diff("",
+                    Some(current), Some(&res.schedule), &mut diffs);
+                if !diffs.is_empty() {
+                    st.rounds_with_differences += 1;
+                }
+                writeln!(log, "{} differences{}", diffs.len(),
+                    if st.differences_truncated { " (truncated)" }
+                    else { "" }).ok();
+                for d in &diffs {
+                    writeln!(log, "  {}: {} -> {}", d.path,
+                        d.current.as_ref().map(|x| x.to_string())
+                            .unwrap_or_else(|| "<none>".into()),
+                        d.shadow.as_ref().map(|x| x.to_string())
+                            .unwrap_or_else(|| "<none>".into())).ok();
+                }
+                st.differences = diffs;
+            }
+            Err(e) => {
+                writeln!(log, "Error: {}", e).ok();
+                st.last_error = Some(e.to_string());
+                st.differences.clear();
+                st.differences_truncated = false;
+            }
+        }
+    }
+    /// Returns true if scheduler may be promoted automatically
+    pub fn check(&mut self, hook: Option<&PathBuf>, log: &mut String)
+        -> bool
+    {
+        if self.status.rounds < self.status.required_rounds {
+            return false;
+        }
+        if let Some(ref err) = self.status.last_error {
+            writeln!(log, "Shadow scheduler is not promoted: {}", err).ok();
+            return false;
+        }
+        if let Some(hook) = hook {
+            match run_hook(hook, &self.status) {
+                Ok(()) => self.status.hook_objection = None,
+                Err(e) => {
+                    writeln!(log, "Shadow scheduler is not promoted, \
+                        diff-check hook objects: {}", e).ok();
+                    self.status.hook_objection = Some(e);
+                    return false;
+                }
+            }
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::from_str;
+    use super::diff;
+
+    #[test]
+    fn test_diff() {
+        let a = from_str(r#"{"roles": {"x": {"v": 1}}, "vars": {}}"#)
+            .unwrap();
+        let b = from_str(r#"{"roles": {"x": {"v": 2}, "y": {}}}"#)
+            .unwrap();
+        let mut out = Vec::new();
+        assert!(diff("", Some(&a), Some(&b), &mut out));
+        let paths = out.iter().map(|d| &d.path[..]).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["roles.x.v", "roles.y", "vars"]);
+    }
+}
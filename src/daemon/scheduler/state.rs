@@ -1,4 +1,7 @@
-use serde_json::Value as Json;
+use std::time::SystemTime;
+
+use serde_json::{Value as Json, from_value};
+use serde_millis;
 
 use hash::hash;
 use id::Id;
@@ -11,6 +14,23 @@ pub struct Schedule {
     pub hash: ScheduleId,
     pub data: Json,
     pub origin: Id,
+    /// Set when leader publishes a backup instead of running scheduler
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub pinned: Option<Pin>,
+    /// Action queue replicated with the schedule, not a part of the hash
+    #[serde(skip_serializing_if="Option::is_none")]
+    pub actions: Option<Json>,
+}
+
+/// Backup pinned as the active schedule
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Pin {
+    /// Name of the backup as listed in `/v1/backups`
+    pub backup: String,
+    /// Hash of the schedule in the backup
+    pub hash: ScheduleId,
+    #[serde(with="serde_millis")]
+    pub since: SystemTime,
 }
 
 pub fn from_json(json: Json) -> Result<Schedule, String> {
@@ -25,6 +45,8 @@ pub fn from_json(json: Json) -> Result<Schedule, String> {
         .and_then(|x| x.as_str().and_then(|x| x.parse().ok()));
     let timestamp = j.remove("timestamp").and_then(|x| x.as_u64());
     let data = j.remove("data");
+    let pinned = j.remove("pinned").and_then(|x| from_value(x).ok());
+    let actions = j.remove("actions");
     match (hashvalue, timestamp, data, origin) {
         (Some(Json::String(h)), Some(t), Some(d), Some(o)) => {
             let hash = hash(d.to_string());
@@ -36,6 +58,8 @@ pub fn from_json(json: Json) -> Result<Schedule, String> {
                     hash: h.to_string(),
                     data: d,
                     origin: o,
+                    pinned: pinned,
+                    actions: actions,
                 })
             }
         }
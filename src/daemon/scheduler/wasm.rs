@@ -11,15 +11,31 @@ pub(in scheduler) struct Scheduler {
 }
 
 impl Scheduler {
-    pub fn read(dir: &Path)
+    pub fn read(dir: &Path, runtime_dir: &Path)
         -> Result<Scheduler, Error>
     {
-        Program::read(&dir.join("scheduler.wasm"))
-        .map(|wasm| Scheduler { wasm })
+        let mut wasm = Program::read(&dir.join("scheduler.wasm"))?;
+        wasm.set_runtime_dir(runtime_dir);
+        Ok(Scheduler { wasm })
     }
     pub fn execute<S: Serialize>(&mut self, input: &S)
         -> Result<SchedulerResult, Error>
     {
-        self.wasm.json_call("scheduler", input)
+        let result = self.wasm.json_call("scheduler", input);
+        // messages logged through host functions go before the log
+        // returned by the scheduler itself
+        let log = self.wasm.take_log();
+        match result {
+            Ok(mut res) => {
+                if !log.is_empty() {
+                    res.log = log + &res.log;
+                }
+                Ok(res)
+            }
+            Err(ref e) if !log.is_empty() => {
+                Err(format_err!("{}\nScheduler log:\n{}", e, log))
+            }
+            Err(e) => Err(e),
+        }
     }
 }
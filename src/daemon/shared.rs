@@ -3,8 +3,9 @@ use std::sync::{Arc, Mutex, MutexGuard};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime};
 use std::collections::{HashMap, BTreeMap, HashSet};
-use std::collections::btree_map::Entry::{Occupied, Vacant};
+use std::io;
 use std::mem;
+use std::path::Path;
 
 use futures::sync::oneshot;
 use tokio_core::reactor::Remote;
@@ -13,7 +14,9 @@ use serde_json::{Value as Json, to_string};
 use crossbeam::atomic::ArcCell;
 use self_meter_http::Meter;
 
-use config::Sandbox;
+use apply::{RoleRetry, Rollback};
+use actions::{Queue, ActionRecord, ActionStatus, Filter, CancelError};
+use config::{Sandbox, RenderResult};
 use elect::{ElectionState, Epoch};
 use fetch;
 use frontend;
@@ -22,8 +25,9 @@ use id::Id;
 use {Options};
 use peer::{Peer, Peers};
 use metrics::{Integer, List, Counter, Metric};
-use scheduler::{Schedule, SchedulerInput, ScheduleId};
+use scheduler::{Schedule, SchedulerInput, ScheduleId, ShadowStatus, Pin};
 use query::Responder;
+use webhooks::{self, Event};
 
 lazy_static! {
     static ref ACTIONS_EXECUTED: Counter = Counter::new();
@@ -67,9 +71,12 @@ pub struct SharedData {
     pub fetch_state: ArcCell<fetch::PublicState>,
     pub meter: Meter,
     graphql: frontend::channel::Sender,
+    webhooks: webhooks::Sender,
     num_roles: AtomicUsize,
     peers: ArcCell<Peers>,
     responder: Responder,
+    /// Version of the action queue last written to disk
+    actions_written: Mutex<u64>,
 }
 
 pub struct LeaderCookie {
@@ -83,16 +90,20 @@ pub enum PushActionError {
     NotALeader,
 }
 
+pub enum CancelActionError {
+    NotALeader,
+    NotFound,
+    AlreadyStarted,
+    AlreadyFinished(ActionStatus),
+}
+
 #[derive(Debug)]
 pub enum ActionError {
     NoResponse,
+    Cancelled,
 }
 
-#[derive(Debug)]
-pub struct Action {
-    data: Arc<Json>,
-    channel: oneshot::Sender<Result<Json, ActionError>>,
-}
+type ActionWaiter = oneshot::Sender<Result<Json, ActionError>>;
 
 #[derive(Debug)]
 struct State {
@@ -101,9 +112,18 @@ struct State {
     owned_schedule: Option<Arc<Schedule>>,
     last_scheduler_debug_info: Arc<Option<(SchedulerInput, String)>>,
     election: Arc<ElectionState>,
-    actions: BTreeMap<u64, Action>,
+    actions: Queue,
+    actions_version: u64,
+    action_waiters: HashMap<u64, Vec<ActionWaiter>>,
     errors: Arc<HashMap<&'static str, String>>,
     failed_roles: Arc<HashSet<String>>,
+    render_results: Arc<HashMap<String, Arc<RenderResult>>>,
+    role_retries: Arc<HashMap<String, RoleRetry>>,
+    role_rollbacks: Arc<HashMap<String, Rollback>>,
+    shadow_scheduler: Option<Arc<ShadowStatus>>,
+    promote_shadow: bool,
+    /// Backup published by the leader instead of running the scheduler
+    pinned_schedule: Option<Arc<Schedule>>,
     // TODO(tailhook) it's a bit ugly that parents used only once, are
     // stored here
     parent_schedules: Option<Vec<Arc<Schedule>>>,
@@ -116,6 +136,28 @@ impl Deref for SharedState {
     }
 }
 
+fn now_ms() -> u64 {
+    let now = get_time();
+    (now.sec as u64) * 1000 + (now.nsec / 1_000_000) as u64
+}
+
+fn load_actions(storage_dir: &Path) -> Queue {
+    let path = storage_dir.join("actions.json");
+    match Queue::load(&path) {
+        Ok(queue) => {
+            if !queue.pending.is_empty() {
+                warn!("Loaded {} pending actions", queue.pending.len());
+            }
+            queue
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Queue::new(),
+        Err(e) => {
+            error!("Error reading actions from {:?}: {}", path, e);
+            Queue::new()
+        }
+    }
+}
+
 fn last_known(schedule: Arc<Schedule>) -> Option<(Arc<Schedule>, Arc<String>)>
 {
     let s = to_string(&schedule).expect("can serialize schedule");
@@ -129,9 +171,11 @@ impl SharedState {
                responder: &Responder,
                mainloop: &Remote,
                meter: &Meter,
-               graphql: &frontend::channel::Sender)
+               graphql: &frontend::channel::Sender,
+               webhooks: webhooks::Sender)
         -> SharedState
     {
+        let actions = load_actions(&options.storage_dir);
         SharedState(
             Arc::new(SharedData {
                 id: id.clone(),
@@ -147,15 +191,25 @@ impl SharedState {
                     Arc::new(fetch::PublicState::Unstable)),
                 responder: responder.clone(),
                 graphql: graphql.clone(),
+                webhooks,
+                actions_written: Mutex::new(0),
             }),
             Arc::new(Mutex::new(State {
                 last_known_schedule: old_schedule.map(Arc::new)
                     .and_then(last_known),
                 last_scheduler_debug_info: Arc::new(None),
                 election: Arc::new(ElectionState::blank()),
-                actions: BTreeMap::new(),
+                actions,
+                actions_version: 0,
+                action_waiters: HashMap::new(),
                 errors: Arc::new(HashMap::new()),
                 failed_roles: Arc::new(HashSet::new()),
+                render_results: Arc::new(HashMap::new()),
+                role_retries: Arc::new(HashMap::new()),
+                role_rollbacks: Arc::new(HashMap::new()),
+                shadow_scheduler: None,
+                promote_shadow: false,
+                pinned_schedule: None,
                 stable_schedule: None,
                 owned_schedule: None,
                 parent_schedules: None,
@@ -168,6 +222,27 @@ impl SharedState {
     fn trigger(&self, sub: Subscription) {
         self.graphql.trigger(sub);
     }
+    /// Queues a webhook notification, never blocks
+    pub fn webhook(&self, event: Event) {
+        self.webhooks.send(event);
+    }
+    /// Writes action queue to disk, must be called without lock held
+    fn save_actions(&self) {
+        let (version, queue) = {
+            let guard = self.lock();
+            (guard.actions_version, guard.actions.clone())
+        };
+        let mut written = self.actions_written.lock()
+            .expect("actions file lock");
+        if *written >= version {
+            return;
+        }
+        let path = self.options.storage_dir.join("actions.json");
+        match queue.save(&path) {
+            Ok(()) => *written = version,
+            Err(e) => error!("Can't write actions to {:?}: {}", path, e),
+        }
+    }
     // Accessors
     pub fn id(&self) -> &Id {
         &self.id
@@ -202,14 +277,29 @@ impl SharedState {
         self.lock().owned_schedule.clone()
     }
     pub fn pending_actions(&self) -> BTreeMap<u64, Arc<Json>> {
-        self.lock().actions.iter().map(|(&k, a)| (k, a.data.clone())).collect()
+        self.lock().actions.pending.iter()
+            .map(|(&k, a)| (k, a.data.clone())).collect()
+    }
+    pub fn action(&self, id: u64) -> Option<ActionRecord> {
+        self.lock().actions.get(id).cloned()
+    }
+    /// Pending actions followed by finished ones, newest first
+    pub fn actions(&self, filter: &Filter) -> Vec<ActionRecord> {
+        self.lock().actions.list(filter)
     }
     pub fn errors(&self) -> Arc<HashMap<&'static str, String>> {
         self.lock().errors.clone()
     }
+    pub fn shadow_scheduler(&self) -> Option<Arc<ShadowStatus>> {
+        self.lock().shadow_scheduler.clone()
+    }
     pub fn failed_roles(&self) -> Arc<HashSet<String>> {
         self.lock().failed_roles.clone()
     }
+    /// Results of the last render of every role, reported by the renderer
+    pub fn render_results(&self) -> Arc<HashMap<String, Arc<RenderResult>>> {
+        self.lock().render_results.clone()
+    }
     // Setters
     pub fn set_peers(&self, time: SystemTime, peers: HashMap<Id, Peer>) {
         // all this logic seems to be very ugly
@@ -261,11 +351,18 @@ impl SharedState {
         {
             guard.last_known_schedule = last_known(schedule.clone());
             guard.stable_schedule = Some(schedule.clone());
+            if let Some(queue) = Queue::from_schedule(&schedule) {
+                let local = mem::replace(&mut guard.actions, queue);
+                guard.actions.merge(local);
+                guard.actions_version += 1;
+            }
             self.0.responder.new_schedule(schedule.clone());
         } else {
             debug!("Ingoring follower schedule {} from {}",
                 schedule.hash, schedule.origin);
         }
+        drop(guard);
+        self.save_actions();
         self.trigger(Subscription::Status);
     }
     pub fn reset_stable_schedule(&self) {
@@ -273,23 +370,40 @@ impl SharedState {
         self.lock().stable_schedule = None;
         self.trigger(Subscription::Status);
     }
+    /// Publishes new schedule, returns its hash if the schedule is accepted
+    ///
+    /// Action queue is attached to the schedule, hash doesn't depend on it.
     pub fn set_schedule_by_leader(&self, cookie: LeaderCookie,
-        val: Schedule, input: SchedulerInput, debug: String,
+        mut val: Schedule, input: SchedulerInput, debug: String,
         mut actions: HashMap<u64, Json>)
+        -> Option<ScheduleId>
     {
         let mut guard = self.lock();
+        let mut result = None;
         if guard.election.is_leader && guard.election.epoch == cookie.epoch {
+            let now = now_ms();
             for id in cookie.actions.keys() {
-                if let Some(action) = guard.actions.remove(id) {
-                    action.channel.send(match actions.remove(id) {
-                        Some(value) => Ok(value),
+                let response = actions.remove(id);
+                if guard.actions.finish(*id, response.clone(), now).is_none()
+                {
+                    continue;
+                }
+                for channel in guard.action_waiters.remove(id)
+                    .unwrap_or_else(Vec::new)
+                {
+                    channel.send(match response {
+                        Some(ref value) => Ok(value.clone()),
                         None => Err(ActionError::NoResponse),
                     }).ok();
                 }
             }
+            guard.actions.prune(now);
             for (aid, action) in actions {
                 error!("unsolicited action response {}: {:?}", aid, action);
             }
+            guard.actions_version += 1;
+            val.actions = Some(guard.actions.to_json());
+            result = Some(val.hash.clone());
             let schedule = Arc::new(val);
             guard.last_known_schedule = last_known(schedule.clone());
             guard.owned_schedule = Some(schedule.clone());
@@ -297,7 +411,63 @@ impl SharedState {
             guard.last_scheduler_debug_info = Arc::new(Some((input, debug)));
             self.0.responder.new_schedule(schedule.clone());
         }
+        drop(guard);
+        self.save_actions();
+        self.trigger(Subscription::Status);
+        return result;
+    }
+    pub fn set_shadow_scheduler(&self, status: Option<ShadowStatus>) {
+        let mut guard = self.lock();
+        if status.is_none() {
+            guard.promote_shadow = false;
+        }
+        guard.shadow_scheduler = status.map(Arc::new);
+        self.trigger(Subscription::Status);
+    }
+    /// Returns false if there is no shadow scheduler to promote
+    pub fn request_shadow_promotion(&self) -> bool {
+        let mut guard = self.lock();
+        if guard.shadow_scheduler.is_some() {
+            guard.promote_shadow = true;
+            return true;
+        }
+        return false;
+    }
+    pub fn take_shadow_promotion(&self) -> bool {
+        mem::replace(&mut self.lock().promote_shadow, false)
+    }
+    /// Schedule which leader publishes instead of running scheduler
+    pub fn pinned_schedule(&self) -> Option<Arc<Schedule>> {
+        self.lock().pinned_schedule.clone()
+    }
+    /// Pins schedule from the backup, returns false if not a leader
+    pub fn pin_schedule(&self, backup: &str, mut schedule: Schedule)
+        -> bool
+    {
+        let mut guard = self.lock();
+        if !guard.election.is_leader {
+            return false;
+        }
+        schedule.pinned = Some(Pin {
+            backup: backup.to_string(),
+            hash: schedule.hash.clone(),
+            since: SystemTime::now(),
+        });
+        guard.pinned_schedule = Some(Arc::new(schedule));
+        drop(guard);
+        self.trigger(Subscription::Status);
+        return true;
+    }
+    /// Resumes scheduler, returns false if not a leader
+    pub fn unpin_schedule(&self) -> bool {
+        let mut guard = self.lock();
+        if !guard.election.is_leader {
+            return false;
+        }
+        guard.pinned_schedule = None;
+        drop(guard);
         self.trigger(Subscription::Status);
+        return true;
     }
     pub fn set_error(&self, domain: &'static str, value: String) {
         let mut lock = self.lock();
@@ -317,7 +487,9 @@ impl SharedState {
     pub fn update_election(&self, elect: ElectionState) {
         let mut guard = self.lock();
         if !elect.is_leader {
-            guard.actions.clear();
+            // pending actions are kept, they are replicated to the new
+            // leader, but nobody waits for them here any more
+            guard.action_waiters.clear();
             guard.owned_schedule = None;
             if guard.last_scheduler_debug_info.is_some() {
                 guard.last_scheduler_debug_info = Arc::new(None);
@@ -325,9 +497,19 @@ impl SharedState {
             let errors = Arc::make_mut(&mut guard.errors);
             errors.remove("reload_configs");
             errors.remove("scheduler_load");
+            errors.remove("scheduler_schema_load");
             errors.remove("scheduler");
         }
         let dest_elect = Arc::make_mut(&mut guard.election);
+        let changed = dest_elect.is_leader != elect.is_leader ||
+            dest_elect.leader != elect.leader;
+        if changed && (elect.is_stable || dest_elect.is_leader) {
+            self.webhook(Event::LeadershipChanged {
+                is_leader: elect.is_leader,
+                leader: elect.leader.as_ref().map(|x| x.to_string()),
+                epoch: elect.epoch,
+            });
+        }
         let tstamp = elect.last_stable_timestamp
             .or(dest_elect.last_stable_timestamp);
         *dest_elect = elect;
@@ -335,7 +517,16 @@ impl SharedState {
         self.trigger(Subscription::Status);
     }
     pub fn set_parents(&self, parents: Vec<Arc<Schedule>>) {
-        self.lock().parent_schedules = Some(parents);
+        let mut guard = self.lock();
+        for parent in &parents {
+            if let Some(queue) = Queue::from_schedule(&parent) {
+                guard.actions.merge(queue);
+                guard.actions_version += 1;
+            }
+        }
+        guard.parent_schedules = Some(parents);
+        drop(guard);
+        self.save_actions();
     }
     // Utility
     pub fn leader_cookie(&self) -> Option<LeaderCookie> {
@@ -345,20 +536,29 @@ impl SharedState {
         {
             return None;
         }
+        let parent_schedules = match guard.parent_schedules.take() {
+            Some(parents) => {
+                // new leader keeps the pin of the previous one
+                guard.pinned_schedule = parents.iter()
+                    .find(|s| s.pinned.is_some())
+                    .cloned();
+                parents
+            }
+            None => guard.stable_schedule.iter().cloned().collect(),
+        };
+        let paused = guard.pinned_schedule.is_some();
         return Some(LeaderCookie {
             epoch: guard.election.epoch,
-            parent_schedules: guard.parent_schedules.take()
-              .unwrap_or(guard.stable_schedule.iter().cloned().collect()),
-            actions: guard.actions
-                .iter().map(|(&k, a)| (k, a.data.clone())).collect(),
+            parent_schedules: parent_schedules,
+            actions: start_actions(&mut guard.actions, paused),
         })
     }
     pub fn refresh_cookie(&self, cookie: &mut LeaderCookie) -> bool {
-        let guard = self.lock();
+        let mut guard = self.lock();
         if cookie.epoch == guard.election.epoch {
             // TODO(tailhook) update only changed items
-            cookie.actions = guard.actions
-                .iter().map(|(&k, a)| (k, a.data.clone())).collect();
+            let paused = guard.pinned_schedule.is_some();
+            cookie.actions = start_actions(&mut guard.actions, paused);
             return true;
         } else {
             return false;
@@ -370,38 +570,109 @@ impl SharedState {
     pub fn force_render(&self) {
         self.0.responder.force_rerender();
     }
-    pub fn push_action(&self, data: Json,
-        respond: oneshot::Sender<Result<Json, ActionError>>)
+    /// Registers new action, returns its id
+    ///
+    /// If an action with the same `idempotency_key` is known, no new action
+    /// is registered: the id of the existing action is returned and the
+    /// result is sent to `respond` when (or if already) it's finished.
+    pub fn push_action(&self, data: Json, submitter: String,
+        idempotency_key: Option<String>, respond: ActionWaiter)
         -> Result<u64, PushActionError>
     {
-        let mut guard = self.lock();
+        let id = {
+            let mut guard = self.lock();
 
-        if !guard.election.is_leader {
-            return Err(PushActionError::NotALeader);
-        }
-        ACTIONS_EXECUTED.incr(1);
+            if !guard.election.is_leader {
+                return Err(PushActionError::NotALeader);
+            }
+
+            let existing = idempotency_key.as_ref()
+                .and_then(|key| guard.actions.find_by_key(key))
+                .map(|a| (a.id, a.status, a.result.clone()));
+            if let Some((id, status, result)) = existing {
+                debug!("Action {} is reused by idempotency key {:?}",
+                    id, idempotency_key);
+                match (status, result) {
+                    (ActionStatus::Pending, _) => {
+                        guard.action_waiters.entry(id)
+                            .or_insert_with(Vec::new).push(respond);
+                    }
+                    (ActionStatus::Cancelled, _) => {
+                        respond.send(Err(ActionError::Cancelled)).ok();
+                    }
+                    (_, Some(result)) => {
+                        respond.send(Ok(result)).ok();
+                    }
+                    (_, None) => {
+                        respond.send(Err(ActionError::NoResponse)).ok();
+                    }
+                }
+                return Ok(id);
+            }
+            ACTIONS_EXECUTED.incr(1);
 
-        let millis = (get_time().sec * 1000) as u64;
+            let now = now_ms();
+            let millis = now - now % 1000;
 
-        // Note we intentionally limit actions to 1000 per second
-        // Usually there is no more than *one*
-        // TODO(tailhook) we can look at max element rather than iterating
-        for i in 0..1000 {
-            match guard.actions.entry(millis + i) {
-                Occupied(_) => continue,
-                Vacant(x) => {
-                    x.insert(Action {
-                        data: Arc::new(data),
-                        channel: respond,
-                    });
-                    return Ok(millis + i);
+            // Note we intentionally limit actions to 1000 per second
+            // Usually there is no more than *one*
+            // TODO(tailhook) we can look at max element rather than iterating
+            let id = (millis..millis+1000)
+                .find(|&id| !guard.actions.contains(id))
+                .ok_or(PushActionError::TooManyRequests)?;
+            guard.actions.pending.insert(id, ActionRecord {
+                id,
+                submitter,
+                submitted: now,
+                started: None,
+                finished: None,
+                status: ActionStatus::Pending,
+                data: Arc::new(data),
+                result: None,
+                idempotency_key,
+            });
+            guard.action_waiters.insert(id, vec![respond]);
+            guard.actions_version += 1;
+            id
+        };
+        self.save_actions();
+        return Ok(id);
+    }
+    pub fn cancel_action(&self, id: u64)
+        -> Result<ActionRecord, CancelActionError>
+    {
+        let action = {
+            let mut guard = self.lock();
+            if !guard.election.is_leader {
+                return Err(CancelActionError::NotALeader);
+            }
+            let action = match guard.actions.cancel(id, now_ms()) {
+                Ok(action) => action.clone(),
+                Err(CancelError::NotFound) => {
+                    return Err(CancelActionError::NotFound);
+                }
+                Err(CancelError::AlreadyStarted) => {
+                    return Err(CancelActionError::AlreadyStarted);
+                }
+                Err(CancelError::AlreadyFinished(status)) => {
+                    return Err(CancelActionError::AlreadyFinished(status));
                 }
+            };
+            for channel in guard.action_waiters.remove(&id)
+                .unwrap_or_else(Vec::new)
+            {
+                channel.send(Err(ActionError::Cancelled)).ok();
             }
-        }
-        return Err(PushActionError::TooManyRequests);
+            guard.actions_version += 1;
+            action
+        };
+        info!("Action {} is cancelled", id);
+        self.save_actions();
+        self.trigger(Subscription::Status);
+        return Ok(action);
     }
     pub fn check_action(&self, action: u64) -> bool {
-        self.lock().actions.get(&action).is_some()
+        self.lock().actions.pending.contains_key(&action)
     }
     pub fn mark_role_failure(&self, role_name: &str) {
         let ref mut lock = self.lock();
@@ -409,6 +680,7 @@ impl SharedState {
         if !role_errors.contains(role_name) {
             role_errors.insert(role_name.to_string());
             FAILING_ROLES.set(role_errors.len() as i64);
+            self.webhook(Event::RoleFailed { role: role_name.to_string() });
         }
         FAILED_ROLES.incr(1);
         self.trigger(Subscription::Status);
@@ -416,8 +688,40 @@ impl SharedState {
     pub fn reset_role_failure(&self, role_name: &str) {
         let mut lock = self.lock();
         let role_errors = Arc::make_mut(&mut lock.failed_roles);
-        role_errors.remove(role_name);
+        if role_errors.remove(role_name) {
+            self.webhook(Event::RoleRecovered {
+                role: role_name.to_string(),
+            });
+        }
         FAILING_ROLES.set(role_errors.len() as i64);
+        Arc::make_mut(&mut lock.role_retries).remove(role_name);
+        Arc::make_mut(&mut lock.role_rollbacks).remove(role_name);
+        self.trigger(Subscription::Status);
+    }
+    /// Retry state of the failed roles
+    pub fn role_retries(&self) -> Arc<HashMap<String, RoleRetry>> {
+        self.lock().role_retries.clone()
+    }
+    pub fn set_role_retry(&self, role_name: &str, retry: RoleRetry) {
+        let mut lock = self.lock();
+        Arc::make_mut(&mut lock.role_retries)
+            .insert(role_name.to_string(), retry);
+        self.trigger(Subscription::Status);
+    }
+    /// Roles which were rolled back to the last good variables
+    pub fn role_rollbacks(&self) -> Arc<HashMap<String, Rollback>> {
+        self.lock().role_rollbacks.clone()
+    }
+    pub fn set_role_rollback(&self, role_name: &str, rollback: Rollback) {
+        let mut lock = self.lock();
+        Arc::make_mut(&mut lock.role_rollbacks)
+            .insert(role_name.to_string(), rollback);
+        self.trigger(Subscription::Status);
+    }
+    pub fn set_render_result(&self, role_name: &str, result: RenderResult) {
+        let mut lock = self.lock();
+        Arc::make_mut(&mut lock.render_results)
+            .insert(role_name.to_string(), Arc::new(result));
         self.trigger(Subscription::Status);
     }
     pub fn num_roles(&self) -> usize {
@@ -430,18 +734,45 @@ impl SharedState {
         let role_errors = Arc::make_mut(&mut lock.failed_roles);
         let old_errors = mem::replace(role_errors, Default::default());
         let mut n = 0;
+        let mut names = HashSet::new();
         for name in roles {
             n += 1;
             if old_errors.contains(name.as_ref()) {
                 role_errors.insert(name.as_ref().into());
             }
+            names.insert(name.as_ref().to_string());
         }
         self.num_roles.store(n, Ordering::SeqCst);
         FAILING_ROLES.set(role_errors.len() as i64);
+        Arc::make_mut(&mut lock.render_results)
+            .retain(|name, _| names.contains(name));
+        Arc::make_mut(&mut lock.role_retries)
+            .retain(|name, _| names.contains(name));
+        Arc::make_mut(&mut lock.role_rollbacks)
+            .retain(|name, _| names.contains(name));
         self.trigger(Subscription::Status);
     }
 }
 
+/// Marks pending actions as started and returns them for the scheduler
+///
+/// Nothing is started while scheduler is `paused` (the schedule is pinned),
+/// so actions can still be cancelled.
+fn start_actions(queue: &mut Queue, paused: bool)
+    -> BTreeMap<u64, Arc<Json>>
+{
+    if paused {
+        return BTreeMap::new();
+    }
+    let now = now_ms();
+    queue.pending.iter_mut().map(|(&k, a)| {
+        if a.started.is_none() {
+            a.started = Some(now);
+        }
+        (k, a.data.clone())
+    }).collect()
+}
+
 pub fn metrics() -> List {
     let global = "global";
     let peers = "peers";
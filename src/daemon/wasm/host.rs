@@ -0,0 +1,265 @@
+//! Host functions exposed to wasm modules in the `verwalter_v1` namespace
+//!
+//! Functions (pointers and lengths are `i32`):
+//!
+//! * `log(level, ptr, len)` -- put a message into the debug log, level is
+//!   one of 1 (error), 2 (warn), 3 (info), 4 (debug), 5 (trace)
+//! * `now_ms() -> i64` -- time in milliseconds when current call was started
+//! * `random_seed(seed: i64)` -- reseed random number generator
+//! * `random_u32() -> i32`, `random_f64() -> f64` -- deterministic random
+//!   numbers, by default generator is seeded by a hash of the input
+//! * `runtime_file_size(path_ptr, path_len) -> i32` -- size of the file in
+//!   the runtime directory, negative on error
+//! * `runtime_file_read(path_ptr, path_len, buf_ptr, buf_len) -> i32` --
+//!   read the file into the buffer, returns number of bytes read
+//!   or negative value on error
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::fmt::Write;
+use std::path::{Path, PathBuf, Component};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use sha1::Sha1;
+use wasmi::{self, ModuleImportResolver, RuntimeValue, RuntimeArgs};
+use wasmi::{FuncRef, ValueType, Signature, FuncInstance, MemoryRef};
+use wasmi::memory_units::Bytes;
+
+use time_util::ToMsec;
+
+
+pub const NAMESPACE: &str = "verwalter_v1";
+
+pub const LOG_INDEX: usize = 1000;
+pub const NOW_MS_INDEX: usize = 1001;
+pub const RANDOM_SEED_INDEX: usize = 1002;
+pub const RANDOM_U32_INDEX: usize = 1003;
+pub const RANDOM_F64_INDEX: usize = 1004;
+pub const RUNTIME_FILE_SIZE_INDEX: usize = 1005;
+pub const RUNTIME_FILE_READ_INDEX: usize = 1006;
+pub const END_INDEX: usize = 1100;
+
+/// Returned by runtime file functions when file does not exist
+const NOT_FOUND: i32 = -1;
+/// Returned by runtime file functions when path is invalid
+const INVALID_PATH: i32 = -2;
+
+pub struct Resolver;
+
+pub struct State {
+    runtime_dir: Option<PathBuf>,
+    log: String,
+    now: SystemTime,
+    rng: u64,
+    files: HashMap<String, Option<Arc<Vec<u8>>>>,
+}
+
+fn signature(name: &str) -> Option<(usize, Signature)> {
+    use wasmi::ValueType::*;
+    let (idx, params, result): (_, &[ValueType], _) = match name {
+        "log" => (LOG_INDEX, &[I32, I32, I32], None),
+        "now_ms" => (NOW_MS_INDEX, &[], Some(I64)),
+        "random_seed" => (RANDOM_SEED_INDEX, &[I64], None),
+        "random_u32" => (RANDOM_U32_INDEX, &[], Some(I32)),
+        "random_f64" => (RANDOM_F64_INDEX, &[], Some(F64)),
+        "runtime_file_size" => {
+            (RUNTIME_FILE_SIZE_INDEX, &[I32, I32], Some(I32))
+        }
+        "runtime_file_read" => {
+            (RUNTIME_FILE_READ_INDEX, &[I32, I32, I32, I32], Some(I32))
+        }
+        _ => return None,
+    };
+    Some((idx, Signature::new(params, result)))
+}
+
+impl ModuleImportResolver for Resolver {
+    fn resolve_func(&self, field_name: &str, signature: &Signature)
+        -> Result<FuncRef, wasmi::Error>
+    {
+        match self::signature(field_name) {
+            Some((idx, ref sig)) if sig == signature => {
+                Ok(FuncInstance::alloc_host(signature.clone(), idx))
+            }
+            Some(_) => Err(wasmi::Error::Instantiation(
+                format!("Export {}::{} expects invalid signature {:?}",
+                    NAMESPACE, field_name, signature)
+            )),
+            None => Err(wasmi::Error::Instantiation(
+                format!("Export {}::{} not found", NAMESPACE, field_name),
+            )),
+        }
+    }
+}
+
+fn read_string(memory: &MemoryRef, ptr: u32, len: u32)
+    -> Result<String, wasmi::Trap>
+{
+    // check before allocating, length is controlled by the module
+    let memsize = Bytes::from(memory.current_size()).0 as u64;
+    if ptr as u64 + len as u64 > memsize {
+        return Err(wasmi::Trap::new(
+            wasmi::TrapKind::MemoryAccessOutOfBounds));
+    }
+    let mut buf = vec![0u8; len as usize];
+    memory.get_into(ptr, &mut buf)
+        .map_err(|_| wasmi::Trap::new(
+            wasmi::TrapKind::MemoryAccessOutOfBounds))?;
+    Ok(String::from_utf8_lossy(&buf).to_string())
+}
+
+fn valid_path(path: &str) -> bool {
+    !path.is_empty() && Path::new(path).components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+impl State {
+    pub fn new(runtime_dir: Option<PathBuf>) -> State {
+        State {
+            runtime_dir,
+            log: String::new(),
+            now: SystemTime::now(),
+            rng: 1,
+            files: HashMap::new(),
+        }
+    }
+    /// Called before each call into the module
+    ///
+    /// Random generator is seeded by the input so that same input produces
+    /// same output. Runtime files are reread on each call.
+    pub fn start_call(&mut self, input: &[u8]) {
+        let mut sha = Sha1::new();
+        sha.update(input);
+        let digest = sha.digest().bytes();
+        let mut seed = 0u64;
+        for &b in &digest[..8] {
+            seed = (seed << 8) | b as u64;
+        }
+        self.seed(seed);
+        self.now = SystemTime::now();
+        self.files.clear();
+    }
+    pub fn take_log(&mut self) -> String {
+        ::std::mem::replace(&mut self.log, String::new())
+    }
+    fn seed(&mut self, seed: u64) {
+        // xorshift doesn't work with zero state
+        self.rng = if seed == 0 { 0x9E3779B97F4A7C15 } else { seed };
+    }
+    /// xorshift64* generator, good enough for schedulers
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.rng;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.rng = x;
+        x.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+    fn runtime_file(&mut self, path: &str) -> Result<Arc<Vec<u8>>, i32> {
+        if !valid_path(path) {
+            return Err(INVALID_PATH);
+        }
+        let dir = match self.runtime_dir {
+            Some(ref dir) => dir,
+            None => return Err(NOT_FOUND),
+        };
+        let data = self.files.entry(path.to_string()).or_insert_with(|| {
+            let mut buf = Vec::new();
+            match File::open(dir.join(path))
+                .and_then(|mut f| f.read_to_end(&mut buf))
+            {
+                Ok(_) => Some(Arc::new(buf)),
+                Err(e) => {
+                    debug!("Can't read runtime file {:?}: {}", path, e);
+                    None
+                }
+            }
+        });
+        data.clone().ok_or(NOT_FOUND)
+    }
+    pub fn invoke(&mut self, memory: &MemoryRef,
+        index: usize, args: RuntimeArgs)
+        -> Result<Option<RuntimeValue>, wasmi::Trap>
+    {
+        use wasmi::RuntimeValue::*;
+
+        let res = match index {
+            LOG_INDEX => {
+                let level: u32 = args.nth_checked(0)?;
+                let ptr: u32 = args.nth_checked(1)?;
+                let len: u32 = args.nth_checked(2)?;
+                let msg = read_string(memory, ptr, len)?;
+                let name = match level {
+                    1 => "ERROR",
+                    2 => "WARN",
+                    3 => "INFO",
+                    4 => "DEBUG",
+                    _ => "TRACE",
+                };
+                debug!("Wasm log {}: {}", name, msg);
+                writeln!(&mut self.log, "{}: {}", name, msg).ok();
+                return Ok(None);
+            }
+            NOW_MS_INDEX => I64(self.now.to_msec() as i64),
+            RANDOM_SEED_INDEX => {
+                let seed: u64 = args.nth_checked(0)?;
+                self.seed(seed);
+                return Ok(None);
+            }
+            RANDOM_U32_INDEX => I32((self.next_u64() >> 32) as i32),
+            RANDOM_F64_INDEX => {
+                let val = (self.next_u64() >> 11) as f64;
+                F64((val / (1u64 << 53) as f64).into())
+            }
+            RUNTIME_FILE_SIZE_INDEX => {
+                let ptr: u32 = args.nth_checked(0)?;
+                let len: u32 = args.nth_checked(1)?;
+                let path = read_string(memory, ptr, len)?;
+                match self.runtime_file(&path) {
+                    Ok(data) => I32(data.len() as i32),
+                    Err(code) => I32(code),
+                }
+            }
+            RUNTIME_FILE_READ_INDEX => {
+                let ptr: u32 = args.nth_checked(0)?;
+                let len: u32 = args.nth_checked(1)?;
+                let buf_ptr: u32 = args.nth_checked(2)?;
+                let buf_len: u32 = args.nth_checked(3)?;
+                let path = read_string(memory, ptr, len)?;
+                match self.runtime_file(&path) {
+                    Ok(data) => {
+                        let n = ::std::cmp::min(data.len(), buf_len as usize);
+                        memory.set(buf_ptr, &data[..n])
+                            .map_err(|_| wasmi::Trap::new(
+                                wasmi::TrapKind::MemoryAccessOutOfBounds))?;
+                        I32(n as i32)
+                    }
+                    Err(code) => I32(code),
+                }
+            }
+            _ => panic!("Unimplemented function at {}", index),
+        };
+        return Ok(Some(res));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmi::MemoryInstance;
+    use wasmi::memory_units::Pages;
+    use super::read_string;
+
+    #[test]
+    fn test_read_string() {
+        let memory = MemoryInstance::alloc(Pages(1), None).unwrap();
+        memory.set(65530, b"hello").unwrap();
+        assert_eq!(read_string(&memory, 65530, 5).unwrap(), "hello");
+        assert_eq!(read_string(&memory, 65536, 0).unwrap(), "");
+        assert!(read_string(&memory, 65530, 7).is_err());
+        assert!(read_string(&memory, 0, u32::max_value()).is_err());
+        assert!(read_string(&memory, u32::max_value(), 2).is_err());
+    }
+}
@@ -0,0 +1,614 @@
+//! Implementation of libm functions imported by wasm32 modules
+//!
+//! Rust and C code compiled to wasm32 imports math functions from the `env`
+//! module. Each function here is exported both as a double precision
+//! variant (`sin`) and as a single precision one (`sinf`), the type of the
+//! result matches the type of the (first) argument, except `ilogb`,
+//! `lround` and `lrint` which return `i32` (`long` on wasm32), and
+//! `llround` and `llrint` which return `i64`.
+use std::f64;
+
+use wasmi::{self, RuntimeValue, RuntimeArgs, Signature, MemoryRef};
+
+
+pub const POW_INDEX: usize = 100;
+pub const LDEXP_INDEX: usize = 101;
+pub const FREXP_INDEX: usize = 102;
+pub const MODF_INDEX: usize = 103;
+pub const FMA_INDEX: usize = 104;
+pub const ILOGB_INDEX: usize = 105;
+pub const LROUND_INDEX: usize = 106;
+pub const LRINT_INDEX: usize = 107;
+pub const LLROUND_INDEX: usize = 108;
+pub const LLRINT_INDEX: usize = 109;
+pub const UNARY_BASE: usize = 200;
+pub const BINARY_BASE: usize = 300;
+pub const END_INDEX: usize = 400;
+
+
+static UNARY: &[(&str, fn(f64) -> f64)] = &[
+    ("acos", f64::acos),
+    ("acosh", f64::acosh),
+    ("asin", f64::asin),
+    ("asinh", f64::asinh),
+    ("atan", f64::atan),
+    ("atanh", f64::atanh),
+    ("cbrt", f64::cbrt),
+    ("ceil", f64::ceil),
+    ("cos", f64::cos),
+    ("cosh", f64::cosh),
+    ("erf", erf),
+    ("erfc", erfc),
+    ("exp", f64::exp),
+    ("exp2", f64::exp2),
+    ("exp10", exp10),
+    ("expm1", f64::exp_m1),
+    ("fabs", f64::abs),
+    ("floor", f64::floor),
+    ("lgamma", lgamma),
+    ("log", f64::ln),
+    ("log10", f64::log10),
+    ("log1p", f64::ln_1p),
+    ("log2", f64::log2),
+    ("logb", logb),
+    ("nearbyint", rint),
+    ("rint", rint),
+    ("round", f64::round),
+    ("sin", f64::sin),
+    ("sinh", f64::sinh),
+    ("sqrt", f64::sqrt),
+    ("tan", f64::tan),
+    ("tanh", f64::tanh),
+    ("tgamma", tgamma),
+    ("trunc", f64::trunc),
+];
+
+static BINARY: &[(&str, fn(f64, f64) -> f64)] = &[
+    ("atan2", f64::atan2),
+    ("copysign", copysign),
+    ("fdim", fdim),
+    ("fmax", f64::max),
+    ("fmin", f64::min),
+    ("fmod", fmod),
+    ("hypot", f64::hypot),
+    ("nextafter", nextafter),
+    ("remainder", remainder),
+];
+
+
+fn exp10(x: f64) -> f64 {
+    (10.0f64).powf(x)
+}
+
+/// Rounds half to even, as default rounding mode of `rint` does
+fn rint(x: f64) -> f64 {
+    if (x - x.trunc()).abs() == 0.5 {
+        2.0 * (x / 2.0).round()
+    } else {
+        x.round()
+    }
+}
+
+fn copysign(x: f64, y: f64) -> f64 {
+    if x.is_sign_negative() == y.is_sign_negative() { x } else { -x }
+}
+
+fn fdim(x: f64, y: f64) -> f64 {
+    if x > y { x - y } else { 0.0 }
+}
+
+fn fmod(x: f64, y: f64) -> f64 {
+    x % y
+}
+
+fn remainder(x: f64, y: f64) -> f64 {
+    x - rint(x / y) * y
+}
+
+/// Multiplies by power of two without overflow in the intermediate
+/// result (same algorithm as in musl)
+fn scalbn(mut x: f64, mut n: i32) -> f64 {
+    let x1p1023 = f64::from_bits(0x7fe0000000000000);
+    let x1p53 = f64::from_bits(0x4340000000000000);
+    let x1p_1022 = f64::from_bits(0x0010000000000000);
+    if n > 1023 {
+        x *= x1p1023;
+        n -= 1023;
+        if n > 1023 {
+            x *= x1p1023;
+            n -= 1023;
+            if n > 1023 {
+                n = 1023;
+            }
+        }
+    } else if n < -1022 {
+        // keep precision by not going into subnormals twice
+        x *= x1p_1022 * x1p53;
+        n += 1022 - 53;
+        if n < -1022 {
+            x *= x1p_1022 * x1p53;
+            n += 1022 - 53;
+            if n < -1022 {
+                n = -1022;
+            }
+        }
+    }
+    x * f64::from_bits(((0x3ff + n) as u64) << 52)
+}
+
+/// Converts float exponent argument of `ldexp` to an integer
+fn exponent(x: f64) -> i32 {
+    if x.is_nan() {
+        0
+    } else {
+        x.max(-100_000.0).min(100_000.0) as i32
+    }
+}
+
+/// Converts rounded value to an integer, out of range values saturate
+fn saturate_i32(x: f64) -> i32 {
+    if x.is_nan() {
+        0
+    } else {
+        x.max(i32::min_value() as f64).min(i32::max_value() as f64) as i32
+    }
+}
+
+fn saturate_i64(x: f64) -> i64 {
+    if x.is_nan() {
+        0
+    } else if x >= 9223372036854775807.0 {
+        i64::max_value()
+    } else if x <= -9223372036854775808.0 {
+        i64::min_value()
+    } else {
+        x as i64
+    }
+}
+
+fn nextafter(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        return x + y;
+    }
+    if x == y {
+        return y;
+    }
+    if x == 0.0 {
+        return copysign(f64::from_bits(1), y);
+    }
+    let bits = x.to_bits();
+    let bits = if (y > x) == (x > 0.0) { bits + 1 } else { bits - 1 };
+    f64::from_bits(bits)
+}
+
+fn logb(x: f64) -> f64 {
+    if x == 0.0 {
+        f64::NEG_INFINITY
+    } else if x.is_infinite() {
+        f64::INFINITY
+    } else if x.is_nan() {
+        x
+    } else {
+        (frexp(x).1 - 1) as f64
+    }
+}
+
+fn ilogb(x: f64) -> i32 {
+    if x == 0.0 || x.is_nan() {
+        // FP_ILOGB0 and FP_ILOGBNAN in musl
+        i32::min_value()
+    } else if x.is_infinite() {
+        i32::max_value()
+    } else {
+        frexp(x).1 - 1
+    }
+}
+
+/// Continued fraction for `erfc`, converges well for `x >= 2`
+fn erfc_fraction(x: f64) -> f64 {
+    // modified Lentz's method for x + 1/2/(x + 1/(x + 3/2/(x + ...)))
+    let tiny = 1e-300;
+    let mut f = x;
+    let mut c = x;
+    let mut d = 0.0;
+    for n in 1..1000 {
+        let a = n as f64 / 2.0;
+        d = x + a * d;
+        if d == 0.0 {
+            d = tiny;
+        }
+        d = 1.0 / d;
+        c = x + a / c;
+        if c == 0.0 {
+            c = tiny;
+        }
+        let delta = c * d;
+        f *= delta;
+        if (delta - 1.0).abs() < 1e-16 {
+            break;
+        }
+    }
+    (-x * x).exp() / (f64::consts::PI.sqrt() * f)
+}
+
+/// Taylor series for `erf`, used for `|x| < 2`
+fn erf_series(x: f64) -> f64 {
+    let x2 = x * x;
+    let mut term = x;
+    let mut sum = x;
+    for n in 1..200 {
+        term *= -x2 / n as f64;
+        let item = term / (2 * n + 1) as f64;
+        sum += item;
+        if item.abs() < 1e-17 * sum.abs() {
+            break;
+        }
+    }
+    sum * 2.0 / f64::consts::PI.sqrt()
+}
+
+fn erf(x: f64) -> f64 {
+    if x.is_nan() {
+        x
+    } else if x.abs() < 2.0 {
+        erf_series(x)
+    } else {
+        copysign(1.0 - erfc_fraction(x.abs()), x)
+    }
+}
+
+fn erfc(x: f64) -> f64 {
+    if x.is_nan() {
+        x
+    } else if x >= 2.0 {
+        erfc_fraction(x)
+    } else if x <= -2.0 {
+        2.0 - erfc_fraction(-x)
+    } else {
+        1.0 - erf_series(x)
+    }
+}
+
+const LANCZOS_G: f64 = 7.0;
+const LANCZOS: [f64; 9] = [
+    0.99999999999980993,
+    676.5203681218851,
+    -1259.1392167224028,
+    771.32342877765313,
+    -176.61502916214059,
+    12.507343278686905,
+    -0.13857109526572012,
+    9.9843695780195716e-6,
+    1.5056327351493116e-7,
+];
+
+/// Returns `t` and the sum of Lanczos series for `x >= 0.5`
+fn lanczos(x: f64) -> (f64, f64) {
+    let x = x - 1.0;
+    let mut sum = LANCZOS[0];
+    for (i, c) in LANCZOS.iter().enumerate().skip(1) {
+        sum += c / (x + i as f64);
+    }
+    (x + LANCZOS_G + 0.5, sum)
+}
+
+fn is_nonpositive_int(x: f64) -> bool {
+    x <= 0.0 && x == x.floor()
+}
+
+fn tgamma(x: f64) -> f64 {
+    use std::f64::consts::PI;
+    if x.is_nan() || x == f64::INFINITY {
+        return x;
+    }
+    if x == 0.0 {
+        return copysign(f64::INFINITY, x);
+    }
+    if is_nonpositive_int(x) {
+        return f64::NAN;
+    }
+    if x < 0.5 {
+        return PI / ((PI * x).sin() * tgamma(1.0 - x));
+    }
+    if x > 171.7 {
+        return f64::INFINITY;
+    }
+    let (t, sum) = lanczos(x);
+    // split the power so that it doesn't overflow before multiplication
+    let half = t.powf((x - 0.5) / 2.0);
+    (2.0 * PI).sqrt() * half * ((-t).exp() * half) * sum
+}
+
+fn lgamma(x: f64) -> f64 {
+    use std::f64::consts::PI;
+    if x.is_nan() {
+        return x;
+    }
+    if x.is_infinite() || is_nonpositive_int(x) {
+        return f64::INFINITY;
+    }
+    if x < 0.5 {
+        return (PI / (PI * x).sin().abs()).ln() - lgamma(1.0 - x);
+    }
+    let (t, sum) = lanczos(x);
+    0.5 * (2.0 * PI).ln() + (x - 0.5) * t.ln() - t + sum.ln()
+}
+
+fn frexp(x: f64) -> (f64, i32) {
+    if x == 0.0 || !x.is_finite() {
+        return (x, 0);
+    }
+    let bits = x.to_bits();
+    let exp = ((bits >> 52) & 0x7ff) as i32;
+    if exp == 0 {
+        // subnormal, normalize first
+        let (mantissa, exp) = frexp(x * (2.0f64).powi(54));
+        return (mantissa, exp - 54);
+    }
+    let mantissa = f64::from_bits((bits & !(0x7ff << 52)) | (1022 << 52));
+    (mantissa, exp - 1022)
+}
+
+fn modf(x: f64) -> (f64, f64) {
+    if x.is_infinite() {
+        (copysign(0.0, x), x)
+    } else {
+        (x.fract(), x.trunc())
+    }
+}
+
+fn lookup(name: &str) -> Option<(usize, usize)> {
+    match name {
+        "pow" => return Some((POW_INDEX, 2)),
+        "ldexp" | "scalbn" => return Some((LDEXP_INDEX, 2)),
+        "frexp" => return Some((FREXP_INDEX, 2)),
+        "modf" => return Some((MODF_INDEX, 2)),
+        "fma" => return Some((FMA_INDEX, 3)),
+        "ilogb" => return Some((ILOGB_INDEX, 1)),
+        "lround" => return Some((LROUND_INDEX, 1)),
+        "lrint" => return Some((LRINT_INDEX, 1)),
+        "llround" => return Some((LLROUND_INDEX, 1)),
+        "llrint" => return Some((LLRINT_INDEX, 1)),
+        _ => {}
+    }
+    if let Some(idx) = UNARY.iter().position(|&(n, _)| n == name) {
+        return Some((UNARY_BASE + idx, 1));
+    }
+    if let Some(idx) = BINARY.iter().position(|&(n, _)| n == name) {
+        return Some((BINARY_BASE + idx, 2));
+    }
+    return None;
+}
+
+/// Returns function index if `field_name` is a known libm function
+pub fn resolve(field_name: &str, signature: &Signature)
+    -> Option<Result<usize, wasmi::Error>>
+{
+    let found = lookup(field_name).or_else(|| {
+        if field_name.ends_with('f') {
+            lookup(&field_name[..field_name.len()-1])
+        } else {
+            None
+        }
+    });
+    found.map(|(index, arity)| {
+        if signature.params().len() == arity {
+            Ok(index)
+        } else {
+            Err(wasmi::Error::Instantiation(
+                format!("Export {} expects invalid signature {:?}",
+                    field_name, signature)
+            ))
+        }
+    })
+}
+
+fn bad_sig<T>() -> Result<T, wasmi::Trap> {
+    Err(wasmi::Trap::new(wasmi::TrapKind::UnexpectedSignature))
+}
+
+fn unary(a: RuntimeValue, f: fn(f64) -> f64) -> RuntimeValue {
+    use wasmi::RuntimeValue::*;
+    match a {
+        I32(a) => F64(f(a as f64).into()),
+        I64(a) => F64(f(a as f64).into()),
+        F32(a) => F32((f(a.to_float() as f64) as f32).into()),
+        F64(a) => F64(f(a.to_float()).into()),
+    }
+}
+
+pub fn invoke(memory: &MemoryRef, index: usize, args: RuntimeArgs)
+    -> Result<Option<RuntimeValue>, wasmi::Trap>
+{
+    use wasmi::RuntimeValue::*;
+
+    let a1 = args.nth_value_checked(0);
+    let a2 = args.nth_value_checked(1);
+    let res = match index {
+        POW_INDEX => match (a1?, a2?) {
+            (I32(a), I32(b)) => I32(i32::pow(a, b as u32)),
+            (I64(a), I32(b)) => I64(i64::pow(a, b as u32)),
+            (F32(a), I32(b)) => F32(f32::powi(a.to_float(), b).into()),
+            (F64(a), I32(b)) => F64(f64::powi(a.to_float(), b).into()),
+            (F32(a), F32(b)) => {
+                F32(f32::powf(a.to_float(), b.to_float()).into())
+            }
+            (F64(a), F64(b)) => {
+                F64(f64::powf(a.to_float(), b.to_float()).into())
+            }
+            (a, b) => {
+                error!("Invalid args for pow: {:?} / {:?}", a, b);
+                return bad_sig();
+            }
+        },
+        LDEXP_INDEX => match (a1?, a2?) {
+            (F32(a), I32(b)) => {
+                F32((scalbn(a.to_float() as f64, b) as f32).into())
+            }
+            (F64(a), I32(b)) => F64(scalbn(a.to_float(), b).into()),
+            (F32(a), F32(b)) => {
+                let b = exponent(b.to_float() as f64);
+                F32((scalbn(a.to_float() as f64, b) as f32).into())
+            }
+            (F64(a), F64(b)) => {
+                F64(scalbn(a.to_float(), exponent(b.to_float())).into())
+            }
+            (a, b) => {
+                error!("Invalid args for ldexp: {:?} / {:?}", a, b);
+                return bad_sig();
+            }
+        },
+        FREXP_INDEX => {
+            let ptr: u32 = args.nth_checked(1)?;
+            let (res, exp) = match a1? {
+                F32(a) => {
+                    let (m, e) = frexp(a.to_float() as f64);
+                    (F32((m as f32).into()), e)
+                }
+                F64(a) => {
+                    let (m, e) = frexp(a.to_float());
+                    (F64(m.into()), e)
+                }
+                a => {
+                    error!("Invalid args for frexp: {:?}", a);
+                    return bad_sig();
+                }
+            };
+            memory.set_value(ptr, exp)
+                .map_err(|_| wasmi::Trap::new(
+                    wasmi::TrapKind::MemoryAccessOutOfBounds))?;
+            res
+        }
+        MODF_INDEX => {
+            let ptr: u32 = args.nth_checked(1)?;
+            let (res, written) = match a1? {
+                F32(a) => {
+                    let (fract, int) = modf(a.to_float() as f64);
+                    (F32((fract as f32).into()),
+                     memory.set_value(ptr, int as f32))
+                }
+                F64(a) => {
+                    let (fract, int) = modf(a.to_float());
+                    (F64(fract.into()), memory.set_value(ptr, int))
+                }
+                a => {
+                    error!("Invalid args for modf: {:?}", a);
+                    return bad_sig();
+                }
+            };
+            written.map_err(|_| wasmi::Trap::new(
+                wasmi::TrapKind::MemoryAccessOutOfBounds))?;
+            res
+        }
+        FMA_INDEX => match (a1?, a2?, args.nth_value_checked(2)?) {
+            (F32(a), F32(b), F32(c)) => {
+                F32(a.to_float().mul_add(b.to_float(), c.to_float()).into())
+            }
+            (F64(a), F64(b), F64(c)) => {
+                F64(a.to_float().mul_add(b.to_float(), c.to_float()).into())
+            }
+            (a, b, c) => {
+                error!("Invalid args for fma: {:?} / {:?} / {:?}", a, b, c);
+                return bad_sig();
+            }
+        },
+        ILOGB_INDEX | LROUND_INDEX | LRINT_INDEX |
+        LLROUND_INDEX | LLRINT_INDEX => {
+            let a = match a1? {
+                F32(a) => a.to_float() as f64,
+                F64(a) => a.to_float(),
+                a => {
+                    error!("Invalid args for {}: {:?}", index, a);
+                    return bad_sig();
+                }
+            };
+            match index {
+                ILOGB_INDEX => I32(ilogb(a)),
+                LROUND_INDEX => I32(saturate_i32(a.round())),
+                LRINT_INDEX => I32(saturate_i32(rint(a))),
+                LLROUND_INDEX => I64(saturate_i64(a.round())),
+                _ => I64(saturate_i64(rint(a))),
+            }
+        }
+        idx if idx >= UNARY_BASE && idx < UNARY_BASE + UNARY.len() => {
+            unary(a1?, UNARY[idx - UNARY_BASE].1)
+        }
+        idx if idx >= BINARY_BASE && idx < BINARY_BASE + BINARY.len() => {
+            let (name, f) = BINARY[idx - BINARY_BASE];
+            match (a1?, a2?) {
+                (F32(a), F32(b)) => {
+                    F32((f(a.to_float() as f64, b.to_float() as f64)
+                        as f32).into())
+                }
+                (F64(a), F64(b)) => F64(f(a.to_float(), b.to_float()).into()),
+                (a, b) => {
+                    error!("Invalid args for {}: {:?} / {:?}", name, a, b);
+                    return bad_sig();
+                }
+            }
+        }
+        _ => panic!("Unimplemented function at {}", index),
+    };
+    return Ok(Some(res));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frexp, rint, scalbn, nextafter, ilogb, logb};
+    use super::{erf, erfc, tgamma, lgamma};
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-13 * b.abs()
+    }
+
+    #[test]
+    fn test_frexp() {
+        assert_eq!(frexp(8.0), (0.5, 4));
+        assert_eq!(frexp(-3.0), (-0.75, 2));
+        assert_eq!(frexp(0.0), (0.0, 0));
+        assert_eq!(frexp(5e-324), (0.5, -1073));
+    }
+
+    #[test]
+    fn test_rint() {
+        assert_eq!(rint(2.5), 2.0);
+        assert_eq!(rint(3.5), 4.0);
+        assert_eq!(rint(-2.5), -2.0);
+        assert_eq!(rint(2.4), 2.0);
+    }
+
+    #[test]
+    fn test_scalbn() {
+        assert_eq!(scalbn(1.0, 10), 1024.0);
+        assert_eq!(scalbn(1e-300, 1100), 1e-300 * 2.0f64.powi(550)
+                                                * 2.0f64.powi(550));
+        assert_eq!(scalbn(1e300, -1100), 1e300 / 2.0f64.powi(550)
+                                               / 2.0f64.powi(550));
+        assert_eq!(scalbn(1.0, 2000), ::std::f64::INFINITY);
+        assert_eq!(scalbn(1.0, -2000), 0.0);
+    }
+
+    #[test]
+    fn test_bits() {
+        assert_eq!(nextafter(1.0, 2.0), 1.0 + ::std::f64::EPSILON);
+        assert_eq!(nextafter(1.0, 0.0), 1.0 - ::std::f64::EPSILON / 2.0);
+        assert_eq!(nextafter(0.0, -1.0), -5e-324);
+        assert_eq!(ilogb(8.0), 3);
+        assert_eq!(ilogb(0.0), i32::min_value());
+        assert_eq!(logb(0.25), -2.0);
+    }
+
+    #[test]
+    fn test_special() {
+        assert!(close(erf(0.5), 0.5204998778130465));
+        assert!(close(erf(-2.5), -0.9995930479825550));
+        assert!(close(erfc(3.0), 2.209049699858544e-05));
+        assert!(close(erfc(-1.0), 1.842700792949715));
+        assert!(close(tgamma(5.0), 24.0));
+        assert!(close(tgamma(0.5), ::std::f64::consts::PI.sqrt()));
+        assert!(close(tgamma(-1.5), 2.363271801207355));
+        assert!(close(lgamma(100.0), 359.1342053695754));
+        assert_eq!(lgamma(-2.0), ::std::f64::INFINITY);
+        assert!(tgamma(171.0).is_finite());
+    }
+}
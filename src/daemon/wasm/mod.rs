@@ -1,6 +1,6 @@
 use std::io::{self, Read, BufReader};
 use std::fs::File;
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 
 use failure::{Error, err_msg};
 use serde_json::{to_vec, de};
@@ -12,13 +12,11 @@ use wasmi::{Externals, RuntimeValue, RuntimeArgs, ModuleImportResolver};
 use wasmi::{FuncRef, ValueType, Signature, FuncInstance};
 use wasmi::memory_units::Bytes;
 
+mod host;
+mod libm;
+
 
 const PANIC_INDEX: usize = 0;
-const POW_INDEX: usize = 100;
-const FMOD_INDEX: usize = 101;
-const EXP2_INDEX: usize = 102;
-const LDEXP_INDEX: usize = 104;
-const LOG10_INDEX: usize = 106;
 
 
 pub struct Program {
@@ -27,6 +25,7 @@ pub struct Program {
     memory: MemoryRef,
     util: Util,
     failed: bool,
+    runtime_dir: Option<PathBuf>,
 }
 
 struct ReadMemory {
@@ -38,6 +37,7 @@ struct Resolver;
 
 struct Util {
     memory: MemoryRef,
+    host: host::State,
 }
 
 
@@ -51,23 +51,40 @@ impl Program {
             .map_err(|e| err_msg(format!("Error reading {:?}: {}", path, e)))?;
         let loaded_module = Module::from_buffer(&buf)
             .map_err(|e| err_msg(format!("error decoding wasm: {:?}", e)))?;
-        let (module, memory, util) = instantiate(&loaded_module)
+        let (module, memory, util) = instantiate(&loaded_module, None)
             .map_err(|e| format_err!("error instantiating wasm: {}", e))?;
-        Ok(Program { loaded_module, module, memory, util, failed: false })
+        Ok(Program {
+            loaded_module, module, memory, util,
+            failed: false,
+            runtime_dir: None,
+        })
+    }
+    /// Sets directory available through `runtime_file_*` host functions
+    pub fn set_runtime_dir(&mut self, dir: &Path) {
+        self.runtime_dir = Some(dir.to_path_buf());
+        self.util.host = host::State::new(self.runtime_dir.clone());
+    }
+    /// Returns messages logged by the module since previous call
+    pub fn take_log(&mut self) -> String {
+        self.util.host.take_log()
     }
 }
 
-fn instantiate(module: &Module)
+fn instantiate(module: &Module, runtime_dir: Option<PathBuf>)
     -> Result<(ModuleRef, MemoryRef, Util), Error>
 {
     let module = ModuleInstance::new(module,
         &ImportsBuilder::new()
-            .with_resolver("env", &Resolver),
+            .with_resolver("env", &Resolver)
+            .with_resolver(host::NAMESPACE, &host::Resolver),
         ).map_err(|e| err_msg(format!("error adding wasm module: {}", e)))?;
     let memory = module.not_started_instance().export_by_name("memory")
         .and_then(|x| x.as_memory().map(Clone::clone))
         .ok_or_else(|| err_msg("no memory exported"))?;
-    let mut util = Util { memory: memory.clone() };
+    let mut util = Util {
+        memory: memory.clone(),
+        host: host::State::new(runtime_dir),
+    };
     let module = module.run_start(&mut util)
         .map_err(|e| err_msg(format!("error starting wasm module: {}", e)))?;
     Ok((module, memory, util))
@@ -81,7 +98,8 @@ impl Program {
               D: Deserialize<'x>,
     {
         if self.failed {
-            match instantiate(&self.loaded_module) {
+            let dir = self.runtime_dir.clone();
+            match instantiate(&self.loaded_module, dir) {
                 Ok((md, mm, u)) => {
                     self.module = md;
                     self.memory = mm;
@@ -104,6 +122,7 @@ impl Program {
             Ok(bytes) => bytes,
             Err(e) => return Err(e.into()),
         };
+        self.util.host.start_call(&bytes);
         let off = match
             self.module.invoke_export("alloc",
                 &[I32(bytes.len() as i32)],
@@ -206,34 +225,12 @@ impl ModuleImportResolver for Resolver {
                 format!("Export {} expects invalid signature {:?}",
                     field_name, signature)
             )),
-            "pow" if signature.params().len() == 2 => POW_INDEX,
-            "pow" => return Err(wasmi::Error::Instantiation(
-                format!("Export {} expects invalid signature {:?}",
-                    field_name, signature)
-            )),
-            "fmod" if signature.params().len() == 2 => FMOD_INDEX,
-            "fmod" => return Err(wasmi::Error::Instantiation(
-                format!("Export {} expects invalid signature {:?}",
-                    field_name, signature)
-            )),
-            "exp2"|"exp2f" if signature.params().len() == 1 => EXP2_INDEX,
-            "exp2"|"exp2f" => return Err(wasmi::Error::Instantiation(
-                format!("Export {} expects invalid signature {:?}",
-                    field_name, signature)
-            )),
-            "ldexp"|"ldexpf" if signature.params().len() == 2 => LDEXP_INDEX,
-            "ldexp"|"ldexpf" => return Err(wasmi::Error::Instantiation(
-                format!("Export {} expects invalid signature {:?}",
-                    field_name, signature)
-            )),
-            "log10"|"log10f" if signature.params().len() == 1 => LOG10_INDEX,
-            "log10"|"log10f" => return Err(wasmi::Error::Instantiation(
-                format!("Export {} expects invalid signature {:?}",
-                    field_name, signature)
-            )),
-            _ => return Err(wasmi::Error::Instantiation(
-                format!("Export {} not found", field_name),
-            ))
+            _ => match libm::resolve(field_name, signature) {
+                Some(res) => res?,
+                None => return Err(wasmi::Error::Instantiation(
+                    format!("Export {} not found", field_name),
+                )),
+            },
         };
         return Ok(FuncInstance::alloc_host(signature.clone(), idx));
     }
@@ -243,15 +240,7 @@ impl Externals for Util {
     fn invoke_index(&mut self, index: usize, args: RuntimeArgs)
         -> Result<Option<RuntimeValue>, wasmi::Trap>
     {
-        use wasmi::RuntimeValue::*;
-
-        fn bad_sig<T>() -> Result<T, wasmi::Trap> {
-            Err(wasmi::Trap::new(wasmi::TrapKind::UnexpectedSignature))
-        }
-
-        let a1 = args.nth_value_checked(0);
-        let a2 = args.nth_value_checked(1);
-        let res = match index {
+        match index {
             PANIC_INDEX => {
                 // panic(payload_str, payload_len, file_ptr, file_len, line);
                 let payload_ptr: Result<u32, _> = args.nth_checked(0);
@@ -293,48 +282,13 @@ impl Externals for Util {
                     filename, line.unwrap_or(0), payload);
                 return Ok(None)
             }
-            POW_INDEX => match (a1?, a2?) {
-                (I32(a), I32(b)) => I32(i32::pow(a, b as u32)),
-                (I64(a), I32(b)) => I64(i64::pow(a, b as u32)),
-                (F32(a), I32(b)) => F32(f32::powi(a.to_float(), b).into()),
-                (F64(a), I32(b)) => F64(f64::powi(a.to_float(), b).into()),
-                (a, b) => {
-                    error!("Invalid args for pow: {:?} / {:?}", a, b);
-                    return bad_sig();
-                }
-            },
-            FMOD_INDEX => match (a1?, a2?) {
-                (F32(a), F32(b)) => F32(a % b),
-                (F64(a), F64(b)) => F64(a % b),
-                (a, b) => {
-                    error!("Invalid args for fmod: {:?} / {:?}", a, b);
-                    return bad_sig();
-                }
-            },
-            EXP2_INDEX => match a1? {
-                I32(a) => F64((a as f64).exp2().into()),
-                I64(a) => F64((a as f64).exp2().into()),
-                F32(a) => F32(a.to_float().exp2().into()),
-                F64(a) => F64(a.to_float().exp2().into()),
-            },
-            LDEXP_INDEX => match (a1?, a2?) {
-                (F32(a), I32(b)) => F32(a*(b as f32).exp2()),
-                (F64(a), I32(b)) => F64(a*(b as f64).exp2()),
-                (F32(a), F32(b)) => F32((a*b).to_float().exp2().into()),
-                (F64(a), F64(b)) => F64((a*b).to_float().exp2().into()),
-                (a, b) => {
-                    error!("Invalid args for ldexp: {:?} / {:?}", a, b);
-                    return bad_sig();
-                }
-            },
-            LOG10_INDEX => match a1? {
-                I32(a) => F64((a as f64).log10().into()),
-                I64(a) => F64((a as f64).log10().into()),
-                F32(a) => F32(a.to_float().log10().into()),
-                F64(a) => F64(a.to_float().log10().into()),
-            },
+            idx if idx < libm::END_INDEX => {
+                libm::invoke(&self.memory, idx, args)
+            }
+            idx if idx < host::END_INDEX => {
+                self.host.invoke(&self.memory, idx, args)
+            }
             _ => panic!("Unimplemented function at {}", index),
-        };
-        return Ok(Some(res));
+        }
     }
 }
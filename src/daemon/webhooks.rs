@@ -0,0 +1,473 @@
+//! Outgoing HTTP notifications about deployments and leadership
+//!
+//! Hooks are configured in `webhooks.yaml` in the config dir. Every hook
+//! has its own delivery thread, so slow or failing endpoints never block
+//! the apply thread or other hooks. Failed deliveries are retried with
+//! exponential backoff.
+use std::cmp::min;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+use humantime::parse_duration;
+use quire::validate::{Structure, Sequence, Mapping, Numeric, Scalar};
+use quire::{parse_config, parse_string, Options};
+use rand::{thread_rng, Rng};
+use serde_json::{Value as Json, to_value, to_string};
+
+use config::http::{self, Url};
+
+use elect::Epoch;
+
+
+const EVENTS: &[&str] = &[
+    "deployment_started",
+    "deployment_finished",
+    "role_failed",
+    "role_recovered",
+    "leadership_changed",
+];
+/// Maximum delay between delivery attempts
+const MAX_RETRY_DELAY: u64 = 300;
+/// Deliveries of a hook are dropped, oldest first, when there are more
+const MAX_QUEUE: usize = 1000;
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag="event", rename_all="snake_case")]
+pub enum Event {
+    DeploymentStarted {
+        deployment_id: String,
+        schedule: String,
+        roles: Vec<String>,
+    },
+    DeploymentFinished {
+        deployment_id: String,
+        schedule: String,
+        roles: usize,
+        failed_roles: Vec<String>,
+    },
+    RoleFailed { role: String },
+    RoleRecovered { role: String },
+    LeadershipChanged {
+        is_leader: bool,
+        leader: Option<String>,
+        epoch: Epoch,
+    },
+}
+
+#[derive(Deserialize, Debug)]
+struct HookConfig {
+    url: String,
+    events: Vec<String>,
+    headers: BTreeMap<String, String>,
+    timeout: String,
+    retry_delay: String,
+    max_attempts: u32,
+}
+
+#[derive(Deserialize, Debug)]
+struct ConfigFile {
+    hooks: Vec<HookConfig>,
+}
+
+#[derive(Debug)]
+pub struct Hook {
+    url: String,
+    target: Url,
+    /// Empty list means all events
+    events: Vec<String>,
+    headers: BTreeMap<String, String>,
+    timeout: Duration,
+    retry_delay: Duration,
+    max_attempts: u32,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub node: String,
+    pub hostname: String,
+    pub hooks: Vec<Arc<Hook>>,
+}
+
+struct Delivery {
+    event: &'static str,
+    body: Arc<String>,
+    attempts: u32,
+    next: Instant,
+}
+
+pub type Receiver = mpsc::Receiver<(SystemTime, Event)>;
+
+/// Sending side of the webhook queue, never blocks
+pub struct Sender(Mutex<mpsc::Sender<(SystemTime, Event)>>);
+
+pub fn channel() -> (Sender, Receiver) {
+    let (tx, rx) = mpsc::channel();
+    (Sender(Mutex::new(tx)), rx)
+}
+
+impl Sender {
+    pub fn send(&self, event: Event) {
+        self.0.lock().expect("webhooks lock")
+            .send((SystemTime::now(), event))
+            // thread exits only if there are no hooks
+            .ok();
+    }
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        use self::Event::*;
+        match *self {
+            DeploymentStarted {..} => "deployment_started",
+            DeploymentFinished {..} => "deployment_finished",
+            RoleFailed {..} => "role_failed",
+            RoleRecovered {..} => "role_recovered",
+            LeadershipChanged {..} => "leadership_changed",
+        }
+    }
+}
+
+impl Hook {
+    fn wants(&self, event: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|e| e == event)
+    }
+}
+
+fn validator() -> Structure<'static> {
+    Structure::new()
+    .member("hooks", Sequence::new(Structure::new()
+        .member("url", Scalar::new())
+        .member("events", Sequence::new(Scalar::new()))
+        .member("headers", Mapping::new(Scalar::new(), Scalar::new()))
+        .member("timeout", Scalar::new().default("10s"))
+        .member("retry_delay", Scalar::new().default("1s"))
+        .member("max_attempts", Numeric::new().min(1).default(8))))
+}
+
+fn hook(cfg: HookConfig) -> Result<Hook, Error> {
+    let target = http::parse_url(&cfg.url)?;
+    for event in &cfg.events {
+        if !EVENTS.contains(&&event[..]) {
+            bail!("unknown event {:?}, expected one of {}",
+                event, EVENTS.join(", "));
+        }
+    }
+    Ok(Hook {
+        target,
+        url: cfg.url,
+        events: cfg.events,
+        headers: cfg.headers,
+        timeout: parse_duration(&cfg.timeout)
+            .map_err(|e| format_err!("bad timeout: {}", e))?,
+        retry_delay: parse_duration(&cfg.retry_delay)
+            .map_err(|e| format_err!("bad retry_delay: {}", e))?,
+        max_attempts: cfg.max_attempts,
+    })
+}
+
+/// Reads `webhooks.yaml`, there are no hooks if there is no such file
+pub fn read_hooks(config_dir: &Path) -> Result<Vec<Arc<Hook>>, Error> {
+    let path = config_dir.join("webhooks.yaml");
+    let file: ConfigFile = if path.exists() {
+        parse_config(&path, &validator(), &Options::default())
+    } else {
+        parse_string("<default>", "{}", &validator(), &Options::default())
+    }.map_err(|e| format_err!("error reading {:?}: {}", path, e))?;
+    file.hooks.into_iter()
+        .map(|h| hook(h).map(Arc::new)
+            .map_err(|e| format_err!("error reading {:?}: {}", path, e)))
+        .collect()
+}
+
+fn post(hook: &Hook, body: &str) -> Result<(), Error> {
+    let agent = concat!("verwalter/", env!("CARGO_PKG_VERSION"));
+    let common = [("User-Agent", agent), ("Content-Type", "application/json")];
+    let headers = common.iter().cloned()
+        .chain(hook.headers.iter().map(|(k, v)| (&k[..], &v[..])));
+    http::request("POST", &hook.target, headers, body.as_bytes(),
+                  hook.timeout)?;
+    Ok(())
+}
+
+/// Delay before the next attempt, doubles on each failure (with jitter)
+fn retry_delay(hook: &Hook, attempts: u32) -> Duration {
+    let base = hook.retry_delay * (1 << min(attempts.saturating_sub(1), 16));
+    let base = min(base, Duration::from_secs(MAX_RETRY_DELAY));
+    let millis = base.as_secs() * 1000 + base.subsec_millis() as u64;
+    let jitter = (millis as f64 * thread_rng().gen_range(0.75, 1.25)) as u64;
+    Duration::from_millis(jitter)
+}
+
+fn payload(config: &Config, time: SystemTime, event: &Event) -> String {
+    let mut json = to_value(event).expect("can serialize event");
+    json.as_object_mut().map(|obj| {
+        let ts = time.duration_since(UNIX_EPOCH)
+            .expect("time is after epoch");
+        obj.insert("node".into(), Json::from(config.node.clone()));
+        obj.insert("hostname".into(), Json::from(config.hostname.clone()));
+        obj.insert("timestamp".into(), Json::from(
+            ts.as_secs() * 1000 + ts.subsec_millis() as u64));
+    });
+    to_string(&json).expect("can serialize event")
+}
+
+fn deliver(hook: &Hook, mut item: Delivery,
+    queue: &mut VecDeque<Delivery>)
+{
+    item.attempts += 1;
+    match post(hook, &item.body) {
+        Ok(()) => {
+            debug!("Delivered {} to {}", item.event, hook.url);
+        }
+        Err(e) if item.attempts >= hook.max_attempts => {
+            error!("Giving up delivering {} to {} after {} attempts: {}",
+                item.event, hook.url, item.attempts, e);
+        }
+        Err(e) => {
+            let delay = retry_delay(hook, item.attempts);
+            warn!("Error delivering {} to {}: {}. Retrying in {:?}",
+                item.event, hook.url, e, delay);
+            item.next = Instant::now() + delay;
+            queue.push_back(item);
+        }
+    }
+}
+
+/// Delivers events to a single hook until the sender is gone and the
+/// queue is empty
+fn worker(hook: Arc<Hook>, rx: mpsc::Receiver<(&'static str, Arc<String>)>)
+{
+    let mut queue = VecDeque::<Delivery>::new();
+    let mut closed = false;
+    loop {
+        let now = Instant::now();
+        for _ in 0..queue.len() {
+            let item = queue.pop_front().expect("queue is not empty");
+            if item.next <= now {
+                deliver(&hook, item, &mut queue);
+            } else {
+                queue.push_back(item);
+            }
+        }
+        let next = queue.iter().map(|d| d.next).min();
+        let msg = match (next, closed) {
+            (None, true) => return,
+            (Some(next), true) => {
+                let now = Instant::now();
+                if next > now {
+                    thread::sleep(next - now);
+                }
+                continue;
+            }
+            (Some(next), false) => {
+                let now = Instant::now();
+                let wait = if next > now { next - now }
+                           else { Duration::new(0, 0) };
+                match rx.recv_timeout(wait) {
+                    Ok(msg) => msg,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => {
+                        closed = true;
+                        continue;
+                    }
+                }
+            }
+            (None, false) => match rx.recv() {
+                Ok(msg) => msg,
+                Err(mpsc::RecvError) => {
+                    closed = true;
+                    continue;
+                }
+            },
+        };
+        let (event, body) = msg;
+        queue.push_back(Delivery {
+            event, body,
+            attempts: 0,
+            next: Instant::now(),
+        });
+        while queue.len() > MAX_QUEUE {
+            let item = queue.pop_front().expect("queue is not empty");
+            error!("Webhook queue is full, dropping {} for {}",
+                item.event, hook.url);
+        }
+    }
+}
+
+/// Delivers events until all the senders are gone and the queues are empty
+pub fn run(config: Config, rx: Receiver) {
+    let mut workers = Vec::new();
+    for hook in &config.hooks {
+        let (tx, hook_rx) = mpsc::channel();
+        let worker_hook = hook.clone();
+        let handle = thread::Builder::new()
+            .name(String::from("webhook"))
+            .spawn(move || worker(worker_hook, hook_rx))
+            .expect("webhook thread starts");
+        workers.push((hook.clone(), tx, handle));
+    }
+    if workers.is_empty() {
+        return;
+    }
+    for (time, event) in rx {
+        let name = event.name();
+        let body = Arc::new(payload(&config, time, &event));
+        for &(ref hook, ref tx, _) in &workers {
+            if hook.wants(name) {
+                tx.send((name, body.clone())).ok();
+            }
+        }
+    }
+    for (_, tx, handle) in workers {
+        drop(tx);
+        handle.join().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write, BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::{SystemTime, Instant, Duration};
+
+    use serde_json::{Value, from_str};
+
+    use super::{Config, Hook, HookConfig, Event, hook, run};
+
+    /// Accepts one connection per status, returns received bodies
+    fn stand_in(statuses: Vec<u16>) -> (u16, thread::JoinHandle<Vec<String>>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in statuses {
+                let (sock, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(sock);
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim().to_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if line.starts_with("content-length:") {
+                        length = line["content-length:".len()..]
+                            .trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                write!(reader.get_mut(),
+                    "HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n",
+                    status).unwrap();
+            }
+            bodies
+        });
+        (port, handle)
+    }
+
+    fn test_hook(port: u16, events: Vec<&str>) -> Arc<Hook> {
+        Arc::new(hook(HookConfig {
+            url: format!("http://127.0.0.1:{}/hook", port),
+            events: events.into_iter().map(String::from).collect(),
+            headers: vec![("X-Token".to_string(), "abc".to_string())]
+                .into_iter().collect(),
+            timeout: "1s".into(),
+            retry_delay: "10ms".into(),
+            max_attempts: 3,
+        }).unwrap())
+    }
+
+    fn run_with(hooks: Vec<Arc<Hook>>, events: Vec<Event>) {
+        let (tx, rx) = channel();
+        for event in events {
+            tx.send((SystemTime::now(), event)).unwrap();
+        }
+        drop(tx);
+        run(Config {
+            node: "n1".into(),
+            hostname: "host1".into(),
+            hooks,
+        }, rx);
+    }
+
+    #[test]
+    fn retries_failed_delivery() {
+        let (port, server) = stand_in(vec![500, 200]);
+        run_with(vec![test_hook(port, vec![])], vec![
+            Event::RoleFailed { role: "web".into() },
+        ]);
+        let bodies = server.join().unwrap();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0], bodies[1]);
+        let json: Value = from_str(&bodies[0]).unwrap();
+        assert_eq!(json["event"], "role_failed");
+        assert_eq!(json["role"], "web");
+        assert_eq!(json["hostname"], "host1");
+    }
+
+    #[test]
+    fn filters_events() {
+        let (port, server) = stand_in(vec![200]);
+        run_with(vec![test_hook(port, vec!["leadership_changed"])], vec![
+            Event::RoleRecovered { role: "web".into() },
+            Event::LeadershipChanged {
+                is_leader: true, leader: None, epoch: 7,
+            },
+        ]);
+        let bodies = server.join().unwrap();
+        let json: Value = from_str(&bodies[0]).unwrap();
+        assert_eq!(json["event"], "leadership_changed");
+        assert_eq!(json["epoch"], 7);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let (port, server) = stand_in(vec![503, 503, 503]);
+        run_with(vec![test_hook(port, vec![])], vec![
+            Event::RoleFailed { role: "web".into() },
+        ]);
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn dead_hook_doesnt_block_others() {
+        // accepts connections, but never responds
+        let dead = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dead_port = dead.local_addr().unwrap().port();
+        let (port, server) = stand_in(vec![200]);
+        let start = Instant::now();
+        let runner = thread::spawn(move || {
+            run_with(vec![test_hook(dead_port, vec![]),
+                          test_hook(port, vec![])],
+                     vec![Event::RoleFailed { role: "web".into() }]);
+        });
+        assert_eq!(server.join().unwrap().len(), 1);
+        // timeout of the dead hook is one second
+        assert!(start.elapsed() < Duration::from_millis(900));
+        runner.join().unwrap();
+        drop(dead);
+    }
+
+    #[test]
+    fn unknown_event() {
+        assert!(hook(HookConfig {
+            url: "http://localhost/".into(),
+            events: vec!["role_exploded".into()],
+            headers: Default::default(),
+            timeout: "1s".into(),
+            retry_delay: "1s".into(),
+            max_attempts: 1,
+        }).is_err());
+    }
+}
@@ -26,8 +26,8 @@ impl Action for CleanFiles {
     fn execute(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
-        let pattern = variables.expand(&self.pattern);
-        let keep_list = variables.expand(&self.keep_list);
+        let pattern = variables.expand(&self.pattern)?;
+        let keep_list = variables.expand(&self.keep_list)?;
         task.log(format_args!(
             "CleanFiles {{ pattern: {:?}, keep_list: {:?} }}\n",
             &pattern, &keep_list));
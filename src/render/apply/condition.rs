@@ -1,6 +1,7 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
 use std::collections::HashMap;
-use std::io::Cursor;
 
 use hex::encode as to_hex;
 use failure::ResultExt;
@@ -8,72 +9,120 @@ use dir_signature::{ScannerConfig, HashType, v1, get_hash};
 use quire::validate as V;
 
 use apply::{Command, Action, Task, Variables, Error};
+use apply::expand::lookup;
 use renderfile::command_validator;
 
+/// How deep `any`/`all` may be nested
+const MAX_NESTING: usize = 3;
 
+
+/// Condition and predicates nested in `any`/`all` (which have no commands)
+///
+/// All predicates specified must be true for commands to run.
 #[derive(Deserialize, Debug, Clone)]
 pub struct Condition {
     dirs_changed: Vec<PathBuf>,
+    files_changed: Vec<FileChange>,
+    vars_changed: Vec<String>,
+    exists: Vec<String>,
+    not_exists: Vec<String>,
+    action_changed: bool,
+    // validator has no `any`/`all` at `MAX_NESTING`
+    #[serde(default)]
+    any: Vec<Condition>,
+    #[serde(default)]
+    all: Vec<Condition>,
+    #[serde(default)]
     commands: Vec<Command>,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct FileChange {
+    src: String,
+    dest: String,
+}
+
 pub struct Data {
     dirs: HashMap<PathBuf, String>,
+    /// Whether destination differed from the rendered file before the action
+    files: HashMap<String, bool>,
 }
 
-impl Condition {
-    pub fn config() -> V::Structure<'static> {
-        V::Structure::new()
+fn predicate_validator(depth: usize) -> V::Structure<'static> {
+    let mut val = V::Structure::new()
         .member("dirs_changed", V::Sequence::new(V::Scalar::new()))
-        .member("commands", V::Sequence::new(command_validator(false)))
+        .member("files_changed", V::Sequence::new(V::Structure::new()
+            .member("src", V::Scalar::new().default("{{ tmp_file }}"))
+            .member("dest", V::Scalar::new())))
+        .member("vars_changed", V::Sequence::new(V::Scalar::new()))
+        .member("exists", V::Sequence::new(V::Scalar::new()))
+        .member("not_exists", V::Sequence::new(V::Scalar::new()))
+        .member("action_changed", V::Scalar::new().default("false"));
+    if depth < MAX_NESTING {
+        val = val
+            .member("any", V::Sequence::new(predicate_validator(depth+1)))
+            .member("all", V::Sequence::new(predicate_validator(depth+1)));
     }
+    return val;
 }
 
-impl Action for Condition {
-    fn needs_pitch(&self) -> bool {
-        self.dirs_changed.len() > 0
+fn dir_hash(dir: &Path) -> Result<String, Error> {
+    let mut cfg = ScannerConfig::new();
+    cfg.auto_threads();
+    cfg.hash(HashType::blake2b_256());
+    cfg.add_dir(&dir, "/");
+    let mut index_buf = Vec::new();
+    v1::scan(&cfg, &mut index_buf)
+        .context(dir.display().to_string())?;
+    Ok(to_hex(get_hash(&mut Cursor::new(&index_buf))?))
+}
+
+fn file_differs(src: &str, dest: &str) -> Result<bool, Error> {
+    let new = fs::read(src).context(src.to_string())?;
+    match fs::read(dest) {
+        Ok(old) => Ok(old != new),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(format_err!("{}: {}", dest, e)),
+    }
+}
+
+impl Condition {
+    pub fn config() -> V::Structure<'static> {
+        predicate_validator(0)
+        .member("commands", V::Sequence::new(command_validator(false)))
     }
 
-    fn pitch(&self, task: &mut Task, _variables: &Variables)
+    fn pitch_data(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
         for dir in &self.dirs_changed {
             if dir.exists() {
-                let mut cfg = ScannerConfig::new();
-                cfg.auto_threads();
-                cfg.hash(HashType::blake2b_256());
-                cfg.add_dir(&dir, "/");
-                let mut index_buf = Vec::new();
-                v1::scan(&cfg, &mut index_buf)
-                    .context(dir.display().to_string())?;
-                task.scratch.condition.dirs.insert(dir.clone(),
-                    to_hex(get_hash(&mut Cursor::new(&index_buf))?));
+                let hash = dir_hash(dir)?;
+                task.scratch.condition.dirs.insert(dir.clone(), hash);
             }
         }
+        for file in &self.files_changed {
+            let dest = variables.expand(&file.dest)?;
+            let changed = file_differs(&variables.expand(&file.src)?, &dest)?;
+            task.scratch.condition.files.insert(dest, changed);
+        }
+        for cond in self.any.iter().chain(&self.all) {
+            cond.pitch_data(task, variables)?;
+        }
         Ok(())
     }
 
-    fn execute(&self, task: &mut Task, variables: &Variables)
-        -> Result<(), Error>
-    {
-        let mut changed = false;
+    fn check_dirs(&self, task: &mut Task) -> Result<bool, Error> {
         for dir in &self.dirs_changed {
             let old_hash = task.scratch.condition.dirs.get(dir);
-            changed = match (dir.exists(), old_hash) {
+            let changed = match (dir.exists(), old_hash) {
                 (false, None) => {
                     task.log.log(format_args!(
                         "Condition: {:?} not exists\n", dir));
                     false
                 }
                 (true, Some(old_hash)) => {
-                    let mut cfg = ScannerConfig::new();
-                    cfg.auto_threads();
-                    cfg.hash(HashType::blake2b_256());
-                    cfg.add_dir(&dir, "/");
-                    let mut index_buf = Vec::new();
-                    v1::scan(&cfg, &mut index_buf)
-                        .context(dir.display().to_string())?;
-                    let hash = to_hex(get_hash(&mut Cursor::new(&index_buf))?);
+                    let hash = dir_hash(dir)?;
                     if &hash != old_hash {
                         task.log.log(format_args!(
                             "Condition: {:?} changed {:.6} -> {:.6}\n",
@@ -98,10 +147,160 @@ impl Action for Condition {
                 }
             };
             if changed {
-                break;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn check_files(&self, task: &mut Task, variables: &Variables)
+        -> Result<bool, Error>
+    {
+        for file in &self.files_changed {
+            let dest = variables.expand(&file.dest)?;
+            let changed = task.scratch.condition.files.get(&dest)
+                .cloned().unwrap_or(true);
+            if changed {
+                task.log.log(format_args!(
+                    "Condition: {:?} changed\n", dest));
+                return Ok(true);
+            } else {
+                task.log.log(format_args!(
+                    "Condition: {:?} unchanged\n", dest));
+            }
+        }
+        Ok(false)
+    }
+
+    fn check_vars(&self, task: &mut Task) -> bool {
+        let previous = match task.previous_vars {
+            Some(prev) => prev,
+            None => {
+                task.log.log(format_args!(
+                    "Condition: no previous successful render, \
+                     assuming vars changed\n"));
+                return true;
             }
         };
-        if !changed {
+        for path in &self.vars_changed {
+            let old = lookup(previous, path);
+            let new = lookup(task.vars, path);
+            if old != new {
+                task.log.log(format_args!(
+                    "Condition: variable {:?} changed\n", path));
+                return true;
+            }
+        }
+        task.log.log(format_args!(
+            "Condition: variables {:?} unchanged\n", self.vars_changed));
+        false
+    }
+
+    fn check_exist(&self, task: &mut Task, variables: &Variables,
+        paths: &[String], expected: bool)
+        -> Result<bool, Error>
+    {
+        for path in paths {
+            let path = variables.expand(path)?;
+            if Path::new(&path).exists() != expected {
+                task.log.log(format_args!("Condition: {:?} {}\n", path,
+                    if expected { "doesn't exist" } else { "exists" }));
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn check(&self, task: &mut Task, variables: &Variables)
+        -> Result<bool, Error>
+    {
+        let mut checks = 0;
+        if !self.dirs_changed.is_empty() {
+            checks += 1;
+            if !self.check_dirs(task)? {
+                return Ok(false);
+            }
+        }
+        if !self.files_changed.is_empty() {
+            checks += 1;
+            if !self.check_files(task, variables)? {
+                return Ok(false);
+            }
+        }
+        if !self.vars_changed.is_empty() {
+            checks += 1;
+            if !self.check_vars(task) {
+                return Ok(false);
+            }
+        }
+        if !self.exists.is_empty() {
+            checks += 1;
+            let exists = &self.exists;
+            if !self.check_exist(task, variables, exists, true)? {
+                return Ok(false);
+            }
+        }
+        if !self.not_exists.is_empty() {
+            checks += 1;
+            let not_exists = &self.not_exists;
+            if !self.check_exist(task, variables, not_exists, false)? {
+                return Ok(false);
+            }
+        }
+        if self.action_changed {
+            checks += 1;
+            if !task.changed {
+                task.log.log(format_args!(
+                    "Condition: files of the action unchanged\n"));
+                return Ok(false);
+            }
+        }
+        if !self.any.is_empty() {
+            checks += 1;
+            let mut matched = false;
+            for cond in &self.any {
+                if cond.check(task, variables)? {
+                    matched = true;
+                    break;
+                }
+            }
+            if !matched {
+                return Ok(false);
+            }
+        }
+        if !self.all.is_empty() {
+            checks += 1;
+            for cond in &self.all {
+                if !cond.check(task, variables)? {
+                    return Ok(false);
+                }
+            }
+        }
+        // condition without predicates never matches
+        Ok(checks > 0)
+    }
+
+    fn has_pitch(&self) -> bool {
+        self.dirs_changed.len() > 0 || self.files_changed.len() > 0 ||
+            self.any.iter().chain(&self.all).any(|c| c.has_pitch())
+    }
+}
+
+impl Action for Condition {
+    fn needs_pitch(&self) -> bool {
+        self.has_pitch()
+    }
+
+    fn pitch(&self, task: &mut Task, variables: &Variables)
+        -> Result<(), Error>
+    {
+        self.pitch_data(task, variables)
+    }
+
+    fn execute(&self, task: &mut Task, variables: &Variables)
+        -> Result<(), Error>
+    {
+        if !self.check(task, variables)? {
             return Ok(());
         }
         for cmd in &self.commands {
@@ -115,6 +314,140 @@ impl Data {
     pub fn new() -> Data {
         Data {
             dirs: HashMap::new(),
+            files: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use indexed_log::Index;
+    use quire::{parse_string, Options};
+    use serde_json::{Value, from_str};
+    use tempfile::tempdir;
+
+    use apply::{Task, Scratch, Source, Variables};
+    use config::Sandbox;
+    use super::Condition;
+
+    fn cond() -> Condition {
+        Condition {
+            dirs_changed: Vec::new(),
+            files_changed: Vec::new(),
+            vars_changed: Vec::new(),
+            exists: Vec::new(),
+            not_exists: Vec::new(),
+            action_changed: false,
+            any: Vec::new(),
+            all: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    fn vars(port: u16) -> Value {
+        from_str(&format!(r#"{{"port": {}, "host": "a"}}"#, port)).unwrap()
+    }
+
+    fn check(cond: &Condition, prev: Option<&Value>, changed: bool)
+        -> bool
+    {
+        let dir = tempdir().unwrap();
+        let vars = vars(8080);
+        let mut index = Index::new(dir.path(), false);
+        let mut deployment = index.deployment("test", true);
+        let mut role = deployment.role("role", true).unwrap();
+        let mut action = role.action("action");
+        let source = Source::TmpFiles(HashMap::new());
+        let sandbox = Sandbox { log_dirs: HashMap::new() };
+        let mut task = Task {
+            runner: "action",
+            log: &mut action,
+            dry_run: false,
+            source: &source,
+            sandbox: &sandbox,
+            scratch: Scratch::new(),
+            written: Vec::new(),
+            changed: changed,
+            vars: &vars,
+            previous_vars: prev,
+        };
+        let variables = Variables::new().add_vars(&vars);
+        cond.check(&mut task, &variables).unwrap()
+    }
+
+    fn vars_changed(path: &str) -> Condition {
+        Condition { vars_changed: vec![path.to_string()], .. cond() }
+    }
+
+    fn exists(path: &str) -> Condition {
+        Condition { exists: vec![path.to_string()], .. cond() }
+    }
+
+    fn not_exists(path: &str) -> Condition {
+        Condition { not_exists: vec![path.to_string()], .. cond() }
+    }
+
+    #[test]
+    fn empty() {
+        assert!(!check(&cond(), None, true));
+    }
+
+    #[test]
+    fn variables() {
+        let old = vars(80);
+        assert!(check(&vars_changed("port"), Some(&old), false));
+        assert!(!check(&vars_changed("host"), Some(&old), false));
+        assert!(check(&vars_changed("host"), None, false));
+    }
+
+    #[test]
+    fn existence() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let missing = dir.path().join("missing");
+        let missing = missing.to_str().unwrap();
+        assert!(check(&exists(path), None, false));
+        assert!(!check(&exists(missing), None, false));
+        assert!(check(&not_exists(missing), None, false));
+        assert!(!check(&not_exists(path), None, false));
+    }
+
+    #[test]
+    fn action_changed() {
+        let cond = Condition { action_changed: true, .. cond() };
+        assert!(check(&cond, None, true));
+        assert!(!check(&cond, None, false));
+    }
+
+    #[test]
+    fn any_and_all() {
+        let old = vars(80);
+        let yes = || vars_changed("port");
+        let no = || vars_changed("host");
+        let any = |c: Vec<Condition>| Condition { any: c, .. cond() };
+        let all = |c: Vec<Condition>| Condition { all: c, .. cond() };
+        assert!(check(&any(vec![no(), yes()]), Some(&old), false));
+        assert!(!check(&any(vec![no(), no()]), Some(&old), false));
+        assert!(check(&all(vec![yes(), yes()]), Some(&old), false));
+        assert!(!check(&all(vec![yes(), no()]), Some(&old), false));
+        // predicates of the same condition are combined with AND
+        let both = Condition {
+            any: vec![yes()],
+            action_changed: true,
+            .. cond()
+        };
+        assert!(check(&both, Some(&old), true));
+        assert!(!check(&both, Some(&old), false));
+    }
+
+    #[test]
+    fn nesting() {
+        fn parse(data: &str) -> bool {
+            parse_string::<Condition>("<test>", data,
+                &Condition::config(), &Options::default()).is_ok()
         }
+        assert!(parse("any: [{any: [{any: [{exists: [/]}]}]}]"));
+        assert!(!parse("any: [{any: [{any: [{any: [{exists: [/]}]}]}]}]"));
     }
 }
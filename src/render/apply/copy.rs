@@ -1,5 +1,5 @@
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::os::unix::fs::PermissionsExt;
 
 use quire::validate as V;
@@ -27,8 +27,8 @@ impl Action for Copy {
     fn execute(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
-        let src = variables.expand(&self.src);
-        let dest = variables.expand(&self.dest);
+        let src = variables.expand(&self.src)?;
+        let dest = variables.expand(&self.dest)?;
         task.log(format_args!("Copy {{ src: {:?}, dest: {:?} }}\n",
             &self.src, &self.dest));
         if !task.dry_run {
@@ -59,6 +59,7 @@ impl Action for Copy {
                     format_err!(
                         "{:?} failed to rename: {}\n", self, e)
                 })?;
+            task.written.push(PathBuf::from(dest));
             Ok(())
         } else {
             Ok(())
@@ -1,47 +1,145 @@
 use std::collections::HashMap;
 
-use regex::{Regex, Captures};
+use regex::Regex;
+use serde_json::{Value, to_string};
 use apply;
 
 
 lazy_static! {
     static ref VAR_REGEX: Regex = {
-        Regex::new(r"\{\{\s*([\w\.]+)\s*\}\}")
+        Regex::new(r"\{\{(.*?)\}\}")
         .expect("regex compiles")
     };
+    static ref PATH_REGEX: Regex = {
+        Regex::new(r"^\s*(\w+(?:\.\w+)*)\s*")
+        .expect("regex compiles")
+    };
+    static ref LITERAL_REGEX: Regex = {
+        Regex::new(r#"^\s*(?:"((?:[^"\\]|\\.)*)"|'([^']*)')\s*$"#)
+        .expect("regex compiles")
+    };
+    static ref FILTER_REGEX: Regex = {
+        Regex::new(concat!(r#"^\|\s*(\w+)\s*"#,
+            r#"(?:\(\s*(?:"((?:[^"\\]|\\.)*)"|'([^']*)')\s*\)\s*)?"#))
+        .expect("regex compiles")
+    };
+}
+
+#[derive(Debug, Fail)]
+pub enum ExpandError {
+    #[fail(display="unknown variable {:?} in {:?}", _0, _1)]
+    UnknownVariable(String, String),
+    #[fail(display="variable {:?} is null, use `default` filter", _0)]
+    Null(String),
+    #[fail(display="variable {:?} is {}, use `json` or `join` filter",
+           _0, _1)]
+    NotAString(String, &'static str),
+    #[fail(display="unknown filter {:?} in {:?}", _0, _1)]
+    UnknownFilter(String, String),
+    #[fail(display="filter {:?} in {:?}: {}", _0, _1, _2)]
+    BadFilter(String, String, &'static str),
+    #[fail(display="invalid expression {:?}", _0)]
+    Syntax(String),
 }
 
+/// Variables available to commands
+///
+/// These are `role`, `files.<template>` (and `tmp_file` if there is a
+/// single template), and all the variables of the role by dotted path.
 #[derive(Debug)]
-pub struct Variables(HashMap<String, String>);
-
-impl Variables {
-    pub fn new() -> Variables {
-        Variables(HashMap::new())
-    }
-    pub fn expand(&self, src: &str) -> String {
-        // TODO(tailhook) proper failure when no such var
-        VAR_REGEX.replace_all(src, |caps: &Captures| {
-            let name = caps.get(1).unwrap();
-            match self.0.get(name.as_str()) {
-                Some(x) => x.clone(),
-                None => format!("<< unknown variable: {} >>", name.as_str()),
+pub struct Variables<'a> {
+    strings: HashMap<String, String>,
+    vars: Option<&'a Value>,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new() -> Variables<'a> {
+        Variables {
+            strings: HashMap::new(),
+            vars: None,
+        }
+    }
+    /// Expands `{{ path | filter }}` expressions
+    ///
+    /// Unknown variables are errors. Braces which don't start with a path
+    /// (like `{{.Name}}` of Go templates) are left as is, and
+    /// `{{ '{{' }}` may be used to put literal braces.
+    pub fn expand(&self, src: &str) -> Result<String, ExpandError> {
+        let mut result = String::with_capacity(src.len());
+        let mut end = 0;
+        for caps in VAR_REGEX.captures_iter(src) {
+            let whole = caps.get(0).unwrap();
+            if let Some(value) = self.evaluate(&caps[1], whole.as_str())? {
+                result.push_str(&src[end..whole.start()]);
+                result.push_str(&value);
+                end = whole.end();
             }
-        }).to_string()
+        }
+        result.push_str(&src[end..]);
+        Ok(result)
+    }
+    fn get(&self, path: &str) -> Option<Value> {
+        if let Some(value) = self.strings.get(path) {
+            return Some(Value::String(value.clone()));
+        }
+        self.vars.and_then(|v| lookup(v, path)).cloned()
+    }
+    /// Returns `None` if `expr` is not an expression
+    fn evaluate(&self, expr: &str, whole: &str)
+        -> Result<Option<String>, ExpandError>
+    {
+        use self::ExpandError::*;
+        if let Some(caps) = LITERAL_REGEX.captures(expr) {
+            return Ok(caps.get(1).map(|x| unescape(x.as_str()))
+                .or_else(|| caps.get(2).map(|x| x.as_str().to_string())));
+        }
+        let caps = match PATH_REGEX.captures(expr) {
+            Some(caps) => caps,
+            None => return Ok(None),
+        };
+        let path = caps.get(1).unwrap().as_str();
+        let mut rest = &expr[caps.get(0).unwrap().end()..];
+        if !rest.is_empty() && !rest.starts_with('|') {
+            return Ok(None);
+        }
+        let mut value = self.get(path);
+        while !rest.is_empty() {
+            let caps = FILTER_REGEX.captures(rest)
+                .ok_or_else(|| Syntax(whole.to_string()))?;
+            let name = caps.get(1).unwrap().as_str();
+            let arg = caps.get(2).map(|x| unescape(x.as_str()))
+                .or_else(|| caps.get(3).map(|x| x.as_str().to_string()));
+            value = filter(name, arg, value, path, whole)?;
+            rest = &rest[caps.get(0).unwrap().end()..];
+        }
+        match value {
+            Some(value) => plain(path, &value).map(Some),
+            None => Err(UnknownVariable(path.to_string(), whole.to_string())),
+        }
     }
     pub fn add<A: AsRef<str>, B: AsRef<str>>(mut self, a: A, b: B)
-        -> Variables
+        -> Variables<'a>
     {
-        self.0.insert(a.as_ref().to_string(), b.as_ref().to_string());
+        self.strings.insert(a.as_ref().to_string(), b.as_ref().to_string());
         self
     }
-    pub fn add_source(mut self, src: &apply::Source) -> Variables
+    pub fn add_vars(mut self, vars: &'a Value) -> Variables<'a> {
+        self.vars = Some(vars);
+        self
+    }
+    pub fn add_source(mut self, src: &apply::Source) -> Variables<'a>
     {
         // TODO(tailhook) proper failure when tmpfile can't be stringified
         use apply::Source::*;
         match *src {
             TmpFiles(ref templates) => {
                 for (name, path) in templates {
-                    self.0.insert(format!("files.{}", name),
+                    self.strings.insert(format!("files.{}", name),
+                                  path.path().display().to_string());
+                }
+                if templates.len() == 1 {
+                    let path = templates.values().next().unwrap();
+                    self.strings.insert("tmp_file".into(),
                                   path.path().display().to_string());
                 }
             }
@@ -49,3 +147,157 @@ impl Variables {
         self
     }
 }
+
+fn unescape(src: &str) -> String {
+    let mut result = String::with_capacity(src.len());
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(c) = chars.next() {
+                result.push(c);
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+fn shell_quote(src: &str) -> String {
+    let safe = !src.is_empty() && src.chars().all(|c| {
+        c.is_alphanumeric() || "-_./=:,+@%".contains(c)
+    });
+    if safe {
+        src.to_string()
+    } else {
+        format!("'{}'", src.replace("'", r"'\''"))
+    }
+}
+
+/// Converts a scalar to string
+fn plain(path: &str, value: &Value) -> Result<String, ExpandError> {
+    use self::ExpandError::*;
+    match *value {
+        Value::String(ref s) => Ok(s.clone()),
+        Value::Number(ref n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        Value::Null => Err(Null(path.to_string())),
+        Value::Array(_) => Err(NotAString(path.to_string(), "an array")),
+        Value::Object(_) => Err(NotAString(path.to_string(), "an object")),
+    }
+}
+
+fn filter(name: &str, arg: Option<String>, value: Option<Value>,
+    path: &str, whole: &str)
+    -> Result<Option<Value>, ExpandError>
+{
+    use self::ExpandError::*;
+    let bad = |msg| BadFilter(name.to_string(), whole.to_string(), msg);
+    if name == "default" {
+        let arg = arg.ok_or_else(|| bad("argument required"))?;
+        return match value {
+            None | Some(Value::Null) => Ok(Some(Value::String(arg))),
+            value => Ok(value),
+        };
+    }
+    let value = value.ok_or_else(|| {
+        UnknownVariable(path.to_string(), whole.to_string())
+    })?;
+    let result = match name {
+        "quote" => shell_quote(&plain(path, &value)?),
+        "json" => to_string(&value).expect("can serialize json"),
+        "join" => {
+            let items = value.as_array().ok_or_else(|| bad("not an array"))?;
+            let items = items.iter()
+                .map(|x| plain(path, x))
+                .collect::<Result<Vec<_>, _>>()?;
+            items.join(arg.as_ref().map(|x| &x[..]).unwrap_or(","))
+        }
+        _ => return Err(UnknownFilter(name.to_string(), whole.to_string())),
+    };
+    Ok(Some(Value::String(result)))
+}
+
+/// Finds a value by dotted path like `nginx.port`, array items are indexed
+/// by number
+pub fn lookup<'x>(vars: &'x Value, path: &str) -> Option<&'x Value> {
+    let mut cur = vars;
+    for key in path.split('.') {
+        cur = match *cur {
+            Value::Object(ref map) => map.get(key)?,
+            Value::Array(ref items) => items.get(key.parse::<usize>().ok()?)?,
+            _ => return None,
+        };
+    }
+    Some(cur)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, from_str};
+    use super::Variables;
+
+    fn vars() -> Value {
+        from_str(r#"{
+            "port": 8080,
+            "nginx": {"hosts": ["a.example.com", "b.example.com"]},
+            "message": "it's done",
+            "empty": null
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn nested() {
+        let vars = vars();
+        let v = Variables::new().add("role", "web").add_vars(&vars);
+        assert_eq!(v.expand("{{role}}:{{ port }}").unwrap(), "web:8080");
+        assert_eq!(v.expand("{{ nginx.hosts.1 }}").unwrap(),
+                   "b.example.com");
+    }
+
+    #[test]
+    fn filters() {
+        let vars = vars();
+        let v = Variables::new().add_vars(&vars);
+        assert_eq!(v.expand(r#"{{ nginx.hosts | join(" ") }}"#).unwrap(),
+                   "a.example.com b.example.com");
+        assert_eq!(v.expand("{{ nginx | json }}").unwrap(),
+                   r#"{"hosts":["a.example.com","b.example.com"]}"#);
+        assert_eq!(v.expand("echo {{ message | quote }}").unwrap(),
+                   r"echo 'it'\''s done'");
+        assert_eq!(v.expand("{{ empty | default('x') }}").unwrap(), "x");
+        assert_eq!(v.expand(r#"{{ missing | default("a b") | quote }}"#)
+                   .unwrap(), "'a b'");
+    }
+
+    #[test]
+    fn errors() {
+        let vars = vars();
+        let v = Variables::new().add_vars(&vars);
+        assert_eq!(v.expand("x{{ missing }}").unwrap_err().to_string(),
+            r#"unknown variable "missing" in "{{ missing }}""#);
+        assert!(v.expand("{{ nginx }}").is_err());
+        assert!(v.expand("{{ empty }}").is_err());
+        assert!(v.expand("{{ port | upper }}").is_err());
+        assert!(v.expand("{{ port | default }}").is_err());
+        assert!(v.expand("{{ port | }}").is_err());
+    }
+
+    #[test]
+    fn not_expressions() {
+        let vars = vars();
+        let v = Variables::new().add_vars(&vars);
+        // go templates, e.g. of `docker inspect -f`
+        assert_eq!(v.expand("-f '{{json .State}}'").unwrap(),
+                   "-f '{{json .State}}'");
+        assert_eq!(v.expand("{{.Name}}:{{ port }}").unwrap(),
+                   "{{.Name}}:8080");
+        assert_eq!(v.expand("{{ printf \"%s\" .Id }}").unwrap(),
+                   "{{ printf \"%s\" .Id }}");
+        assert_eq!(v.expand("{{}}").unwrap(), "{{}}");
+        // explicit escape
+        assert_eq!(v.expand("{{ '{{' }} port }}").unwrap(), "{{ port }}");
+        assert_eq!(v.expand(r#"{{ "{{" }}json .{{ port }}}}"#).unwrap(),
+                   "{{json .8080}}");
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use humantime::{parse_duration, format_duration};
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+use quire::validate as V;
+
+use config::http::{self, Url};
+
+use apply::{Task, Error, Action};
+use apply::expand::Variables;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthCheck {
+    http: Option<String>,
+    tcp: Option<String>,
+    command: Option<Vec<String>>,
+    #[serde(deserialize_with="duration")]
+    timeout: Duration,
+    #[serde(deserialize_with="duration")]
+    interval: Duration,
+    retries: Option<u32>,
+}
+
+enum Probe {
+    Http { url: String, target: Url },
+    Tcp(String),
+    Command(Vec<String>),
+}
+
+fn duration<'x, D: Deserializer<'x>>(d: D) -> Result<Duration, D::Error> {
+    let value = String::deserialize(d)?;
+    parse_duration(&value).map_err(|e| D::Error::custom(
+        format!("bad duration {:?}: {}", value, e)))
+}
+
+impl HealthCheck {
+    pub fn config() -> V::Structure<'static> {
+        V::Structure::new()
+        .member("http", V::Scalar::new().optional())
+        .member("tcp", V::Scalar::new().optional())
+        .member("command", V::Sequence::new(V::Scalar::new()).optional())
+        .member("timeout", V::Scalar::new().default("60s"))
+        .member("interval", V::Scalar::new().default("1s"))
+        .member("retries", V::Numeric::new().min(1).optional())
+    }
+    fn probe(&self, variables: &Variables) -> Result<Probe, Error> {
+        match (&self.http, &self.tcp, &self.command) {
+            (&Some(ref url), &None, &None) => {
+                let url = variables.expand(url)?;
+                Ok(Probe::Http { target: http::parse_url(&url)?, url })
+            }
+            (&None, &Some(ref addr), &None) => {
+                Ok(Probe::Tcp(variables.expand(addr)?))
+            }
+            (&None, &None, &Some(ref cmd)) if cmd.len() > 0 => {
+                Ok(Probe::Command(cmd.iter()
+                    .map(|x| variables.expand(x))
+                    .collect::<Result<_, _>>()?))
+            }
+            _ => Err(format_err!("HealthCheck needs exactly one of \
+                                  `http`, `tcp` or non-empty `command`")),
+        }
+    }
+}
+
+fn check_command(task: &mut Task, args: &[String], timeout: Duration)
+    -> Result<(), Error>
+{
+    let mut cmd = Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    task.log.redirect_command(&mut cmd)?;
+    let mut child = cmd.spawn()?;
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            } else {
+                return Err(format_err!("{}", status));
+            }
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            return Err(format_err!("timed out after {}",
+                                   format_duration(timeout)));
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
+
+impl Probe {
+    fn check(&self, task: &mut Task, timeout: Duration) -> Result<(), Error> {
+        match *self {
+            Probe::Http { ref target, .. } => {
+                let headers = vec![("User-Agent", "verwalter_render")];
+                http::request("GET", target, headers, b"", timeout)
+                    .map_err(Into::into)
+            }
+            Probe::Tcp(ref addr) => {
+                http::connect(&addr[..], timeout).map(|_| ())
+                    .map_err(Into::into)
+            }
+            Probe::Command(ref args) => check_command(task, args, timeout),
+        }
+    }
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Probe::Http { ref url, .. } => write!(f, "http {}", url),
+            Probe::Tcp(ref addr) => write!(f, "tcp {}", addr),
+            Probe::Command(ref args) => write!(f, "command {:?}", args),
+        }
+    }
+}
+
+impl Action for HealthCheck {
+    fn execute(&self, task: &mut Task, variables: &Variables)
+        -> Result<(), Error>
+    {
+        let probe = self.probe(variables)
+            .map_err(|e| task.log.log_err(format_args!(
+                "HealthCheck: {}\n", e)))?;
+        task.log(format_args!("HealthCheck {} (timeout {}, interval {})\n",
+            probe, format_duration(self.timeout),
+            format_duration(self.interval)));
+        if task.dry_run {
+            return Ok(());
+        }
+        let deadline = Instant::now() + self.timeout;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let now = Instant::now();
+            let left = if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_millis(1)
+            };
+            let err = match probe.check(task, left) {
+                Ok(()) => {
+                    task.log(format_args!(
+                        "HealthCheck {} passed at attempt {}\n",
+                        probe, attempt));
+                    return Ok(());
+                }
+                Err(e) => e,
+            };
+            let out_of_retries = self.retries
+                .map(|r| attempt >= r).unwrap_or(false);
+            if out_of_retries || Instant::now() + self.interval >= deadline {
+                return Err(task.log.log_err(format_args!(
+                    "HealthCheck {} failed after {} attempt(s): {}\n",
+                    probe, attempt, err)));
+            }
+            task.log(format_args!("HealthCheck {} attempt {} failed: {}\n",
+                probe, attempt, err));
+            sleep(self.interval);
+        }
+    }
+}
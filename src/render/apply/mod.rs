@@ -1,27 +1,35 @@
 use std::fmt::{self, Arguments, Debug};
 use std::sync::Arc;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::Instant;
 
 use failure::Error;
 use serde::de::{Deserializer, Deserialize, Error as DeError, Visitor};
 use serde::de::{VariantAccess, EnumAccess};
+use serde_json::Value;
 use tempfile::NamedTempFile;
 use indexed_log as log;
 
 use config::Sandbox;
+use config::render_result::{ActionResult, CommandResult, Status, millis};
 use apply::expand::Variables;
 
-mod expand;
+pub mod expand;
+mod process;
+mod users;
 
 // commands
 pub mod cmd;
 pub mod condition;
 pub mod copy;
+pub mod health_check;
 pub mod clean_files;
 pub mod peek_log;
 pub mod root_command;
 pub mod shell;
 pub mod split_text;
+pub mod write_file;
 
 const COMMANDS: &'static [&'static str] = &[
     "RootCommand",
@@ -32,6 +40,8 @@ const COMMANDS: &'static [&'static str] = &[
     "SplitText",
     "CleanFiles",
     "PeekLog",
+    "HealthCheck",
+    "WriteFile",
 ];
 
 pub enum CommandName {
@@ -43,6 +53,8 @@ pub enum CommandName {
     SplitText,
     CleanFiles,
     PeekLog,
+    HealthCheck,
+    WriteFile,
 }
 
 pub struct NameVisitor;
@@ -55,6 +67,15 @@ pub struct Task<'a: 'b, 'b: 'c, 'c: 'd, 'd> {
     pub source: &'d Source,
     pub sandbox: &'d Sandbox,
     pub scratch: Scratch,
+    /// Files written by the command, reported in render result
+    pub written: Vec<PathBuf>,
+    /// Set when a command changed some file, visible to the next commands
+    /// of the same action
+    pub changed: bool,
+    /// Variables of the role
+    pub vars: &'d Value,
+    /// Variables of the last successful render, if known
+    pub previous_vars: Option<&'d Value>,
 }
 
 // TODO(tailhook) maybe make typemap or enum?
@@ -78,8 +99,9 @@ trait Action: Debug + Send + Sync {
         -> Result<(), Error>;
 }
 
+/// A command and its name used in error messages
 #[derive(Debug, Clone)]
-pub struct Command(Arc<Action>);
+pub struct Command(Arc<Action>, &'static str);
 
 pub enum Source {
     TmpFiles(HashMap<String, NamedTempFile>),
@@ -108,19 +130,22 @@ impl<'a> Visitor<'a> for NameVisitor {
             "SplitText" => SplitText,
             "CleanFiles" => CleanFiles,
             "PeekLog" => PeekLog,
+            "HealthCheck" => HealthCheck,
+            "WriteFile" => WriteFile,
             _ => return Err(E::custom("invalid command")),
         };
         Ok(res)
     }
 }
 
-fn decode<'x, T, V>(v: V)
+fn decode<'x, T, V>(v: V, name: &'static str)
     -> Result<Command, V::Error>
     where
         T: Action + Deserialize<'x> + 'static,
         V: VariantAccess<'x>,
 {
-    v.newtype_variant::<T>().map(|x| Command(Arc::new(x) as Arc<Action>))
+    v.newtype_variant::<T>()
+        .map(|x| Command(Arc::new(x) as Arc<Action>, name))
 }
 
 impl<'a> Visitor<'a> for CommandVisitor {
@@ -134,14 +159,19 @@ impl<'a> Visitor<'a> for CommandVisitor {
         use self::CommandName::*;
         let (tag, v) = data.variant()?;
         match tag {
-            RootCommand => decode::<root_command::RootCommand, _>(v),
-            Cmd => decode::<cmd::Cmd, _>(v),
-            Sh => decode::<shell::Sh, _>(v),
-            Copy => decode::<copy::Copy, _>(v),
-            Condition => decode::<condition::Condition, _>(v),
-            SplitText => decode::<split_text::SplitText, _>(v),
-            CleanFiles => decode::<clean_files::CleanFiles, _>(v),
-            PeekLog => decode::<peek_log::PeekLog, _>(v),
+            RootCommand => decode::<root_command::RootCommand, _>(v,
+                "RootCommand"),
+            Cmd => decode::<cmd::Cmd, _>(v, "Cmd"),
+            Sh => decode::<shell::Sh, _>(v, "Sh"),
+            Copy => decode::<copy::Copy, _>(v, "Copy"),
+            Condition => decode::<condition::Condition, _>(v, "Condition"),
+            SplitText => decode::<split_text::SplitText, _>(v, "SplitText"),
+            CleanFiles => decode::<clean_files::CleanFiles, _>(v,
+                "CleanFiles"),
+            PeekLog => decode::<peek_log::PeekLog, _>(v, "PeekLog"),
+            HealthCheck => decode::<health_check::HealthCheck, _>(v,
+                "HealthCheck"),
+            WriteFile => decode::<write_file::WriteFile, _>(v, "WriteFile"),
         }
     }
 }
@@ -160,12 +190,28 @@ impl Action for Command {
     fn pitch(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
-        self.0.pitch(task, variables)
+        let res = self.0.pitch(task, variables);
+        self.expand_error(task, res)
     }
     fn execute(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
-        self.0.execute(task, variables)
+        let res = self.0.execute(task, variables);
+        self.expand_error(task, res)
+    }
+}
+
+impl Command {
+    /// Logs variable expansion errors along with the command name
+    ///
+    /// Other errors are logged by the commands themselves.
+    fn expand_error(&self, task: &mut Task, res: Result<(), Error>)
+        -> Result<(), Error>
+    {
+        res.map_err(|e| match e.downcast::<expand::ExpandError>() {
+            Ok(e) => task.log.log_err(format_args!("{}: {}\n", self.1, e)),
+            Err(e) => e,
+        })
     }
 }
 
@@ -180,39 +226,96 @@ impl<'a, 'b, 'c, 'd> Task<'a, 'b, 'c, 'd> {
     }
 }
 
+fn command_result(cmd: &Command, start: Instant, res: &Result<(), Error>)
+    -> CommandResult
+{
+    CommandResult {
+        command: format!("{:?}", cmd.0),
+        status: if res.is_ok() { Status::Ok } else { Status::Failed },
+        duration_ms: millis(start.elapsed()),
+        error: res.as_ref().err().map(|e| e.to_string()),
+    }
+}
+
+fn apply_action(role: &String, aname: &String, commands: &Vec<Command>,
+    source: &Source, log: &mut log::Role, dry_run: bool, sandbox: &Sandbox,
+    role_vars: &Value, previous_vars: Option<&Value>,
+    result: &mut ActionResult)
+    -> Result<(), Error>
+{
+    let mut action = log.action(&aname);
+    let mut atasks = Vec::with_capacity(commands.len());
+    for cmd in commands {
+        let vars = expand::Variables::new()
+           .add("role", role)
+           .add_source(&source)
+           .add_vars(role_vars);
+        if cmd.needs_pitch() {
+            let start = Instant::now();
+            let mut task = Task {
+                runner: &aname,
+                log: &mut action,
+                scratch: Scratch::new(),
+                written: Vec::new(),
+                changed: false,
+                vars: role_vars,
+                dry_run, source, sandbox, previous_vars,
+            };
+            let res = cmd.pitch(&mut task, &vars);
+            if res.is_err() {
+                result.commands.push(command_result(cmd, start, &res));
+                return res;
+            }
+            atasks.push((cmd, task.scratch, vars));
+        } else {
+            atasks.push((cmd, Scratch::new(), vars));
+        }
+    }
+    for (cmd, scratch, vars) in atasks {
+        let start = Instant::now();
+        let mut task = Task {
+            runner: &aname,
+            log: &mut action,
+            written: Vec::new(),
+            changed: result.changed,
+            vars: role_vars,
+            dry_run, source, sandbox, scratch, previous_vars,
+        };
+        let res = cmd.execute(&mut task, &vars);
+        result.changed = task.changed;
+        result.files.extend(task.written.drain(..));
+        result.commands.push(command_result(cmd, start, &res));
+        res?;
+    }
+    Ok(())
+}
+
+/// Applies actions, appending result of each one to `results`
 pub fn apply_list(role: &String,
     actions: Vec<(String, Vec<Command>, Source)>,
     log: &mut log::Role, dry_run: bool,
-    sandbox: &Sandbox)
+    sandbox: &Sandbox, vars: &Value, previous_vars: Option<&Value>,
+    results: &mut Vec<ActionResult>)
     -> Result<(), Error>
 {
     for &(ref aname, ref commands, ref source) in &actions {
-        let mut action = log.action(&aname);
-        let mut atasks = Vec::with_capacity(commands.len());
-        for cmd in commands {
-            let vars = expand::Variables::new()
-               .add("role", role)
-               .add_source(&source);
-            if cmd.needs_pitch() {
-                let mut task = Task {
-                    runner: &aname,
-                    log: &mut action,
-                    scratch: Scratch::new(),
-                    dry_run, source, sandbox,
-                };
-                cmd.pitch(&mut task, &vars)?;
-                atasks.push((cmd, task.scratch, vars));
-            } else {
-                atasks.push((cmd, Scratch::new(), vars));
-            }
-        }
-        for (cmd, scratch, vars) in atasks {
-            cmd.execute(&mut Task {
-                runner: &aname,
-                log: &mut action,
-                dry_run, source, sandbox, scratch,
-            }, &vars)?;
+        let start = Instant::now();
+        let mut result = ActionResult {
+            name: aname.clone(),
+            status: Status::Ok,
+            duration_ms: 0,
+            files: Vec::new(),
+            changed: false,
+            commands: Vec::new(),
+        };
+        let res = apply_action(role, aname, commands, source, log,
+            dry_run, sandbox, vars, previous_vars, &mut result);
+        result.duration_ms = millis(start.elapsed());
+        if res.is_err() {
+            result.status = Status::Failed;
         }
+        results.push(result);
+        res?;
     }
     Ok(())
 }
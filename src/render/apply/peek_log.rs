@@ -19,7 +19,7 @@ impl Action for PeekLog {
     fn execute(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
-        let expanded = variables.expand(&self.0);
+        let expanded = variables.expand(&self.0)?;
         let path = Path::new(&expanded);
         if path.is_absolute() {
             task.log(
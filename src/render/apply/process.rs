@@ -0,0 +1,272 @@
+//! Options shared by `Cmd`, `Sh` and `RootCommand`
+//!
+//! Each of the commands may be written either in a short form (a list of
+//! arguments or a script), or as a mapping with the `command` key and
+//! options: `env`, `cwd`, `timeout` and `user`.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Child, ExitStatus};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use humantime::{parse_duration, format_duration};
+use libc;
+use quire::ast::Ast;
+use quire::{Pos, ErrorCollector};
+use quire::validate as V;
+use serde::de::{Deserialize, Deserializer, Error as DeError};
+
+use apply::{Task, Error};
+use apply::expand::{Variables, ExpandError};
+use apply::users::user_and_group;
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub env: BTreeMap<String, String>,
+    pub cwd: Option<String>,
+    pub timeout: Option<Duration>,
+    pub user: Option<String>,
+}
+
+/// A command with options, see module docs
+#[derive(Debug, Clone)]
+pub struct Spec<T> {
+    pub command: T,
+    pub options: Options,
+}
+
+/// Validates either a short form with `short` or a mapping with options
+pub struct ShortOrFull<T> {
+    short: T,
+    full: V::Structure<'static>,
+}
+
+/// Options with variables expanded, used for logging
+struct Expanded<'a> {
+    env: BTreeMap<&'a str, String>,
+    cwd: Option<String>,
+    timeout: Option<Duration>,
+    user: Option<String>,
+}
+
+pub fn validator<T, F>(command: F) -> ShortOrFull<T>
+    where T: V::Validator + 'static, F: Fn() -> T,
+{
+    ShortOrFull {
+        short: command(),
+        full: V::Structure::new()
+            .member("command", command())
+            .member("env",
+                V::Mapping::new(V::Scalar::new(), V::Scalar::new()))
+            .member("cwd", V::Scalar::new().optional())
+            .member("timeout", V::Scalar::new().optional())
+            .member("user", V::Scalar::new().optional()),
+    }
+}
+
+impl<T: V::Validator> V::Validator for ShortOrFull<T> {
+    fn default(&self, pos: Pos) -> Option<Ast> {
+        self.short.default(pos)
+    }
+    fn validate(&self, ast: Ast, err: &ErrorCollector) -> Ast {
+        match ast {
+            ast @ Ast::Map(..) => self.full.validate(ast, err),
+            ast => self.short.validate(ast, err),
+        }
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Spec<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Spec<T>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Form<T> {
+            Short(T),
+            Full {
+                command: T,
+                #[serde(default)]
+                env: BTreeMap<String, String>,
+                cwd: Option<String>,
+                timeout: Option<String>,
+                user: Option<String>,
+            },
+        }
+        match Form::deserialize(d)? {
+            Form::Short(command) => Ok(Spec {
+                command,
+                options: Options::default(),
+            }),
+            Form::Full { command, env, cwd, timeout, user } => {
+                let timeout = match timeout {
+                    Some(ref value) => Some(parse_duration(value)
+                        .map_err(|e| D::Error::custom(format!(
+                            "bad timeout {:?}: {}", value, e)))?),
+                    None => None,
+                };
+                Ok(Spec {
+                    command,
+                    options: Options { env, cwd, timeout, user },
+                })
+            }
+        }
+    }
+}
+
+impl Options {
+    fn expand<'a>(&'a self, variables: &Variables)
+        -> Result<Expanded<'a>, ExpandError>
+    {
+        let mut env = BTreeMap::new();
+        for (key, value) in &self.env {
+            env.insert(&key[..], variables.expand(value)?);
+        }
+        let cwd = match self.cwd {
+            Some(ref cwd) => Some(variables.expand(cwd)?),
+            None => None,
+        };
+        let user = match self.user {
+            Some(ref user) => Some(variables.expand(user)?),
+            None => None,
+        };
+        Ok(Expanded { env, cwd, timeout: self.timeout, user })
+    }
+}
+
+impl<'a> fmt::Display for Expanded<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.env.is_empty() {
+            write!(f, " env: {:?}", self.env)?;
+        }
+        if let Some(ref cwd) = self.cwd {
+            write!(f, " cwd: {:?}", cwd)?;
+        }
+        if let Some(timeout) = self.timeout {
+            write!(f, " timeout: {}", format_duration(timeout))?;
+        }
+        if let Some(ref user) = self.user {
+            write!(f, " user: {:?}", user)?;
+        }
+        Ok(())
+    }
+}
+
+enum Outcome {
+    Exited(ExitStatus),
+    /// Timed out and the process group is killed
+    Killed,
+    /// Timed out, but the process group can't be killed
+    KillFailed(io::Error),
+}
+
+/// Kills the process group led by `pid`
+///
+/// Commands run by sudo belong to root, so they are killed by `sudo kill`.
+fn kill_group(pid: u32, sudo: bool) -> io::Result<()> {
+    if sudo {
+        let status = Command::new("/usr/bin/sudo")
+            .arg("-n").arg("kill").arg("-KILL")
+            .arg("--").arg(format!("-{}", pid))
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::Other,
+                format!("sudo kill {}", status)));
+        }
+        return Ok(());
+    }
+    if unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn wait(child: &mut Child, timeout: Option<Duration>, sudo: bool)
+    -> io::Result<Outcome>
+{
+    let deadline = match timeout {
+        Some(timeout) => Instant::now() + timeout,
+        None => return child.wait().map(Outcome::Exited),
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Outcome::Exited(status));
+        }
+        if Instant::now() >= deadline {
+            // the command is a leader of the process group
+            let result = kill_group(child.id(), sudo);
+            child.kill().ok();
+            child.wait()?;
+            return match result {
+                Ok(()) => Ok(Outcome::Killed),
+                Err(e) => Ok(Outcome::KillFailed(e)),
+            };
+        }
+        sleep(Duration::from_millis(100));
+    }
+}
+
+/// Runs the command with options, logging it as `name`
+///
+/// If `use_uid` is false the `user` option should already be handled by
+/// the caller (i.e. passed to sudo), and the command is killed on timeout
+/// via sudo too.
+pub fn run(task: &mut Task, name: &str, mut cmd: Command,
+    options: &Options, use_uid: bool, variables: &Variables)
+    -> Result<(), Error>
+{
+    let expanded = options.expand(variables)?;
+    task.log(format_args!("{} {:#?}{}\n", name, cmd, expanded));
+    for (key, value) in &expanded.env {
+        cmd.env(key, value);
+    }
+    if let Some(ref cwd) = expanded.cwd {
+        cmd.current_dir(cwd);
+    }
+    if use_uid {
+        if let Some(ref user) = expanded.user {
+            let (uid, gid) = user_and_group(user)
+                .map_err(|e| task.log.log_err(format_args!(
+                    "{} {:#?}: {}\n", name, cmd, e)))?;
+            cmd.uid(uid);
+            cmd.gid(gid);
+        }
+    }
+    if expanded.timeout.is_some() {
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::setpgid(0, 0);
+                Ok(())
+            });
+        }
+    }
+    if task.dry_run {
+        return Ok(());
+    }
+    task.log.redirect_command(&mut cmd)?;
+    let mut child = cmd.spawn()
+        .map_err(|e| task.log.log_err(format_args!(
+            "{} {:#?} failed to start: {}\n", name, cmd, e)))?;
+    match wait(&mut child, expanded.timeout, !use_uid) {
+        Ok(Outcome::Exited(s)) if s.success() => Ok(()),
+        Ok(Outcome::Exited(s)) => {
+            Err(task.log.log_err(format_args!("{} {:#?}: {}\n", name, cmd, s)))
+        }
+        Ok(Outcome::Killed) => {
+            Err(task.log.log_err(format_args!(
+                "{} {:#?}: timed out after {}, killed\n", name, cmd,
+                format_duration(expanded.timeout.unwrap()))))
+        }
+        Ok(Outcome::KillFailed(e)) => {
+            Err(task.log.log_err(format_args!(
+                "{} {:#?}: timed out after {}, \
+                can't kill process group: {}\n", name, cmd,
+                format_duration(expanded.timeout.unwrap()), e)))
+        }
+        Err(e) => {
+            Err(task.log.log_err(format_args!(
+                "{} {:#?}: error waiting: {}\n", name, cmd, e)))
+        }
+    }
+}
@@ -5,14 +5,14 @@ use quire::validate as V;
 
 use apply::{Task, Error, Action};
 use apply::expand::Variables;
+use apply::process::{self, Spec, ShortOrFull};
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct RootCommand(Vec<String>);
+pub struct RootCommand(Spec<Vec<String>>);
 
 impl RootCommand {
-    pub fn config() -> V::Sequence<'static> {
-        V::Sequence::new(V::Scalar::new())
-        //.from_scalar(..)
+    pub fn config() -> ShortOrFull<V::Sequence<'static>> {
+        process::validator(|| V::Sequence::new(V::Scalar::new()))
     }
 }
 
@@ -20,33 +20,37 @@ impl Action for RootCommand {
     fn execute(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
+        let args = &self.0.command;
+        if args.is_empty() {
+            return Err(task.log.log_err(format_args!(
+                "RootCommand: command must not be empty\n")));
+        }
         let uid = unsafe { geteuid() };
         let mut cmd = if uid != 0 {
             let mut cmd = Command::new("/usr/bin/sudo");
             cmd.arg("-n");
-            cmd.arg(&variables.expand(&self.0[0]));
+            if let Some(ref user) = self.0.options.user {
+                cmd.arg("-u");
+                cmd.arg(variables.expand(user)?);
+            }
+            cmd.arg("--");
+            // sudo resets the environment, so it's passed to `env` instead
+            if !self.0.options.env.is_empty() {
+                cmd.arg("/usr/bin/env");
+                for (key, value) in &self.0.options.env {
+                    cmd.arg(format!("{}={}", key, variables.expand(value)?));
+                }
+            }
+            cmd.arg(&variables.expand(&args[0])?);
             cmd
         } else {
-            Command::new(&self.0[0])
+            Command::new(&args[0])
         };
-        for arg in &self.0[1..] {
-            cmd.arg(variables.expand(arg));
-        }
-        // TODO(tailhook) redirect output
-        task.log(format_args!("RootCommand {:#?}\n", cmd));
-        if !task.dry_run {
-            try!(task.log.redirect_command(&mut cmd));
-            cmd.status()
-            .map_err(|e| {
-                task.log.log_err(format_args!(
-                    "RootCommand {:#?} failed to start: {}\n", cmd, e))
-            }).and_then(|s| if s.success() { Ok(()) } else {
-                Err(task.log.log_err(
-                    format_args!("RootCommand {:#?}: {}\n", cmd, s)))
-            })
-        } else {
-            Ok(())
+        for arg in &args[1..] {
+            cmd.arg(variables.expand(arg)?);
         }
+        process::run(task, "RootCommand", cmd, &self.0.options,
+            uid == 0, variables)
     }
 
 }
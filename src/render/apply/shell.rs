@@ -4,13 +4,14 @@ use quire::validate as V;
 
 use apply::{Task, Error, Action};
 use apply::expand::Variables;
+use apply::process::{self, Spec, ShortOrFull};
 
 #[derive(Debug, Clone, Deserialize)]
-pub struct Sh(String);
+pub struct Sh(Spec<String>);
 
 impl Sh {
-    pub fn config() -> V::Scalar {
-        V::Scalar::new()
+    pub fn config() -> ShortOrFull<V::Scalar> {
+        process::validator(|| V::Scalar::new())
     }
 }
 
@@ -20,21 +21,8 @@ impl Action for Sh {
     {
         let mut cmd = Command::new("sh");
         cmd.arg("-exc");
-        cmd.arg(variables.expand(&self.0));
-        // TODO(tailhook) redirect output
-        task.log(format_args!("Sh {:#?}\n", cmd));
-        if !task.dry_run {
-            try!(task.log.redirect_command(&mut cmd));
-            cmd.status()
-            .map_err(|e| {
-                task.log.log_err(format_args!(
-                    "Sh {:#?} failed to start: {}\n", cmd, e))
-            }).and_then(|s| if s.success() { Ok(()) } else {
-                Err(task.log.log_err(format_args!("Sh {:#?}: {}\n", cmd, s)))
-            })
-        } else {
-            Ok(())
-        }
+        cmd.arg(variables.expand(&self.0.command)?);
+        process::run(task, "Sh", cmd, &self.0.options, true, variables)
     }
 
 }
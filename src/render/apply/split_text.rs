@@ -2,7 +2,7 @@ use std::collections::HashSet;
 use std::io::{BufRead, BufReader, Write, BufWriter};
 use std::fs::{self, File, remove_file};
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use capturing_glob::{self, glob_with, MatchOptions};
 use failure::ResultExt;
@@ -54,7 +54,8 @@ fn open_file(dest: &str, name: &str) -> Result<File, Error> {
     Ok(File::create(&tmpdest).context(tmpdest.display().to_string())?)
 }
 
-fn commit_file(dest: &str, name: &str, mode: Option<u32>) -> Result<(), Error>
+fn commit_file(dest: &str, name: &str, mode: Option<u32>)
+    -> Result<PathBuf, Error>
 {
     let fpath = capturing_glob::Pattern::new(dest)
         .map_err(|_| format_err!(
@@ -77,7 +78,7 @@ fn commit_file(dest: &str, name: &str, mode: Option<u32>) -> Result<(), Error>
     }
     fs::rename(&tmpdest, &fpath)
         .map_err(|e| format_err!("can't rename: {}", e))?;
-    Ok(())
+    Ok(PathBuf::from(fpath))
 }
 
 
@@ -85,8 +86,8 @@ impl Action for SplitText {
     fn execute(&self, task: &mut Task, variables: &Variables)
         -> Result<(), Error>
     {
-        let src_fn = variables.expand(&self.src);
-        let dest = variables.expand(&self.dest);
+        let src_fn = variables.expand(&self.src)?;
+        let dest = variables.expand(&self.dest)?;
         task.log(format_args!("SplitText {{ src: {:?}, dest: {:?} }}\n",
             &src_fn, &dest));
 
@@ -108,7 +109,8 @@ impl Action for SplitText {
                     }
 
                     if let Some(cur_name) = name.take() {
-                        commit_file(&dest, &cur_name, self.mode)?;
+                        task.written.push(
+                            commit_file(&dest, &cur_name, self.mode)?);
                     }
                     file = Some(BufWriter::new(open_file(&dest, sect)?));
                     name = Some(sect.to_string());
@@ -126,7 +128,7 @@ impl Action for SplitText {
                 }
             }
             if let Some(cur_name) = name.take() {
-                commit_file(&dest, &cur_name, self.mode)?;
+                task.written.push(commit_file(&dest, &cur_name, self.mode)?);
             }
             let items = glob_with(&dest, &MatchOptions {
                 case_sensitive: true,
@@ -0,0 +1,51 @@
+use std::ffi::CString;
+
+use libc::{getpwnam, getpwuid, getgrnam, uid_t, gid_t};
+
+use apply::Error;
+
+
+/// Returns uid for a user name or a numeric id
+pub fn user_id(name: &str) -> Result<uid_t, Error> {
+    if let Ok(uid) = name.parse() {
+        return Ok(uid);
+    }
+    let cname = CString::new(name)
+        .map_err(|_| format_err!("invalid user name {:?}", name))?;
+    // render is single-threaded, so non-reentrant version is fine
+    let pw = unsafe { getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        bail!("no such user {:?}", name);
+    }
+    Ok(unsafe { (*pw).pw_uid })
+}
+
+/// Returns gid for a group name or a numeric id
+pub fn group_id(name: &str) -> Result<gid_t, Error> {
+    if let Ok(gid) = name.parse() {
+        return Ok(gid);
+    }
+    let cname = CString::new(name)
+        .map_err(|_| format_err!("invalid group name {:?}", name))?;
+    let gr = unsafe { getgrnam(cname.as_ptr()) };
+    if gr.is_null() {
+        bail!("no such group {:?}", name);
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+/// Returns uid and primary gid of a user name or a numeric id
+pub fn user_and_group(name: &str) -> Result<(uid_t, gid_t), Error> {
+    let pw = match name.parse() {
+        Ok(uid) => unsafe { getpwuid(uid) },
+        Err(_) => {
+            let cname = CString::new(name)
+                .map_err(|_| format_err!("invalid user name {:?}", name))?;
+            unsafe { getpwnam(cname.as_ptr()) }
+        }
+    };
+    if pw.is_null() {
+        bail!("no such user {:?}", name);
+    }
+    Ok(unsafe { ((*pw).pw_uid, (*pw).pw_gid) })
+}
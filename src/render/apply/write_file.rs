@@ -0,0 +1,309 @@
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use libc::{chown, uid_t, gid_t};
+use quire::validate as V;
+
+use apply::{Task, Error, Action};
+use apply::expand::Variables;
+use apply::users::{user_id, group_id};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct WriteFile {
+    src: Option<String>,
+    dest: String,
+    mode: Option<u32>,
+    owner: Option<String>,
+    group: Option<String>,
+    mkdir: bool,
+}
+
+impl WriteFile {
+    pub fn config() -> V::Structure<'static> {
+        V::Structure::new()
+        .member("src", V::Scalar::new().optional())
+        .member("dest", V::Scalar::new())
+        .member("mode", V::Numeric::new().optional())
+        .member("owner", V::Scalar::new().optional())
+        .member("group", V::Scalar::new().optional())
+        .member("mkdir", V::Scalar::new().default("false"))
+    }
+}
+
+/// Checks whether file needs to be rewritten
+///
+/// Only contents is compared, permissions are fixed in place
+fn is_changed(dest: &Path, data: &[u8]) -> io::Result<bool> {
+    match fs::read(dest) {
+        Ok(old) => Ok(old != data),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+fn set_owner(path: &Path, uid: Option<uid_t>, gid: Option<gid_t>)
+    -> io::Result<()>
+{
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                                    "path contains null byte"))?;
+    // -1 keeps the value unchanged
+    let rc = unsafe {
+        chown(cpath.as_ptr(), uid.unwrap_or(!0), gid.unwrap_or(!0))
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl WriteFile {
+    /// Writes `data` to `dest` via a temporary file
+    ///
+    /// Mode and owner of the replaced file are kept unless overriden.
+    /// Temporary file is created with `0600` and gets its owner and mode
+    /// before any data is written, so secrets are never exposed.
+    fn write(&self, dest: &Path, data: &[u8], mode: Option<u32>,
+        uid: Option<uid_t>, gid: Option<gid_t>)
+        -> Result<(), Error>
+    {
+        let fname = dest.file_name()
+            .and_then(|x| x.to_str())
+            .ok_or_else(|| format_err!(
+                "destination must be filename not a directory: {:?}",
+                dest))?;
+        if self.mkdir {
+            if let Some(dir) = dest.parent() {
+                fs::create_dir_all(dir)
+                    .map_err(|e| format_err!("can't create {:?}: {}",
+                                             dir, e))?;
+            }
+        }
+        let old = fs::metadata(dest).ok();
+        let mode = mode.or_else(|| {
+            old.as_ref().map(|m| m.permissions().mode() & 0o7777)
+        });
+        let uid = uid.or_else(|| old.as_ref().map(|m| m.uid()));
+        let gid = gid.or_else(|| old.as_ref().map(|m| m.gid()));
+        let tmpdest = dest.with_file_name(format!(".tmp.{}", fname));
+        // leftover of a crashed write may have any permissions
+        match fs::remove_file(&tmpdest) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format_err!("can't remove {:?}: {}",
+                                             tmpdest, e)),
+        }
+        let mut file = OpenOptions::new()
+            .write(true).create_new(true).mode(0o600)
+            .open(&tmpdest)
+            .map_err(|e| format_err!("can't create {:?}: {}", tmpdest, e))?;
+        let meta = file.metadata()
+            .map_err(|e| format_err!("can't stat {:?}: {}", tmpdest, e))?;
+        // chown may reset setuid bits, so it goes before chmod
+        let uid = uid.filter(|&x| x != meta.uid());
+        let gid = gid.filter(|&x| x != meta.gid());
+        set_owner(&tmpdest, uid, gid)
+            .map_err(|e| format_err!("can't set owner: {}", e))?;
+        if let Some(mode) = mode {
+            fs::set_permissions(&tmpdest, fs::Permissions::from_mode(mode))
+                .map_err(|e| format_err!("can't set mode: {}", e))?;
+        }
+        file.write_all(data)
+            // contents must be on disk before the rename is
+            .and_then(|()| file.sync_all())
+            .map_err(|e| format_err!("can't write {:?}: {}", tmpdest, e))?;
+        fs::rename(&tmpdest, dest)
+            .map_err(|e| format_err!("can't rename {:?}: {}", tmpdest, e))?;
+        Ok(())
+    }
+
+    fn fix_permissions(&self, dest: &Path, meta: &fs::Metadata,
+        mode: Option<u32>, uid: Option<uid_t>, gid: Option<gid_t>)
+        -> Result<bool, Error>
+    {
+        let mut fixed = false;
+        if let Some(mode) = mode {
+            if meta.permissions().mode() & 0o7777 != mode {
+                fs::set_permissions(dest, fs::Permissions::from_mode(mode))
+                    .map_err(|e| format_err!("can't set mode: {}", e))?;
+                fixed = true;
+            }
+        }
+        let uid = uid.filter(|&x| x != meta.uid());
+        let gid = gid.filter(|&x| x != meta.gid());
+        if uid.is_some() || gid.is_some() {
+            set_owner(dest, uid, gid)
+                .map_err(|e| format_err!("can't set owner: {}", e))?;
+            fixed = true;
+        }
+        Ok(fixed)
+    }
+}
+
+impl Action for WriteFile {
+    fn execute(&self, task: &mut Task, variables: &Variables)
+        -> Result<(), Error>
+    {
+        let dest = PathBuf::from(variables.expand(&self.dest)?);
+        task.log(format_args!("WriteFile {{ src: {:?}, dest: {:?}, \
+            mode: {:?}, owner: {:?}, group: {:?} }}\n",
+            &self.src, dest, self.mode, self.owner, self.group));
+        let src = match self.src {
+            Some(ref src) => variables.expand(src)?,
+            None => variables.expand("{{ tmp_file }}")
+                .map_err(|_| task.log.log_err(format_args!(
+                    "WriteFile {:?}: `src` is required unless there is \
+                    exactly one template\n", dest)))?,
+        };
+        let owner = match self.owner {
+            Some(ref name) => Some(variables.expand(name)?),
+            None => None,
+        };
+        let group = match self.group {
+            Some(ref name) => Some(variables.expand(name)?),
+            None => None,
+        };
+        let uid = match owner {
+            Some(ref name) => Some(user_id(name)
+                .map_err(|e| task.log.log_err(format_args!(
+                    "WriteFile {:?}: {}\n", dest, e)))?),
+            None => None,
+        };
+        let gid = match group {
+            Some(ref name) => Some(group_id(name)
+                .map_err(|e| task.log.log_err(format_args!(
+                    "WriteFile {:?}: {}\n", dest, e)))?),
+            None => None,
+        };
+        let data = fs::read(&src)
+            .map_err(|e| task.log.log_err(format_args!(
+                "WriteFile {:?}: can't read {:?}: {}\n", dest, src, e)))?;
+        let changed = is_changed(&dest, &data)
+            .map_err(|e| task.log.log_err(format_args!(
+                "WriteFile {:?}: can't read destination: {}\n", dest, e)))?;
+        if !changed {
+            let meta = fs::metadata(&dest)
+                .map_err(|e| task.log.log_err(format_args!(
+                    "WriteFile {:?}: {}\n", dest, e)))?;
+            if task.dry_run {
+                task.log(format_args!("WriteFile {:?}: unchanged\n", dest));
+                return Ok(());
+            }
+            let fixed = self.fix_permissions(&dest, &meta,
+                    self.mode, uid, gid)
+                .map_err(|e| task.log.log_err(format_args!(
+                    "WriteFile {:?}: {}\n", dest, e)))?;
+            if fixed {
+                task.log(format_args!(
+                    "WriteFile {:?}: unchanged, permissions updated\n",
+                    dest));
+            } else {
+                task.log(format_args!("WriteFile {:?}: unchanged\n", dest));
+            }
+            return Ok(());
+        }
+        task.changed = true;
+        if task.dry_run {
+            task.log(format_args!("WriteFile {:?}: changed\n", dest));
+            return Ok(());
+        }
+        self.write(&dest, &data, self.mode, uid, gid)
+            .map_err(|e| task.log.log_err(format_args!(
+                "WriteFile {:?}: {}\n", dest, e)))?;
+        task.log(format_args!("WriteFile {:?}: changed\n", dest));
+        task.written.push(dest);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+    use super::{WriteFile, is_changed};
+
+    fn cmd(mkdir: bool) -> WriteFile {
+        WriteFile {
+            src: None, dest: String::new(),
+            mode: None, owner: None, group: None,
+            mkdir,
+        }
+    }
+
+    fn mode(path: &::std::path::Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn unchanged() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("file");
+        assert!(is_changed(&dest, b"hello").unwrap());
+        fs::write(&dest, b"hello").unwrap();
+        assert!(!is_changed(&dest, b"hello").unwrap());
+        assert!(is_changed(&dest, b"world").unwrap());
+    }
+
+    #[test]
+    fn changed() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("file");
+        fs::write(&dest, b"hello").unwrap();
+        cmd(false).write(&dest, b"world", Some(0o640), None, None).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"world");
+        assert_eq!(mode(&dest), 0o640);
+        assert!(!dir.path().join(".tmp.file").exists());
+    }
+
+    #[test]
+    fn new_file_is_private() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("key");
+        cmd(false).write(&dest, b"secret", None, None, None).unwrap();
+        assert_eq!(mode(&dest), 0o600);
+    }
+
+    #[test]
+    fn mkdir() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("a/b/file");
+        assert!(cmd(false).write(&dest, b"x", None, None, None).is_err());
+        cmd(true).write(&dest, b"x", None, None, None).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"x");
+    }
+
+    #[test]
+    fn mode_preserved() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("file");
+        fs::write(&dest, b"hello").unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o604))
+            .unwrap();
+        cmd(false).write(&dest, b"world", None, None, None).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"world");
+        assert_eq!(mode(&dest), 0o604);
+    }
+
+    #[test]
+    fn fix_permissions() {
+        let dir = tempdir().unwrap();
+        let dest = dir.path().join("file");
+        fs::write(&dest, b"hello").unwrap();
+        fs::set_permissions(&dest, fs::Permissions::from_mode(0o644))
+            .unwrap();
+        let meta = fs::metadata(&dest).unwrap();
+        assert!(!cmd(false).fix_permissions(&dest, &meta, Some(0o644),
+                                            None, None).unwrap());
+        assert!(cmd(false).fix_permissions(&dest, &meta, Some(0o600),
+                                           None, None).unwrap());
+        assert_eq!(mode(&dest), 0o600);
+    }
+}
@@ -4,6 +4,7 @@ extern crate dir_signature;
 extern crate error_chain;
 extern crate handlebars;
 extern crate hex;
+extern crate humantime;
 extern crate libc;
 extern crate quire;
 extern crate rand;
@@ -27,17 +28,21 @@ extern crate verwalter_config as config;
 mod apply;
 mod render;
 mod renderfile;
+mod structured;
 
-use std::io::{BufReader};
+use std::io::{BufReader, Write, stdin};
 use std::fs::File;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::path::{PathBuf};
 use std::process::exit;
+use std::time::Instant;
 
 use argparse::{ArgumentParser, Parse, ParseOption, StoreTrue, FromCommandLine};
 use serde_json::{Value, from_str as parse_json};
 
 use indexed_log::Index;
-use config::Sandbox;
+use config::{Sandbox, RenderInput, RenderResult};
+use config::render_result::{Status, millis};
 
 struct ParseJson(Value);
 
@@ -47,6 +52,43 @@ impl FromCommandLine for ParseJson {
     }
 }
 
+/// Reports result to the daemon (if requested) and exits
+struct Reporter {
+    fd: Option<RawFd>,
+    start: Instant,
+    actions: Vec<config::render_result::ActionResult>,
+}
+
+impl Reporter {
+    fn new(fd: Option<RawFd>) -> Reporter {
+        if let Some(fd) = fd {
+            // commands run by us must not keep the pipe open
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        Reporter {
+            fd,
+            start: Instant::now(),
+            actions: Vec::new(),
+        }
+    }
+    fn exit(self, code: i32, error: Option<String>) -> ! {
+        if let Some(fd) = self.fd {
+            let result = RenderResult {
+                status: if code == 0 { Status::Ok } else { Status::Failed },
+                duration_ms: millis(self.start.elapsed()),
+                error: error,
+                actions: self.actions,
+            };
+            let mut file = unsafe { File::from_raw_fd(fd) };
+            serde_json::to_writer(&mut file, &result)
+                .map_err(|e| e.into())
+                .and_then(|()| file.flush())
+                .map_err(|e| eprintln!("Error writing result: {}", e))
+                .ok();
+        }
+        exit(code);
+    }
+}
 
 fn main() {
     let mut vars = ParseJson(Value::Null);
@@ -57,6 +99,9 @@ fn main() {
     let mut config_dir = PathBuf::from("/etc/verwalter");
     let mut check_dir = None::<PathBuf>;
     let mut dry_run = false;
+    let mut input_stdin = false;
+    let mut result_fd = None::<RawFd>;
+    let mut previous_vars = None::<Value>;
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("
@@ -94,14 +139,36 @@ fn main() {
         ap.refer(&mut config_dir)
             .add_option(&["--config-dir"], Parse,
                 "Directory of configuration files (default /etc/verwalter)");
+        ap.refer(&mut input_stdin)
+            .add_option(&["--input-stdin"], StoreTrue, "
+                Read variables and schedule from stdin as
+                `{\"vars\": .., \"schedule\": ..}`. Overrides `vars`,
+                `--vars-file`, `--schedule` and `--schedule-file`.");
+        ap.refer(&mut result_fd)
+            .add_option(&["--result-fd"], ParseOption, "
+                Write JSON-encoded result of the render to this file
+                descriptor");
         ap.parse_args_or_exit();
     }
+    let mut reporter = Reporter::new(result_fd);
     if check_dir.is_some() {
         dry_run = true;
     }
     let mut log = Index::new(&log_dir, dry_run);
 
-    if let Some(filename) = vars_file {
+    if input_stdin {
+        let input: RenderInput = match serde_json::from_reader(stdin()) {
+            Ok(input) => input,
+            Err(e) => {
+                eprintln!("Error reading input: {}", e);
+                reporter.exit(3, Some(format!("bad input: {}", e)));
+            }
+        };
+        vars = ParseJson(Value::Object(input.vars));
+        previous_vars = input.previous_vars.map(Value::Object);
+        schedule = ParseJson(input.schedule);
+        schedule_file = None;
+    } else if let Some(filename) = vars_file {
         let res = File::open(&filename)
             .map(|f| BufReader::new(f)).map_err(|e| e.to_string())
             .and_then(|f| serde_json::from_reader(f)
@@ -110,7 +177,8 @@ fn main() {
             Ok(json) => ParseJson(json),
             Err(e) => {
                 eprintln!("Error reading {:?}: {}", filename, e);
-                exit(3);
+                reporter.exit(3,
+                    Some(format!("error reading {:?}: {}", filename, e)));
             }
         }
     };
@@ -119,7 +187,7 @@ fn main() {
         ParseJson(v) => {
             eprintln!("`vars` argument or contents of `--vars-file` should be \
                 an object, got {:.16} instead", format!("{:?}", v));
-            exit(3);
+            reporter.exit(3, Some("vars must be an object".into()));
         }
     };
 
@@ -128,22 +196,23 @@ fn main() {
     } else {
         let id = match vars.get("deployment_id").and_then(|x| x.as_str()) {
             Some(x) => x.to_string(),
-            None => exit(3),
+            None => reporter.exit(3, Some("no deployment_id".into())),
         };
         let template = match vars.get("template").and_then(|x| x.as_str()) {
             Some(x) => x.to_string(),
-            None => exit(4),
+            None => reporter.exit(4, Some("no `template` key found".into())),
         };
         match vars.get("verwalter_version").and_then(|x| x.as_str()) {
             Some(concat!("v", env!("CARGO_PKG_VERSION"))) => {},
-            Some(_) => exit(5),
-            None => exit(3),
+            Some(_) => reporter.exit(5, Some("version mismatch".into())),
+            None => reporter.exit(3, Some("no verwalter_version".into())),
         };
         let sandbox = match Sandbox::parse_all(&config_dir.join("sandbox")) {
             Ok(cfg) => cfg,
             Err(e) => {
                 eprintln!("Error reading sandbox config: {}", e);
-                exit(3);
+                reporter.exit(3,
+                    Some(format!("error reading sandbox config: {}", e)));
             }
         };
         (id, config_dir.join("templates").join(template), sandbox)
@@ -151,7 +220,7 @@ fn main() {
 
     let role = match vars.get("role").and_then(|x| x.as_str()) {
         Some(x) => x.to_string(),
-        None => exit(3),
+        None => reporter.exit(3, Some("no role".into())),
     };
     if let Some(filename) = schedule_file {
         let res = File::open(&filename)
@@ -162,7 +231,8 @@ fn main() {
             Ok(json) => json,
             Err(e) => {
                 eprintln!("Error reading {:?}: {}", filename, e);
-                exit(3);
+                reporter.exit(3,
+                    Some(format!("error reading {:?}: {}", filename, e)));
             }
         };
         vars.insert(String::from("full_schedule"), schedule);
@@ -170,36 +240,44 @@ fn main() {
         vars.insert(String::from("full_schedule"), schedule.0);
     }
 
+    let vars = Value::Object(vars);
     let mut dlog = log.deployment(&id, false);
-    {
+    let error = {
         let mut rlog = match dlog.role(&role, false) {
             Ok(rlog) => rlog,
-            Err(_) => exit(81),
+            Err(e) => reporter.exit(81, Some(e.to_string())),
         };
-        match render::render_role(&dir, &Value::Object(vars), &mut rlog)
-        {
+        match render::render_role(&dir, &vars, &mut rlog) {
             Err(e) => {
                 rlog.log(format_args!(
                     "ERROR: Can't render templates: {}\n", e));
                 // TODO(tailhook) should we still check dlog.errors()
-                exit(10);
+                Some((10, format!("can't render templates: {}", e)))
             }
             Ok(actions) => {
                 match apply::apply_list(&role, actions, &mut rlog, dry_run,
-                        &sandbox)
+                        &sandbox, &vars, previous_vars.as_ref(),
+                        &mut reporter.actions)
                 {
                     Err(e) => {
                         rlog.log(format_args!(
                             "ERROR: Can't apply templates: {}\n", e));
                         // TODO(tailhook) should we still check dlog.errors()
-                        exit(20);
+                        Some((20, format!("can't apply templates: {}", e)))
                     }
-                    Ok(()) => {}
+                    Ok(()) => None,
                 }
             }
         }
+    };
+    if let Some((code, error)) = error {
+        reporter.exit(code, Some(error));
     }
     if dlog.errors().len() != 0 {
-        exit(81);
+        let errors = dlog.errors().iter()
+            .map(|e| e.to_string()).collect::<Vec<_>>();
+        reporter.exit(81, Some(format!("logging errors: {}",
+            errors.join(", "))));
     }
+    reporter.exit(0, None);
 }
@@ -16,14 +16,24 @@ use trimmer;
 
 use indexed_log::Role;
 use renderfile::{self as config, TemplateError};
+use structured::{Structured, Format};
 
 
 #[derive(Deserialize, Debug)]
 pub struct Renderer {
-    pub templates: HashMap<String, PathBuf>,
+    pub templates: HashMap<String, Template>,
     pub commands: Vec<Command>,
 }
 
+/// A template file or a subtree of variables serialized to some format
+#[derive(Deserialize, Debug)]
+pub enum Template {
+    Path(PathBuf),
+    Json(Structured),
+    Yaml(Structured),
+    Toml(Structured),
+}
+
 quick_error! {
     #[derive(Debug)]
     pub enum Error {
@@ -45,6 +55,11 @@ quick_error! {
             display("template {:?} not found", path)
             description("template not found")
         }
+        Structured(name: String, format: Format, message: String) {
+            display("error serializing template {:?} to {:?}: {}",
+                name, format, message)
+            description("error serializing template")
+        }
         Handlebars(err: HbsError, file: PathBuf, data: Value) {
             cause(err)
             display("error rendering template, file {:?}: \
@@ -74,6 +89,43 @@ quick_error! {
     }
 }
 
+fn render_file(tpath: &PathBuf, vars: &Value, hbars: &Handlebars,
+    tera: &Tera, trm: &HashMap<String, trimmer::Template>)
+    -> Result<String, Error>
+{
+    let output = match tpath.extension().and_then(|x| x.to_str()) {
+        Some("hbs") | Some("handlebars") => {
+            hbars.render(&tpath.display().to_string(), vars)
+                .context((tpath, vars))?
+        }
+        Some("tera") => {
+            tera.render(&tpath.display().to_string(), vars)
+                .context((tpath, vars))?
+        }
+        Some("trm") | Some("trimmer") => {
+            trm.get(&tpath.display().to_string())
+                .ok_or_else(|| Error::NotFound(tpath.clone()))
+            .and_then(|tpl| {
+                Ok(trimmer::render_json(tpl, vars)
+                    .context((tpath, vars))?)
+            })?
+        }
+        _ => {
+            return Err(Error::UnknownTemplateType(tpath.to_path_buf()));
+        }
+    };
+    Ok(output)
+}
+
+fn render_structured(name: &str, st: &Structured, format: Format,
+    vars: &Value)
+    -> Result<String, Error>
+{
+    st.select(vars)
+        .and_then(|value| format.serialize(&value))
+        .map_err(|e| Error::Structured(name.to_string(), format, e))
+}
+
 pub fn render_role(dir: &Path, vars: &Value, log: &mut Role)
     -> Result<Vec<(String, Vec<Command>, Source)>, Error>
 {
@@ -87,33 +139,30 @@ pub fn render_role(dir: &Path, vars: &Value, log: &mut Role)
 
     for (rname, render) in renderers {
         let mut dest = HashMap::new();
-        for (tname, tpath) in render.templates {
+        for (tname, template) in render.templates {
             let mut tmpfile = try!(NamedTempFile::new());
 
-            let output = match tpath.extension().and_then(|x| x.to_str()) {
-                Some("hbs") | Some("handlebars") => {
-                    hbars.render(&tpath.display().to_string(), vars)
-                        .context((&tpath, vars))?
+            let output = match template {
+                Template::Path(ref tpath) => {
+                    render_file(tpath, vars, &hbars, &tera, &trm)?
                 }
-                Some("tera") => {
-                    tera.render(&tpath.display().to_string(), vars)
-                        .context((&tpath, vars))?
+                Template::Json(ref st) => {
+                    render_structured(&tname, st, Format::Json, vars)?
                 }
-                Some("trm") | Some("trimmer") => {
-                    trm.get(&tpath.display().to_string())
-                        .ok_or_else(|| Error::NotFound(tpath.clone()))
-                    .and_then(|tpl| {
-                        Ok(trimmer::render_json(tpl, vars)
-                            .context((&tpath, vars))?)
-                    })?
+                Template::Yaml(ref st) => {
+                    render_structured(&tname, st, Format::Yaml, vars)?
                 }
-                _ => {
-                    return Err(
-                        Error::UnknownTemplateType(tpath.to_path_buf()));
+                Template::Toml(ref st) => {
+                    render_structured(&tname, st, Format::Toml, vars)?
                 }
             };
             tmpfile.write_all(output.as_bytes())?;
-            log.template(&tpath, &tmpfile.path(), &output);
+            match template {
+                Template::Path(ref tpath) => {
+                    log.template(tpath, &tmpfile.path(), &output);
+                }
+                ref other => log.template(other, &tmpfile.path(), &output),
+            }
             dest.insert(tname.clone(), tmpfile);
         }
         result.push((rname, render.commands, Source::TmpFiles(dest)));
@@ -13,7 +13,8 @@ use tera::{Tera, Error as TeraError};
 use trimmer::{self, Template as Trimmer, ParseError as TrimmerError};
 
 use apply;
-use render::Renderer;
+use render::{Renderer, Template};
+use structured::Structured;
 
 
 quick_error! {
@@ -68,16 +69,29 @@ pub fn command_validator<'x>(root: bool) -> V::Enum<'x> {
         .option("Copy", apply::copy::Copy::config())
         .option("SplitText", apply::split_text::SplitText::config())
         .option("CleanFiles", apply::clean_files::CleanFiles::config())
-        .option("PeekLog", apply::peek_log::PeekLog::config());
+        .option("PeekLog", apply::peek_log::PeekLog::config())
+        .option("HealthCheck", apply::health_check::HealthCheck::config())
+        .option("WriteFile", apply::write_file::WriteFile::config());
     if root {
         val = val.option("Condition", apply::condition::Condition::config())
     }
     return val;
 }
 
+/// Either a path to a template file or a structured template
+fn template_validator<'x>() -> V::Enum<'x> {
+    V::Enum::new()
+    .option("Path", V::Scalar::new())
+    .option("Json", Structured::config())
+    .option("Yaml", Structured::config())
+    .option("Toml", Structured::config())
+    .default_tag("Path")
+}
+
 fn config_validator<'x>() -> V::Structure<'x> {
     V::Structure::new()
-    .member("templates", V::Mapping::new(V::Scalar::new(), V::Scalar::new()))
+    .member("templates", V::Mapping::new(V::Scalar::new(),
+                                         template_validator()))
     .member("commands", V::Sequence::new(command_validator(true)))
 }
 
@@ -92,7 +106,12 @@ fn read_renderer(path: &Path, base: &Path)
             // Normalize path to be relative to base path
             // rather than relative to current subdir
         templates: orig.templates.into_iter()
-            .map(|(name, path)| (name, template_base.join(path)))
+            .map(|(name, tpl)| match tpl {
+                Template::Path(path) => {
+                    (name, Template::Path(template_base.join(path)))
+                }
+                tpl => (name, tpl),
+            })
             .collect(),
         commands: orig.commands,
     }))
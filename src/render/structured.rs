@@ -0,0 +1,267 @@
+//! Templates serialized directly from variables
+//!
+//! Instead of writing JSON, YAML or TOML by hand in a textual template,
+//! the subtree of variables is serialized, so the output is always
+//! well-formed.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use quire::validate as V;
+use serde_json::{Value, Map, to_string, to_string_pretty};
+use yaml_rust::{Yaml, YamlEmitter};
+use yaml_rust::yaml::Hash;
+
+use apply::expand::lookup;
+
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Structured {
+    vars: Option<String>,
+    rename: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl Structured {
+    pub fn config() -> V::Structure<'static> {
+        V::Structure::new()
+        .member("vars", V::Scalar::new().optional())
+        .member("rename", V::Mapping::new(V::Scalar::new(), V::Scalar::new()))
+    }
+
+    /// Selects the subtree of `vars` and renames its keys
+    pub fn select(&self, vars: &Value) -> Result<Value, String> {
+        let mut value = match self.vars {
+            Some(ref path) => lookup(vars, path)
+                .ok_or_else(|| format!("unknown variable {:?}", path))?
+                .clone(),
+            None => vars.clone(),
+        };
+        if !self.rename.is_empty() {
+            let map = value.as_object_mut()
+                .ok_or_else(|| "can't rename keys of non-object".to_string())?;
+            for (old, new) in &self.rename {
+                if let Some(item) = map.remove(old) {
+                    map.insert(new.clone(), item);
+                }
+            }
+        }
+        Ok(value)
+    }
+}
+
+impl Format {
+    pub fn serialize(&self, value: &Value) -> Result<String, String> {
+        match *self {
+            Format::Json => {
+                let mut buf = to_string_pretty(value)
+                    .map_err(|e| e.to_string())?;
+                buf.push('\n');
+                Ok(buf)
+            }
+            Format::Yaml => {
+                let mut buf = String::new();
+                YamlEmitter::new(&mut buf).dump(&to_yaml(value))
+                    .map_err(|e| format!("{:?}", e))?;
+                buf.push('\n');
+                Ok(buf)
+            }
+            Format::Toml => {
+                let map = value.as_object().ok_or_else(|| {
+                    "TOML document must be a table".to_string()
+                })?;
+                let mut buf = String::new();
+                write_table(&mut buf, &[], map)?;
+                // no plain values at the top level
+                if buf.starts_with('\n') {
+                    buf.remove(0);
+                }
+                Ok(buf)
+            }
+        }
+    }
+}
+
+fn to_yaml(value: &Value) -> Yaml {
+    match *value {
+        Value::Null => Yaml::Null,
+        Value::Bool(b) => Yaml::Boolean(b),
+        Value::Number(ref n) => match n.as_i64() {
+            Some(n) => Yaml::Integer(n),
+            None => Yaml::Real(n.to_string()),
+        },
+        Value::String(ref s) => Yaml::String(s.clone()),
+        Value::Array(ref items) => {
+            Yaml::Array(items.iter().map(to_yaml).collect())
+        }
+        Value::Object(ref map) => {
+            let mut hash = Hash::new();
+            for (key, value) in map {
+                hash.insert(Yaml::String(key.clone()), to_yaml(value));
+            }
+            Yaml::Hash(hash)
+        }
+    }
+}
+
+fn toml_key(key: &str) -> String {
+    let bare = !key.is_empty() && key.chars().all(|c| {
+        c.is_ascii_alphanumeric() || c == '_' || c == '-'
+    });
+    if bare {
+        key.to_string()
+    } else {
+        to_string(key).expect("can serialize string")
+    }
+}
+
+fn is_table_array(value: &Value) -> bool {
+    match *value {
+        Value::Array(ref items) => {
+            !items.is_empty() && items.iter().all(|x| x.is_object())
+        }
+        _ => false,
+    }
+}
+
+fn write_inline(buf: &mut String, value: &Value) -> Result<(), String> {
+    match *value {
+        Value::Null => return Err("TOML has no null values".to_string()),
+        // TOML basic strings use the same escapes as JSON
+        Value::String(ref s) => {
+            buf.push_str(&to_string(s).expect("can serialize string"));
+        }
+        Value::Bool(_) | Value::Number(_) => {
+            write!(buf, "{}", value).unwrap();
+        }
+        Value::Array(ref items) => {
+            buf.push('[');
+            for (idx, item) in items.iter().enumerate() {
+                if idx > 0 {
+                    buf.push_str(", ");
+                }
+                write_inline(buf, item)?;
+            }
+            buf.push(']');
+        }
+        Value::Object(ref map) => {
+            buf.push('{');
+            for (idx, (key, item)) in map.iter().enumerate() {
+                buf.push_str(if idx > 0 { ", " } else { " " });
+                write!(buf, "{} = ", toml_key(key)).unwrap();
+                write_inline(buf, item)?;
+            }
+            buf.push_str(if map.is_empty() { "}" } else { " }" });
+        }
+    }
+    Ok(())
+}
+
+fn write_table(buf: &mut String, path: &[String], map: &Map<String, Value>)
+    -> Result<(), String>
+{
+    // plain values must precede subtables
+    for (key, value) in map {
+        if value.is_object() || is_table_array(value) {
+            continue;
+        }
+        write!(buf, "{} = ", toml_key(key)).unwrap();
+        write_inline(buf, value)
+            .map_err(|e| format!("{}: {}", key, e))?;
+        buf.push('\n');
+    }
+    for (key, value) in map {
+        let mut sub = path.to_vec();
+        sub.push(toml_key(key));
+        match *value {
+            Value::Object(ref table) => {
+                write!(buf, "\n[{}]\n", sub.join(".")).unwrap();
+                write_table(buf, &sub, table)?;
+            }
+            Value::Array(ref items) if is_table_array(value) => {
+                for item in items {
+                    write!(buf, "\n[[{}]]\n", sub.join(".")).unwrap();
+                    let table = item.as_object().expect("is a table");
+                    write_table(buf, &sub, table)?;
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use serde_json::{Value, from_str};
+    use super::{Structured, Format};
+
+    fn vars() -> Value {
+        from_str(r#"{
+            "app": {
+                "port": 8080,
+                "name": "web \"main\"",
+                "hosts": ["a", "b"],
+                "db": {"url": "postgres://db"},
+                "workers": [{"queue": "q1"}, {"queue": "q2"}]
+            }
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn select_and_rename() {
+        let mut rename = BTreeMap::new();
+        rename.insert("port".to_string(), "listen".to_string());
+        let st = Structured { vars: Some("app.db".into()), rename };
+        assert_eq!(st.select(&vars()).unwrap(),
+                   from_str::<Value>(r#"{"url": "postgres://db"}"#).unwrap());
+        let mut rename = BTreeMap::new();
+        rename.insert("port".to_string(), "listen".to_string());
+        let st = Structured { vars: Some("app".into()), rename };
+        let value = st.select(&vars()).unwrap();
+        assert_eq!(value["listen"], 8080);
+        assert!(value.get("port").is_none());
+        let st = Structured { vars: Some("nope".into()),
+                              rename: BTreeMap::new() };
+        assert!(st.select(&vars()).is_err());
+    }
+
+    #[test]
+    fn toml() {
+        let vars = vars();
+        assert_eq!(Format::Toml.serialize(&vars).unwrap(), concat!(
+            "[app]\n",
+            "hosts = [\"a\", \"b\"]\n",
+            "name = \"web \\\"main\\\"\"\n",
+            "port = 8080\n",
+            "\n",
+            "[app.db]\n",
+            "url = \"postgres://db\"\n",
+            "\n",
+            "[[app.workers]]\n",
+            "queue = \"q1\"\n",
+            "\n",
+            "[[app.workers]]\n",
+            "queue = \"q2\"\n",
+        ));
+        assert!(Format::Toml.serialize(&vars["app"]["hosts"]).is_err());
+        assert!(Format::Toml.serialize(
+            &from_str(r#"{"x": null}"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn json_and_yaml() {
+        let vars = vars();
+        let db = &vars["app"]["db"];
+        assert_eq!(Format::Json.serialize(db).unwrap(),
+                   "{\n  \"url\": \"postgres://db\"\n}\n");
+        assert_eq!(Format::Yaml.serialize(&vars["app"]["hosts"]).unwrap(),
+                   "---\n- a\n- b\n");
+    }
+}
@@ -0,0 +1,163 @@
+//! Minimal blocking HTTP client
+//!
+//! Used by health checks of the renderer and by webhooks of the daemon.
+//! Only plain `http://` urls are supported and only the status line of
+//! the response is read.
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+
+quick_error! {
+    #[derive(Debug)]
+    pub enum HttpError {
+        Url(message: String) {
+            display("{}", message)
+            description("invalid url")
+        }
+        Io(err: io::Error) {
+            from()
+            cause(err)
+            display("{}", err)
+            description("network error")
+        }
+        Status(line: String) {
+            display("bad status: {}", line)
+            description("bad http status")
+        }
+        InvalidResponse(line: String) {
+            display("invalid http response {:?}", line)
+            description("invalid http response")
+        }
+    }
+}
+
+/// Parsed `http://host:port/path` url
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    /// Host without brackets (for IPv6 addresses)
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+pub fn parse_url(url: &str) -> Result<Url, HttpError> {
+    if !url.starts_with("http://") {
+        return Err(HttpError::Url(
+            format!("only http:// urls are supported, got {:?}", url)));
+    }
+    let rest = &url["http://".len()..];
+    let (hostport, path) = match rest.find('/') {
+        Some(idx) => (&rest[..idx], &rest[idx..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match hostport.rfind(':') {
+        Some(idx) if !hostport.ends_with(']') => {
+            let port = hostport[idx+1..].parse()
+                .map_err(|_| HttpError::Url(
+                    format!("bad port in url {:?}", url)))?;
+            (&hostport[..idx], port)
+        }
+        _ => (hostport, 80),
+    };
+    if host.is_empty() {
+        return Err(HttpError::Url(format!("no host in url {:?}", url)));
+    }
+    Ok(Url {
+        host: host.trim_matches(|c| c == '[' || c == ']').to_string(),
+        port: port,
+        path: path.to_string(),
+    })
+}
+
+/// Connects to the first address `addr` resolves to which accepts
+pub fn connect<A: ToSocketAddrs>(addr: A, timeout: Duration)
+    -> Result<TcpStream, HttpError>
+{
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(sock) => return Ok(sock),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    match last_err {
+        Some(e) => Err(e.into()),
+        None => Err(io::Error::new(io::ErrorKind::Other,
+                                   "address resolved to nothing").into()),
+    }
+}
+
+/// Sends a request, returns an error unless response status is 2xx
+///
+/// Every network operation is limited by `timeout`. `Host`, `Connection`
+/// and, if there is a body, `Content-Length` headers are added.
+pub fn request<'a, I>(method: &str, url: &Url, headers: I, body: &[u8],
+    timeout: Duration)
+    -> Result<(), HttpError>
+    where I: IntoIterator<Item=(&'a str, &'a str)>,
+{
+    let mut sock = connect((&url.host[..], url.port), timeout)?;
+    sock.set_read_timeout(Some(timeout))?;
+    sock.set_write_timeout(Some(timeout))?;
+    let mut req = format!("{} {} HTTP/1.1\r\n\
+        Host: {}:{}\r\n\
+        Connection: close\r\n",
+        method, url.path, url.host, url.port);
+    if !body.is_empty() {
+        req.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    for (name, value) in headers {
+        req.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    req.push_str("\r\n");
+    let mut req = req.into_bytes();
+    req.extend_from_slice(body);
+    sock.write_all(&req)?;
+    let mut buf = Vec::with_capacity(128);
+    let mut chunk = [0u8; 128];
+    while !buf.contains(&b'\n') && buf.len() < 4096 {
+        let n = sock.read(&mut chunk)?;
+        if n == 0 {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let line = String::from_utf8_lossy(&buf);
+    let line = line.lines().next().unwrap_or("").trim();
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(proto), Some(code)) if proto.starts_with("HTTP/") => {
+            if code.starts_with('2') {
+                Ok(())
+            } else {
+                Err(HttpError::Status(line.to_string()))
+            }
+        }
+        _ => Err(HttpError::InvalidResponse(line.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_url, Url};
+
+    fn url(host: &str, port: u16, path: &str) -> Url {
+        Url { host: host.into(), port: port, path: path.into() }
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(parse_url("http://localhost").unwrap(),
+                   url("localhost", 80, "/"));
+        assert_eq!(parse_url("http://127.0.0.1:8080/health?x=1").unwrap(),
+                   url("127.0.0.1", 8080, "/health?x=1"));
+        assert_eq!(parse_url("http://[::1]:81/").unwrap(),
+                   url("::1", 81, "/"));
+        assert_eq!(parse_url("http://[::1]/x").unwrap(),
+                   url("::1", 80, "/x"));
+        assert!(parse_url("https://localhost/").is_err());
+        assert!(parse_url("http://localhost:x/").is_err());
+        assert!(parse_url("http:///path").is_err());
+    }
+}
@@ -17,8 +17,11 @@ use yaml_rust::Yaml;
 
 mod meta;
 mod sandbox;
+pub mod http;
+pub mod render_result;
 
 pub use self::sandbox::Sandbox;
+pub use self::render_result::{RenderInput, RenderResult};
 
 quick_error! {
     #[derive(Debug)]
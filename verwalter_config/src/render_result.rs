@@ -0,0 +1,68 @@
+//! Protocol between verwalter daemon and `verwalter_render`
+//!
+//! Daemon writes `RenderInput` to stdin of the renderer, and the renderer
+//! reports `RenderResult` into a pipe passed by `--result-fd`.
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenderInput {
+    pub vars: Map<String, Value>,
+    pub schedule: Value,
+    /// Variables of the last successful render of the role
+    #[serde(default)]
+    pub previous_vars: Option<Map<String, Value>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all="snake_case")]
+pub enum Status {
+    Ok,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandResult {
+    /// Debug representation of the command
+    pub command: String,
+    pub status: Status,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ActionResult {
+    pub name: String,
+    pub status: Status,
+    pub duration_ms: u64,
+    /// Files written by the commands of this action
+    pub files: Vec<PathBuf>,
+    /// Whether any command of this action changed a file
+    #[serde(default)]
+    pub changed: bool,
+    pub commands: Vec<CommandResult>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenderResult {
+    pub status: Status,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+    pub actions: Vec<ActionResult>,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Status::Ok => "ok",
+            Status::Failed => "failed",
+        }
+    }
+}
+
+pub fn millis(dur: Duration) -> u64 {
+    dur.as_secs() * 1000 + (dur.subsec_nanos() / 1_000_000) as u64
+}